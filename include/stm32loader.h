@@ -0,0 +1,208 @@
+/* The library exports these functions only when built with --features ffi */
+
+#ifndef STM32LOADER_H
+#define STM32LOADER_H
+
+/* Generated with cbindgen from src/ffi.rs, do not edit */
+
+#include <stdarg.h>
+#include <stdbool.h>
+#include <stddef.h>
+#include <stdint.h>
+#include <stdlib.h>
+
+// Result of every call, the bootloader errors mirror [DfuLoaderError]
+typedef enum Stm32LoaderStatus {
+  STM32_LOADER_STATUS_OK = 0,
+  STM32_LOADER_STATUS_SYNC_ERROR = 1,
+  STM32_LOADER_STATUS_ALREADY_SYNCED = 2,
+  STM32_LOADER_STATUS_NOT_IMPLEMENTED = 3,
+  STM32_LOADER_STATUS_INVALID_REQUEST = 4,
+  STM32_LOADER_STATUS_IO_ERROR = 5,
+  // The device answered NACK
+  STM32_LOADER_STATUS_NACK = 6,
+  // The device answered BUSY
+  STM32_LOADER_STATUS_BUSY = 7,
+  // An unexpected byte where ACK or NACK was expected
+  STM32_LOADER_STATUS_GARBAGE = 8,
+  // A response with the wrong length or checksum
+  STM32_LOADER_STATUS_MALFORMED = 9,
+  STM32_LOADER_STATUS_TIMEOUT = 10,
+  STM32_LOADER_STATUS_INVALID_ARGUMENT = 100,
+  STM32_LOADER_STATUS_IMAGE_ERROR = 101,
+  STM32_LOADER_STATUS_NOT_BLANK = 102,
+  STM32_LOADER_STATUS_VERIFY_FAILED = 103,
+  STM32_LOADER_STATUS_NO_PAGE_LAYOUT = 104,
+  STM32_LOADER_STATUS_INTERNAL_ERROR = 105,
+} Stm32LoaderStatus;
+
+typedef enum Stm32LoaderErase {
+  STM32_LOADER_ERASE_NONE = 0,
+  STM32_LOADER_ERASE_ALL = 1,
+  STM32_LOADER_ERASE_CHANGED_PAGES = 2,
+} Stm32LoaderErase;
+
+typedef enum Stm32LoaderParity {
+  STM32_LOADER_PARITY_EVEN = 0,
+  STM32_LOADER_PARITY_NONE = 1,
+  STM32_LOADER_PARITY_ODD = 2,
+} Stm32LoaderParity;
+
+typedef enum Stm32LoaderChipSelect {
+  STM32_LOADER_CHIP_SELECT_ACTIVE_LOW = 0,
+  STM32_LOADER_CHIP_SELECT_ACTIVE_HIGH = 1,
+  // Not driven by the controller
+  STM32_LOADER_CHIP_SELECT_NONE = 2,
+} Stm32LoaderChipSelect;
+
+// An open connection to a device, created by [stm32loader_open]
+typedef struct Stm32Loader Stm32Loader;
+
+// Settings of the port for [stm32loader_open], NULL selects the defaults of
+// [SerialSettings] and [SpiSettings]
+typedef struct Stm32LoaderOpenOptions {
+  // Baud rate of a serial port, 0 for the default of 115200
+  uint32_t baud_rate;
+  // A Stm32LoaderParity
+  uint32_t parity;
+  // SPI clock in Hz, the bootloader supports up to 8 MHz, 0 for the default
+  uint32_t spi_clock_hz;
+  // SPI mode 0 to 3, the bootloader uses mode 0
+  uint8_t spi_mode;
+  // A Stm32LoaderChipSelect
+  uint32_t chip_select;
+} Stm32LoaderOpenOptions;
+
+typedef struct Stm32LoaderDeviceInfo {
+  uint16_t chip_id;
+  uint8_t bootloader_version;
+  uint16_t bootloader_options;
+  uint32_t write_alignment;
+  // NUL terminated device family name
+  char name[32];
+} Stm32LoaderDeviceInfo;
+
+// Options for the write functions, NULL selects the defaults: no erase,
+// pad to the write alignment, blank check and no verify
+typedef struct Stm32LoaderWriteOptions {
+  // A Stm32LoaderErase, a plain integer so an unknown value from C is an
+  // error instead of an invalid enum
+  uint32_t erase;
+  // Read the current flash content around unaligned edges instead of padding
+  bool read_modify_write;
+  bool blank_check;
+  bool verify;
+} Stm32LoaderWriteOptions;
+
+// Open a port and synchronise with the bootloader.
+//
+// `interface` is "Serial", "SPI" or "Tcp", `port` the port name as on the
+// command line, `options` the port settings or NULL for the defaults. On
+// success `*handle` receives a handle for [stm32loader_close].
+//
+// # Safety
+// `interface` and `port` must be NUL terminated strings, `options` NULL or
+// valid, `handle` must be writable.
+enum Stm32LoaderStatus stm32loader_open(const char *interface,
+                                        const char *port,
+                                        const struct Stm32LoaderOpenOptions *options,
+                                        struct Stm32Loader **handle);
+
+// Close the port and free the handle, NULL is ignored
+//
+// # Safety
+// `handle` must come from [stm32loader_open] and must not be used afterwards.
+void stm32loader_close(struct Stm32Loader *handle);
+
+// The message of the last failure on this thread, valid until the next call
+const char *stm32loader_last_error(void);
+
+// Identify the device, `info` may be NULL to only select the device profile
+//
+// # Safety
+// `handle` must be a valid handle, `info` NULL or writable.
+enum Stm32LoaderStatus stm32loader_identify(struct Stm32Loader *handle,
+                                            struct Stm32LoaderDeviceInfo *info);
+
+// Read the 96-bit unique device id into `uid`, the device has to be
+// identified first so the address is known
+//
+// # Safety
+// `handle` must be a valid handle, `uid` must point to 12 writable bytes.
+enum Stm32LoaderStatus stm32loader_unique_id(struct Stm32Loader *handle, uint8_t *uid);
+
+// Read the flash size of the part in bytes, the device has to be
+// identified first so the address is known
+//
+// # Safety
+// `handle` must be a valid handle, `size` writable.
+enum Stm32LoaderStatus stm32loader_flash_size(struct Stm32Loader *handle, uint32_t *size);
+
+// Report the progress of writes to `callback`, which receives `user_data`,
+// the bytes written and the total. NULL removes the callback.
+//
+// # Safety
+// `handle` must be a valid handle, `user_data` is passed to `callback` as is.
+enum Stm32LoaderStatus stm32loader_set_progress(struct Stm32Loader *handle,
+                                                void (*callback)(void *user_data,
+                                                                 size_t done,
+                                                                 size_t total),
+                                                void *user_data);
+
+// Try read and write chunks up to `attempts` times, waiting `backoff_ms`
+// before the first retry and doubling the wait for every further retry.
+// The default is 3 attempts with 50 ms backoff, 1 disables retrying.
+//
+// # Safety
+// `handle` must be a valid handle.
+enum Stm32LoaderStatus stm32loader_set_retry(struct Stm32Loader *handle,
+                                             uint32_t attempts,
+                                             uint32_t backoff_ms);
+
+// Erase the whole flash
+//
+// # Safety
+// `handle` must be a valid handle.
+enum Stm32LoaderStatus stm32loader_erase_all(struct Stm32Loader *handle);
+
+// Write `length` bytes from `data` to `address`
+//
+// # Safety
+// `handle` must be a valid handle, `data` must point to `length` bytes and
+// `options` must be NULL or valid.
+enum Stm32LoaderStatus stm32loader_write(struct Stm32Loader *handle,
+                                         uint32_t address,
+                                         const uint8_t *data,
+                                         size_t length,
+                                         const struct Stm32LoaderWriteOptions *options);
+
+// Write an Intel hex or raw binary file, see [MemoryImage::load]. `address`
+// may be NULL for Intel hex files, a raw binary needs it.
+//
+// # Safety
+// `handle` must be a valid handle, `path` a NUL terminated string, `address`
+// and `options` NULL or valid.
+enum Stm32LoaderStatus stm32loader_write_file(struct Stm32Loader *handle,
+                                              const char *path,
+                                              const uint32_t *address,
+                                              const struct Stm32LoaderWriteOptions *options);
+
+// Compare the device content at `address` with `data`. On a difference
+// the first differing address is stored in `failed_address` if not NULL.
+//
+// # Safety
+// `handle` must be a valid handle, `data` must point to `length` bytes and
+// `failed_address` must be NULL or writable.
+enum Stm32LoaderStatus stm32loader_verify(struct Stm32Loader *handle,
+                                          uint32_t address,
+                                          const uint8_t *data,
+                                          size_t length,
+                                          uint32_t *failed_address);
+
+// Start the application at `address`
+//
+// # Safety
+// `handle` must be a valid handle.
+enum Stm32LoaderStatus stm32loader_go(struct Stm32Loader *handle, uint32_t address);
+
+#endif  /* STM32LOADER_H */
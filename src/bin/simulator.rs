@@ -0,0 +1,123 @@
+//! Runs the emulated bootloader on a pseudo-terminal, so the loader (or any
+//! other tool) can connect to it as if it was a serial port:
+//!
+//! ```text
+//! Stm32Simulator --pid 0x433 --link /tmp/stm32
+//! Stm32Loader --type Serial --port /tmp/stm32 write firmware.hex
+//! ```
+
+use clap::Parser;
+use nix::pty::openpty;
+use nix::sys::termios::{cfmakeraw, tcgetattr, tcsetattr, SetArg};
+use nix::unistd::ttyname;
+use std::error::Error;
+use std::fs::{remove_file, symlink_metadata, write, File};
+use std::io::{ErrorKind, Read, Write};
+use std::num::ParseIntError;
+use std::os::unix::fs::symlink;
+use std::path::{Path, PathBuf};
+use stm32loader::emulator::EmulatedDevice;
+
+#[derive(Parser, Debug)]
+#[command(version, about = "Emulated STM32 bootloader on a pseudo-terminal")]
+struct Cli {
+    #[arg(long = "pid", default_value = "0x433", value_parser = parse_hex_u16, help = "Product id of the emulated device")]
+    pid: u16,
+
+    #[arg(long = "bootloader-version", default_value = "0x31", value_parser = parse_hex_u8)]
+    bootloader_version: u8,
+
+    #[arg(long = "commands", value_delimiter = ',', value_parser = parse_hex_u8, help = "Supported command opcodes, e.g. 0x00,0x01,0x02,0x11")]
+    commands: Option<Vec<u8>>,
+
+    #[arg(
+        long = "readout-protected",
+        help = "Start with readout protection enabled"
+    )]
+    readout_protected: bool,
+
+    #[arg(
+        long = "link",
+        help = "Create a symlink to the serial port at this path, replacing a symlink but no \
+                other file"
+    )]
+    link: Option<PathBuf>,
+
+    #[arg(
+        long = "dump",
+        help = "Write the flash content to this file and exit when the host sends Go"
+    )]
+    dump: Option<PathBuf>,
+}
+
+fn main() -> Result<(), Box<dyn Error>> {
+    let cli = Cli::parse();
+
+    let mut device = EmulatedDevice::new(cli.pid)
+        .ok_or(format!("Unknown product id 0x{:03x}", cli.pid))?
+        .with_bootloader_version(cli.bootloader_version)
+        .with_readout_protection(cli.readout_protected);
+    if let Some(commands) = &cli.commands {
+        device = device.with_commands(commands);
+    }
+
+    let pty = openpty(None, None)?;
+    let mut termios = tcgetattr(&pty.slave)?;
+    cfmakeraw(&mut termios);
+    tcsetattr(&pty.slave, SetArg::TCSANOW, &termios)?;
+
+    let slave_path = ttyname(&pty.slave)?;
+    if let Some(link) = &cli.link {
+        replace_link(link)?;
+        symlink(&slave_path, link)?;
+    }
+    println!("{}", slave_path.display());
+    std::io::stdout().flush()?;
+
+    // Keep the slave open, reading the master fails while no side has it open
+    let _slave = pty.slave;
+    let mut master = File::from(pty.master);
+    let mut port = device.usart();
+    let mut buffer = [0u8; 1024];
+    loop {
+        let n = master.read(&mut buffer)?;
+        port.write_all(&buffer[..n])?;
+
+        let mut response = vec![];
+        while let Ok(n) = port.read(&mut buffer) {
+            response.extend_from_slice(&buffer[..n]);
+        }
+        master.write_all(&response)?;
+
+        if let (Some(dump), Some(address)) = (&cli.dump, device.jumped_to()) {
+            let flash = device.read_flash(device.profile().flash_start, device.flash_size());
+            write(dump, flash)?;
+            println!("Go {:#08X}, flash written to {}", address, dump.display());
+            break;
+        }
+    }
+
+    if let Some(link) = &cli.link {
+        remove_file(link)?;
+    }
+    Ok(())
+}
+
+/// Remove a symlink left at `link` by an earlier run, anything else there is
+/// not ours to delete
+fn replace_link(link: &Path) -> Result<(), Box<dyn Error>> {
+    match symlink_metadata(link) {
+        Ok(metadata) if metadata.file_type().is_symlink() => Ok(remove_file(link)?),
+        Ok(_) => Err(format!("{} exists and is not a symlink", link.display()).into()),
+        Err(err) if err.kind() == ErrorKind::NotFound => Ok(()),
+        Err(err) => Err(err.into()),
+    }
+}
+
+fn parse_hex_u8(value: &str) -> Result<u8, ParseIntError> {
+    u8::from_str_radix(value.trim_start_matches("0x"), 16)
+}
+
+fn parse_hex_u16(value: &str) -> Result<u16, ParseIntError> {
+    u16::from_str_radix(value.trim_start_matches("0x"), 16)
+}
@@ -0,0 +1,405 @@
+//! The CAN bootloader protocol (AN3154), async only.
+//!
+//! Every command is a frame with the command code as standard identifier,
+//! the device answers with frames using the same identifier. Frames with
+//! other identifiers are ignored, so the bus can be shared with other nodes.
+
+use crate::dfuloader::DfuLoaderError::*;
+use crate::dfuloader::{
+    BootLoaderInfo, BootloaderChipId, BootloaderOptions, DfuLoaderError, Exchange, Failure,
+    Functions, Stage, ACK, ERASE_TIMEOUT, NAK,
+};
+use crate::nonblocking::{with_timeout, AsyncDfuLoader, RESPONSE_TIMEOUT};
+use crate::trace;
+use async_trait::async_trait;
+use socketcan::{CanFrame, EmbeddedFrame, Id, StandardId};
+use std::error::Error;
+use std::io;
+use std::time::Duration;
+use tokio::time::sleep;
+
+/// Identifier of the synchronisation frame
+const SYNC_ID: u16 = 0x79;
+
+/// Identifier of the data frames sent with Write Memory
+const WRITE_DATA_ID: u16 = 0x04;
+
+/// A CAN bus carrying standard (11-bit) identifier data frames
+#[async_trait]
+pub trait CanBus: Send {
+    async fn send(&mut self, id: u16, data: &[u8]) -> io::Result<()>;
+
+    /// Wait for the next data frame, remote and error frames are skipped
+    async fn receive(&mut self) -> io::Result<(u16, Vec<u8>)>;
+}
+
+#[async_trait]
+impl CanBus for socketcan::tokio::CanSocket {
+    async fn send(&mut self, id: u16, data: &[u8]) -> io::Result<()> {
+        let frame = StandardId::new(id)
+            .and_then(|id| CanFrame::new(id, data))
+            .ok_or(io::ErrorKind::InvalidInput)?;
+        self.write_frame(frame).await
+    }
+
+    async fn receive(&mut self) -> io::Result<(u16, Vec<u8>)> {
+        loop {
+            let frame = self.read_frame().await?;
+            if let (true, Id::Standard(id)) = (frame.is_data_frame(), frame.id()) {
+                return Ok((id.as_raw(), frame.data().to_vec()));
+            }
+        }
+    }
+}
+
+/// Open a SocketCAN interface, e.g. can0, must be called from within a tokio runtime.
+///
+/// The bootloader runs at 125 kbit/s, the interface has to be configured for that.
+pub fn new_can_connection(
+    interface: &str,
+) -> Result<Box<dyn AsyncDfuLoader>, Box<dyn Error + Send + Sync>> {
+    let socket = socketcan::tokio::CanSocket::open(interface)?;
+
+    Ok(Box::new(CanConnection::new(Box::new(socket))))
+}
+
+/// The CAN bootloader protocol (AN3154) over any CAN bus
+pub struct CanConnection {
+    bus: Box<dyn CanBus>,
+    exchange: Exchange,
+}
+
+impl CanConnection {
+    pub fn new(bus: Box<dyn CanBus>) -> Self {
+        CanConnection {
+            bus,
+            exchange: Exchange::default(),
+        }
+    }
+
+    /// Send a command frame, for memory commands it carries the address
+    async fn send_command(
+        &mut self,
+        command: u8,
+        address: Option<u32>,
+        data: &[u8],
+    ) -> Result<(), DfuLoaderError> {
+        self.exchange = Exchange { command, address };
+        self.send(Stage::Command, command as u16, data).await?;
+        self.read_ack(Stage::Command).await
+    }
+
+    async fn send(&mut self, stage: Stage, id: u16, data: &[u8]) -> Result<(), DfuLoaderError> {
+        self.exchange.sent(stage, data);
+        let result = self.bus.send(id, data).await;
+        result.map_err(|e| self.exchange.fail(stage, e))
+    }
+
+    /// Wait for the next frame answering the current command
+    async fn receive(
+        &mut self,
+        stage: Stage,
+        duration: Duration,
+    ) -> Result<Vec<u8>, DfuLoaderError> {
+        let result = self
+            .receive_id(self.exchange.command as u16, duration)
+            .await;
+        let data = result.map_err(|e| self.exchange.fail(stage, e))?;
+        self.exchange.received(stage, &data);
+        Ok(data)
+    }
+
+    /// Wait for the next frame with identifier `id`
+    async fn receive_id(&mut self, id: u16, duration: Duration) -> io::Result<Vec<u8>> {
+        let bus = &mut self.bus;
+        with_timeout(duration, async {
+            loop {
+                let (received, data) = bus.receive().await?;
+                if received == id {
+                    return Ok(data);
+                }
+            }
+        })
+        .await
+    }
+
+    async fn read_ack(&mut self, stage: Stage) -> Result<(), DfuLoaderError> {
+        self.receive_ack(stage, RESPONSE_TIMEOUT).await
+    }
+
+    /// Erasing can take a while, wait longer for the final acknowledge
+    async fn wait_for_ack(&mut self) -> Result<(), DfuLoaderError> {
+        self.receive_ack(Stage::Ack, ERASE_TIMEOUT).await
+    }
+
+    async fn receive_ack(
+        &mut self,
+        stage: Stage,
+        duration: Duration,
+    ) -> Result<(), DfuLoaderError> {
+        match self.receive(stage, duration).await?.first() {
+            Some(&ACK) => Ok(()),
+            Some(&response) => Err(self.exchange.fail(stage, Failure::from_response(response))),
+            None => Err(self.exchange.fail(stage, Failure::Malformed)),
+        }
+    }
+
+    /// Receive single byte frames, the way Get and Get Version return their fields
+    async fn receive_byte(&mut self) -> Result<u8, DfuLoaderError> {
+        let frame = self.receive(Stage::Data, RESPONSE_TIMEOUT).await?;
+        match frame.as_slice() {
+            [byte] => Ok(*byte),
+            _ => Err(self.exchange.fail(Stage::Data, Failure::Malformed)),
+        }
+    }
+}
+
+#[async_trait]
+impl AsyncDfuLoader for CanConnection {
+    async fn initialize(&mut self) -> Result<(), DfuLoaderError> {
+        for _ in 0..10 {
+            trace::sync("->", &[]);
+            self.bus.send(SYNC_ID, &[]).await?;
+
+            let result = self.receive_id(SYNC_ID, RESPONSE_TIMEOUT).await;
+            if let Ok(response) = &result {
+                trace::sync("<-", response);
+            }
+            match result {
+                Err(e) if e.kind() == io::ErrorKind::TimedOut => (),
+                Ok(response) if matches!(response.first(), Some(&ACK) | Some(&NAK)) => {
+                    return Ok(())
+                }
+                Ok(_) => (),
+                Err(e) => return Err(e.into()),
+            }
+
+            sleep(Duration::from_millis(500)).await;
+        }
+        Err(SyncError())
+    }
+
+    async fn get_version(&mut self) -> Result<BootloaderOptions, DfuLoaderError> {
+        self.send_command(0x01, None, &[]).await?;
+
+        let version = self.receive_byte().await?;
+        let options = self.receive(Stage::Data, RESPONSE_TIMEOUT).await?;
+        if options.len() != 2 {
+            return Err(self.exchange.fail(Stage::Data, Failure::Malformed));
+        }
+        self.read_ack(Stage::Ack).await?;
+
+        Ok(BootloaderOptions {
+            version,
+            options: (options[0] as u16) << 8 | options[1] as u16,
+        })
+    }
+
+    async fn supported_functions(&mut self) -> Result<BootLoaderInfo, DfuLoaderError> {
+        self.send_command(0x00, None, &[]).await?;
+
+        let count = self.receive_byte().await?;
+        let version = self.receive_byte().await?;
+        let mut supported_functions = vec![];
+        for _ in 0..count {
+            supported_functions.push(Functions::from(self.receive_byte().await?));
+        }
+        self.read_ack(Stage::Ack).await?;
+
+        Ok(BootLoaderInfo {
+            version,
+            supported_functions,
+        })
+    }
+
+    async fn get_id(&mut self) -> Result<BootloaderChipId, DfuLoaderError> {
+        self.send_command(0x02, None, &[]).await?;
+
+        let id = self.receive(Stage::Data, RESPONSE_TIMEOUT).await?;
+        if id.len() != 2 {
+            return Err(self.exchange.fail(Stage::Data, Failure::Malformed));
+        }
+        self.read_ack(Stage::Ack).await?;
+
+        Ok(BootloaderChipId {
+            chipid: (id[0] as u16) << 8 | id[1] as u16,
+        })
+    }
+
+    async fn write_unprotect(&mut self) -> Result<(), DfuLoaderError> {
+        self.send_command(0x73, None, &[]).await?;
+        self.wait_for_ack().await
+    }
+
+    async fn read_memory(&mut self, address: u32, size: usize) -> Result<Vec<u8>, DfuLoaderError> {
+        if size > 256 || size == 0 {
+            return Err(InvalidRequest("read size must be 1 to 256 bytes"));
+        }
+
+        let mut request = address.to_be_bytes().to_vec();
+        request.push((size - 1) as u8);
+        self.send_command(0x11, Some(address), &request).await?;
+
+        let mut data = vec![];
+        while data.len() < size {
+            data.extend(self.receive(Stage::Data, RESPONSE_TIMEOUT).await?);
+        }
+        if data.len() != size {
+            return Err(self.exchange.fail(Stage::Data, Failure::Malformed));
+        }
+        self.read_ack(Stage::Ack).await?;
+
+        Ok(data)
+    }
+
+    async fn write_memory(&mut self, address: u32, data: Vec<u8>) -> Result<(), DfuLoaderError> {
+        if data.len() > 256 || data.is_empty() {
+            return Err(InvalidRequest("write size must be 1 to 256 bytes"));
+        }
+
+        let mut request = address.to_be_bytes().to_vec();
+        request.push((data.len() - 1) as u8);
+        self.send_command(0x31, Some(address), &request).await?;
+
+        // Every data frame is acknowledged, then once more when written
+        for frame in data.chunks(8) {
+            self.send(Stage::Data, WRITE_DATA_ID, frame).await?;
+            self.read_ack(Stage::Data).await?;
+        }
+        self.read_ack(Stage::Ack).await
+    }
+
+    async fn erase_all(&mut self) -> Result<(), DfuLoaderError> {
+        self.send_command(0x43, None, &[0xFF]).await?;
+        self.wait_for_ack().await
+    }
+
+    /// The CAN bootloader only has the Erase (0x43) command, with one byte page numbers
+    async fn erase_pages(&mut self, pages: &[u16]) -> Result<(), DfuLoaderError> {
+        if pages.is_empty() || pages.len() > 0xFF || pages.iter().any(|&p| p > 0xFF) {
+            return Err(InvalidRequest("CAN erase takes up to 255 pages below 256"));
+        }
+
+        self.send_command(0x43, None, &[(pages.len() - 1) as u8])
+            .await?;
+
+        let pages: Vec<u8> = pages.iter().map(|&p| p as u8).collect();
+        for frame in pages.chunks(8) {
+            self.send(Stage::Length, 0x43, frame).await?;
+        }
+        self.wait_for_ack().await
+    }
+
+    async fn go(&mut self, address: u32) -> Result<(), DfuLoaderError> {
+        self.send_command(0x21, Some(address), &address.to_be_bytes())
+            .await
+    }
+
+    /// Get Checksum is not part of the CAN protocol
+    async fn get_checksum(&mut self, _address: u32, _length: u32) -> Result<u32, DfuLoaderError> {
+        Err(NotImplemented())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dfuloader::CommandError;
+    use std::collections::VecDeque;
+    use std::sync::{Arc, Mutex};
+
+    type SentFrames = Arc<Mutex<Vec<(u16, Vec<u8>)>>>;
+
+    /// Replays the frames a device would send, records what the host sent
+    struct ScriptedBus {
+        responses: VecDeque<(u16, Vec<u8>)>,
+        sent: SentFrames,
+    }
+
+    #[async_trait]
+    impl CanBus for ScriptedBus {
+        async fn send(&mut self, id: u16, data: &[u8]) -> io::Result<()> {
+            self.sent.lock().unwrap().push((id, data.to_vec()));
+            Ok(())
+        }
+
+        async fn receive(&mut self) -> io::Result<(u16, Vec<u8>)> {
+            self.responses
+                .pop_front()
+                .ok_or_else(|| io::ErrorKind::TimedOut.into())
+        }
+    }
+
+    fn connection(responses: &[(u16, &[u8])]) -> (CanConnection, SentFrames) {
+        let sent = Arc::new(Mutex::new(vec![]));
+        let bus = ScriptedBus {
+            responses: responses.iter().map(|(i, d)| (*i, d.to_vec())).collect(),
+            sent: sent.clone(),
+        };
+        (CanConnection::new(Box::new(bus)), sent)
+    }
+
+    #[tokio::test]
+    async fn get_id_ignores_other_nodes() {
+        let (mut connection, sent) = connection(&[
+            (0x02, &[ACK]),
+            (0x123, &[1, 2, 3]),
+            (0x02, &[0x04, 0x13]),
+            (0x02, &[ACK]),
+        ]);
+
+        assert_eq!(connection.get_id().await.unwrap().chipid, 0x413);
+        assert_eq!(*sent.lock().unwrap(), vec![(0x02, vec![])]);
+    }
+
+    #[tokio::test]
+    async fn lists_supported_functions() {
+        let (mut connection, _) = connection(&[
+            (0x00, &[ACK]),
+            (0x00, &[3]),
+            (0x00, &[0x20]),
+            (0x00, &[0x00]),
+            (0x00, &[0x11]),
+            (0x00, &[0x43]),
+            (0x00, &[ACK]),
+        ]);
+
+        let info = connection.supported_functions().await.unwrap();
+        assert_eq!(info.version, 0x20);
+        assert!(info.supports(Functions::Erase));
+        assert_eq!(info.supported_functions.len(), 3);
+    }
+
+    #[tokio::test]
+    async fn write_memory_sends_data_in_frames_of_eight() {
+        let (mut connection, sent) = connection(&[
+            (0x31, &[ACK]),
+            (0x31, &[ACK]),
+            (0x31, &[ACK]),
+            (0x31, &[ACK]),
+        ]);
+
+        let data: Vec<u8> = (0..12).collect();
+        connection.write_memory(0x0800_0000, data).await.unwrap();
+
+        let sent = sent.lock().unwrap();
+        assert_eq!(sent[0], (0x31, vec![0x08, 0x00, 0x00, 0x00, 11]));
+        assert_eq!(sent[1], (WRITE_DATA_ID, (0..8).collect()));
+        assert_eq!(sent[2], (WRITE_DATA_ID, (8..12).collect()));
+    }
+
+    #[tokio::test]
+    async fn read_memory_reports_nack() {
+        let (mut connection, _) = connection(&[(0x11, &[NAK])]);
+
+        assert!(matches!(
+            connection.read_memory(0x0800_0000, 16).await,
+            Err(CommandFailed(CommandError {
+                command: 0x11,
+                stage: Stage::Command,
+                address: Some(0x0800_0000),
+                failure: Failure::Nack,
+            }))
+        ));
+    }
+}
@@ -0,0 +1,528 @@
+//! Record every byte of a session to a file and play it back later.
+//!
+//! A [Capture] wraps the transport of a session in a [Recording], which logs
+//! what goes over the wire with the time it happened. A [Replay] loads such
+//! a file and plays the device side back, so a session from the field can be
+//! reproduced without the hardware, or turned into a regression test.
+//!
+//! The file is plain text with one event per line, the time in microseconds
+//! since the capture was started, the direction seen from the host and the
+//! bytes in hex:
+//!
+//! ```text
+//! # stm32loader capture
+//! 0 > 7F
+//! 95 < 79
+//! 102 > 00FF
+//! 100310 < timeout
+//! 100412 = 5A0000 00A579
+//! 100530 = 00 A5
+//! 131022 * 4999
+//! ```
+//!
+//! `>` is sent and `<` received over a byte stream, `timeout` a read that got
+//! nothing in time. `=` is a transfer on a full duplex bus, sent and received
+//! bytes. `*` repeats the event before it this many more times, like the
+//! dummy bytes of a device polled while it erases.
+
+use crate::transport::{ByteStream, FullDuplex};
+use std::fs::{self, File};
+use std::io::{self, BufWriter, ErrorKind, Read, Write};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+const HEADER: &str = "# stm32loader capture";
+
+/// A capture file being written. Clones write to the same file, so a port
+/// opened more than once, like during baud rate negotiation, ends up in one
+/// capture.
+#[derive(Clone)]
+pub struct Capture {
+    file: Arc<Mutex<CaptureFile>>,
+}
+
+struct CaptureFile {
+    out: Box<dyn Write + Send>,
+    start: Instant,
+    /// The last event written, and how often it was repeated since
+    last: Option<(String, String)>,
+    repeats: u64,
+    /// The time of the last repeat
+    repeated_at: u128,
+}
+
+impl CaptureFile {
+    fn write_repeats(&mut self) -> io::Result<()> {
+        if self.repeats > 0 {
+            writeln!(self.out, "{} * {}", self.repeated_at, self.repeats)?;
+            self.repeats = 0;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for CaptureFile {
+    fn drop(&mut self) {
+        let _ = self.write_repeats().and_then(|_| self.out.flush());
+    }
+}
+
+impl Capture {
+    /// Start a capture file at `path`, replacing an existing one
+    pub fn create(path: &Path) -> io::Result<Self> {
+        Capture::new(BufWriter::new(File::create(path)?))
+    }
+
+    /// Start a capture written to `out`
+    pub fn new(out: impl Write + Send + 'static) -> io::Result<Self> {
+        let mut out: Box<dyn Write + Send> = Box::new(out);
+        writeln!(out, "{}", HEADER)?;
+        Ok(Capture {
+            file: Arc::new(Mutex::new(CaptureFile {
+                out,
+                start: Instant::now(),
+                last: None,
+                repeats: 0,
+                repeated_at: 0,
+            })),
+        })
+    }
+
+    /// Wrap a transport, everything that goes through it is recorded
+    pub fn record<T>(&self, transport: T) -> Recording<T> {
+        Recording {
+            transport,
+            capture: self.clone(),
+        }
+    }
+
+    /// Write an event, a repeat of the last one is only counted and the
+    /// count written with the next event or when the capture is closed
+    fn event(&self, direction: &str, data: &str) -> io::Result<()> {
+        let mut file = self.file.lock().unwrap();
+        let time = file.start.elapsed().as_micros();
+        if let Some((last_direction, last_data)) = &file.last {
+            if last_direction == direction && last_data == data {
+                file.repeats += 1;
+                file.repeated_at = time;
+                return Ok(());
+            }
+        }
+        file.write_repeats()?;
+        writeln!(file.out, "{} {} {}", time, direction, data)?;
+        file.last = Some((direction.to_string(), data.to_string()));
+        // Flush every event, the interesting captures end with a crash
+        file.out.flush()
+    }
+}
+
+/// A transport that records what goes through it, see [Capture::record]
+pub struct Recording<T> {
+    transport: T,
+    capture: Capture,
+}
+
+impl<T: Read> Read for Recording<T> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self.transport.read(buf) {
+            Ok(n) => {
+                self.capture.event("<", &hex(&buf[..n]))?;
+                Ok(n)
+            }
+            Err(err) if err.kind() == ErrorKind::TimedOut => {
+                self.capture.event("<", "timeout")?;
+                Err(err)
+            }
+            Err(err) => Err(err),
+        }
+    }
+}
+
+impl<T: Write> Write for Recording<T> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.transport.write(buf)?;
+        self.capture.event(">", &hex(&buf[..n]))?;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.transport.flush()
+    }
+}
+
+impl<T: Read + Write + Send> ByteStream for Recording<T> {}
+
+impl<T: FullDuplex> FullDuplex for Recording<T> {
+    fn transfer(&mut self, tx: &[u8], rx: &mut [u8]) -> io::Result<()> {
+        self.transport.transfer(tx, rx)?;
+        self.capture.event("=", &format!("{} {}", hex(tx), hex(rx)))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Event {
+    Sent(Vec<u8>),
+    Received(Vec<u8>),
+    Timeout,
+    Transfer(Vec<u8>, Vec<u8>),
+}
+
+/// Plays the device side of a capture back.
+///
+/// What the host sends has to match the capture byte for byte, otherwise the
+/// replay fails with [ErrorKind::InvalidData] naming the line where the
+/// session went a different way. Clones share the position in the capture.
+#[derive(Clone)]
+pub struct Replay {
+    state: Arc<Mutex<ReplayState>>,
+}
+
+struct ReplayState {
+    /// The events with their time and line in the file
+    events: Vec<(u64, usize, Event)>,
+    next: usize,
+    /// Bytes of the next event already sent or received
+    offset: usize,
+    timing: bool,
+    start: Option<Instant>,
+}
+
+impl Replay {
+    pub fn load(path: &Path) -> io::Result<Self> {
+        Replay::parse(&fs::read_to_string(path)?)
+    }
+
+    /// Read a capture from its text
+    pub fn parse(capture: &str) -> io::Result<Self> {
+        let mut events = vec![];
+        for (index, line) in capture.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let invalid = || {
+                io::Error::new(
+                    ErrorKind::InvalidData,
+                    format!("malformed capture at line {}", index + 1),
+                )
+            };
+            let mut fields = line.split_whitespace();
+            let time = fields.next().and_then(|t| t.parse().ok());
+            if let (Some(time), Some("*"), Some(count), None) =
+                (time, fields.next(), fields.next(), fields.next())
+            {
+                let count: usize = count.parse().map_err(|_| invalid())?;
+                let (_, _, event) = events.last().cloned().ok_or_else(invalid)?;
+                events.extend(std::iter::repeat_n((time, index + 1, event), count));
+                continue;
+            }
+            let mut fields = line.split_whitespace().skip(1);
+            let event = match (fields.next(), fields.next(), fields.next()) {
+                (Some("<"), Some("timeout"), None) => Some(Event::Timeout),
+                (Some(">"), Some(data), None) => unhex(data).map(Event::Sent),
+                (Some("<"), Some(data), None) => unhex(data).map(Event::Received),
+                (Some("="), Some(tx), Some(rx)) => match (unhex(tx), unhex(rx)) {
+                    (Some(tx), Some(rx)) if tx.len() == rx.len() => Some(Event::Transfer(tx, rx)),
+                    _ => None,
+                },
+                _ => None,
+            };
+            match (time, event) {
+                (Some(time), Some(event)) => events.push((time, index + 1, event)),
+                _ => return Err(invalid()),
+            }
+        }
+
+        Ok(Replay {
+            state: Arc::new(Mutex::new(ReplayState {
+                events,
+                next: 0,
+                offset: 0,
+                timing: false,
+                start: None,
+            })),
+        })
+    }
+
+    /// Answer with the delays of the capture instead of right away
+    pub fn with_timing(self) -> Self {
+        self.state.lock().unwrap().timing = true;
+        self
+    }
+
+    /// Whether the capture is from a full duplex bus, that is SPI
+    pub fn is_full_duplex(&self) -> bool {
+        let state = self.state.lock().unwrap();
+        matches!(state.events.first(), Some((_, _, Event::Transfer(..))))
+    }
+
+    /// Whether every event of the capture has been played
+    pub fn is_finished(&self) -> bool {
+        let state = self.state.lock().unwrap();
+        state.next == state.events.len()
+    }
+}
+
+impl ReplayState {
+    /// The next event, after waiting for its time when replaying with timing
+    fn peek(&mut self) -> io::Result<(usize, Event)> {
+        let (time, line, event) = match self.events.get(self.next) {
+            Some(event) => event.clone(),
+            None => return Err(io::Error::new(ErrorKind::UnexpectedEof, "end of capture")),
+        };
+        // Only the device side keeps time, the host sends when it is ready
+        if self.timing && !matches!(event, Event::Sent(_)) {
+            let first = self.events[0].0;
+            let start = *self.start.get_or_insert_with(Instant::now);
+            let due = start + Duration::from_micros(time.saturating_sub(first));
+            sleep(due.saturating_duration_since(Instant::now()));
+        }
+        Ok((line, event))
+    }
+
+    fn advance(&mut self) {
+        self.next += 1;
+        self.offset = 0;
+    }
+}
+
+fn diverged(line: usize, expected: &Event, actual: &str) -> io::Error {
+    let expected = match expected {
+        Event::Sent(data) => format!("the host to send {}", excerpt(data)),
+        Event::Received(data) => format!("the host to receive {}", excerpt(data)),
+        Event::Timeout => "a read timeout".to_string(),
+        Event::Transfer(tx, _) => format!("a transfer of {}", excerpt(tx)),
+    };
+    io::Error::new(
+        ErrorKind::InvalidData,
+        format!(
+            "session differs from the capture at line {}: expected {}, got {}",
+            line, expected, actual
+        ),
+    )
+}
+
+impl Read for Replay {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut state = self.state.lock().unwrap();
+        match state.peek()? {
+            (_, Event::Received(data)) => {
+                let n = buf.len().min(data.len() - state.offset);
+                buf[..n].copy_from_slice(&data[state.offset..state.offset + n]);
+                state.offset += n;
+                if state.offset == data.len() {
+                    state.advance();
+                }
+                Ok(n)
+            }
+            (_, Event::Timeout) => {
+                state.advance();
+                Err(ErrorKind::TimedOut.into())
+            }
+            (line, event) => Err(diverged(line, &event, "a read")),
+        }
+    }
+}
+
+impl Write for Replay {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut state = self.state.lock().unwrap();
+        let mut written = 0;
+        while written < buf.len() {
+            match state.peek()? {
+                (line, Event::Sent(data)) => {
+                    let n = (buf.len() - written).min(data.len() - state.offset);
+                    let sent = &buf[written..written + n];
+                    if sent != &data[state.offset..state.offset + n] {
+                        let actual = format!("{} sent", excerpt(&buf[written..]));
+                        return Err(diverged(line, &Event::Sent(data), &actual));
+                    }
+                    written += n;
+                    state.offset += n;
+                    if state.offset == data.len() {
+                        state.advance();
+                    }
+                }
+                (line, event) => {
+                    let actual = format!("{} sent", excerpt(&buf[written..]));
+                    return Err(diverged(line, &event, &actual));
+                }
+            }
+        }
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl ByteStream for Replay {}
+
+impl FullDuplex for Replay {
+    fn transfer(&mut self, tx: &[u8], rx: &mut [u8]) -> io::Result<()> {
+        let mut state = self.state.lock().unwrap();
+        match state.peek()? {
+            (_, Event::Transfer(sent, received)) if sent == tx && received.len() == rx.len() => {
+                rx.copy_from_slice(&received);
+                state.advance();
+                Ok(())
+            }
+            (line, event) => Err(diverged(
+                line,
+                &event,
+                &format!("a transfer of {}", excerpt(tx)),
+            )),
+        }
+    }
+}
+
+fn hex(data: &[u8]) -> String {
+    data.iter().map(|b| format!("{:02X}", b)).collect()
+}
+
+/// The start of a long frame, for error messages
+fn excerpt(data: &[u8]) -> String {
+    match data.len() {
+        0..=16 => hex(data),
+        _ => format!("{}...", hex(&data[..16])),
+    }
+}
+
+fn unhex(text: &str) -> Option<Vec<u8>> {
+    if !text.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..text.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(text.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dfuloader::DfuLoader;
+    use crate::emulator::EmulatedDevice;
+    use crate::serial::SerialConnection;
+    use crate::spi::SpiConnection;
+
+    const FLASH: u32 = 0x0800_0000;
+
+    /// A capture written to memory
+    #[derive(Clone, Default)]
+    struct Buffer(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Buffer {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Buffer {
+        fn text(&self) -> String {
+            String::from_utf8(self.0.lock().unwrap().clone()).unwrap()
+        }
+    }
+
+    fn session(connection: &mut dyn DfuLoader) -> (u16, Vec<u8>) {
+        connection.initialize().unwrap();
+        let chip_id = connection.get_id().unwrap().chipid;
+        connection.write_memory(FLASH, vec![1, 2, 3, 4]).unwrap();
+        (chip_id, connection.read_memory(FLASH, 4).unwrap())
+    }
+
+    #[test]
+    fn replays_a_serial_session() {
+        let device = EmulatedDevice::new(0x433).unwrap();
+        let buffer = Buffer::default();
+        let capture = Capture::new(buffer.clone()).unwrap();
+        let mut connection = SerialConnection::new(Box::new(capture.record(device.usart())));
+        let recorded = session(&mut connection);
+
+        let replay = Replay::parse(&buffer.text()).unwrap();
+        let mut connection = SerialConnection::new(Box::new(replay.clone()));
+        assert_eq!(session(&mut connection), recorded);
+        assert!(replay.is_finished());
+        assert!(!replay.is_full_duplex());
+    }
+
+    #[test]
+    fn replays_an_spi_session() {
+        let device = EmulatedDevice::new(0x433).unwrap();
+        let buffer = Buffer::default();
+        let capture = Capture::new(buffer.clone()).unwrap();
+        let mut connection = SpiConnection::new(Box::new(capture.record(device.spi())));
+        connection.initialize().unwrap();
+        connection.write_memory(FLASH, vec![1, 2, 3, 4]).unwrap();
+
+        let replay = Replay::parse(&buffer.text()).unwrap();
+        let mut connection = SpiConnection::new(Box::new(replay.clone()));
+        connection.initialize().unwrap();
+        connection.write_memory(FLASH, vec![1, 2, 3, 4]).unwrap();
+        assert!(replay.is_finished());
+        assert!(replay.is_full_duplex());
+    }
+
+    /// A bus that answers dummy bytes to the first polls, then ACK
+    struct Busy(usize);
+
+    impl FullDuplex for Busy {
+        fn transfer(&mut self, _tx: &[u8], rx: &mut [u8]) -> io::Result<()> {
+            self.0 = self.0.saturating_sub(1);
+            rx.fill(if self.0 == 0 { 0x79 } else { 0xA5 });
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn collapses_repeated_events() {
+        let buffer = Buffer::default();
+        let capture = Capture::new(buffer.clone()).unwrap();
+        let mut bus = capture.record(Busy(5));
+        let mut rx = [0u8];
+        for _ in 0..4 {
+            bus.transfer(&[0x00], &mut rx).unwrap();
+        }
+        assert_eq!(buffer.text().lines().count(), 2);
+        bus.transfer(&[0x00], &mut rx).unwrap();
+
+        let text = buffer.text();
+        let lines: Vec<_> = text
+            .lines()
+            .skip(1)
+            .map(|line| line.split_once(' ').unwrap().1)
+            .collect();
+        assert_eq!(lines, ["= 00 A5", "* 3", "= 00 79"]);
+
+        let mut replay = Replay::parse(&text).unwrap();
+        for expected in [0xA5, 0xA5, 0xA5, 0xA5, 0x79] {
+            replay.transfer(&[0x00], &mut rx).unwrap();
+            assert_eq!(rx[0], expected);
+        }
+        assert!(replay.is_finished());
+    }
+
+    #[test]
+    fn reports_where_the_session_differs() {
+        let device = EmulatedDevice::new(0x433).unwrap();
+        let buffer = Buffer::default();
+        let capture = Capture::new(buffer.clone()).unwrap();
+        let mut connection = SerialConnection::new(Box::new(capture.record(device.usart())));
+        session(&mut connection);
+
+        let mut connection =
+            SerialConnection::new(Box::new(Replay::parse(&buffer.text()).unwrap()));
+        connection.initialize().unwrap();
+        let err = connection.go(FLASH).unwrap_err();
+        assert!(err
+            .to_string()
+            .contains("session differs from the capture at line 4"));
+    }
+}
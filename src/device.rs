@@ -0,0 +1,209 @@
+/// Properties of a device family that influence how it is programmed,
+/// looked up by the product id returned by the Get ID command.
+#[derive(Debug)]
+pub struct DeviceProfile {
+    pub pid: u16,
+    pub name: &'static str,
+    /// Smallest unit the flash can be programmed in, writes must start
+    /// and end on a multiple of this
+    pub write_alignment: u32,
+    pub flash_start: u32,
+    /// Address of the 96-bit unique device id
+    pub uid_address: Option<u32>,
+    /// Address of the flash size register, the size of this part in KiB
+    pub flash_size_address: Option<u32>,
+    /// Where the option bytes can be read, not memory mapped on every family
+    pub option_bytes: Option<Area>,
+    /// Address of the package data register
+    pub package_address: Option<u32>,
+    /// Erase pages (or sectors) of the largest flash size in the family,
+    /// numbered in order as expected by the Extended Erase command
+    pub pages: &'static [PageRegion],
+}
+
+/// A run of equally sized pages
+#[derive(Debug)]
+pub struct PageRegion {
+    pub count: u16,
+    pub size: u32,
+}
+
+/// A range of addresses
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Area {
+    pub address: u32,
+    pub length: u32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Page {
+    pub index: u16,
+    pub address: u32,
+    pub size: u32,
+}
+
+impl DeviceProfile {
+    pub fn pages(&self) -> Vec<Page> {
+        let mut pages = vec![];
+        let mut address = self.flash_start;
+        for region in self.pages {
+            for _ in 0..region.count {
+                pages.push(Page {
+                    index: pages.len() as u16,
+                    address,
+                    size: region.size,
+                });
+                address += region.size;
+            }
+        }
+        pages
+    }
+}
+
+const K: u32 = 1024;
+
+const fn pages(count: u16, size: u32) -> PageRegion {
+    PageRegion { count, size }
+}
+
+const fn device(
+    pid: u16,
+    name: &'static str,
+    write_alignment: u32,
+    pages: &'static [PageRegion],
+) -> DeviceProfile {
+    DeviceProfile {
+        pid,
+        name,
+        write_alignment,
+        flash_start: 0x0800_0000,
+        uid_address: uid_address(pid),
+        flash_size_address: flash_size_address(pid),
+        option_bytes: option_bytes(pid),
+        package_address: package_address(pid),
+        pages,
+    }
+}
+
+/// The unique device id moved around between the families
+const fn uid_address(pid: u16) -> Option<u32> {
+    match pid {
+        0x440 | 0x442 | 0x444 | 0x445 | 0x448 | 0x422 => Some(0x1FFF_F7AC),
+        0x410 | 0x414 | 0x430 => Some(0x1FFF_F7E8),
+        0x411 | 0x413 | 0x419 | 0x423 | 0x433 | 0x431 | 0x421 => Some(0x1FFF_7A10),
+        0x449 | 0x451 => Some(0x1FF0_F420),
+        0x466 | 0x460 | 0x468 | 0x469 | 0x479 => Some(0x1FFF_7590),
+        0x435 | 0x462 | 0x415 | 0x461 | 0x470 | 0x495 => Some(0x1FFF_7590),
+        0x450 | 0x483 => Some(0x1FF1_E800),
+        0x480 => Some(0x08FF_F800),
+        0x417 | 0x447 | 0x416 => Some(0x1FF8_0050),
+        0x482 | 0x481 => Some(0x0BFA_0700),
+        _ => None,
+    }
+}
+
+/// The profiles describe the largest part of a family, this register tells
+/// the size of the one at hand
+const fn flash_size_address(pid: u16) -> Option<u32> {
+    match pid {
+        0x440 | 0x442 | 0x444 | 0x445 | 0x448 | 0x422 => Some(0x1FFF_F7CC),
+        0x410 | 0x414 | 0x430 => Some(0x1FFF_F7E0),
+        0x411 | 0x413 | 0x419 | 0x423 | 0x433 | 0x431 | 0x421 => Some(0x1FFF_7A22),
+        0x449 | 0x451 => Some(0x1FF0_F442),
+        0x466 | 0x460 | 0x468 | 0x469 | 0x479 => Some(0x1FFF_75E0),
+        0x435 | 0x462 | 0x415 | 0x461 | 0x470 | 0x495 => Some(0x1FFF_75E0),
+        0x450 | 0x483 => Some(0x1FF1_E880),
+        0x480 => Some(0x08FF_F80C),
+        0x417 | 0x447 => Some(0x1FF8_007C),
+        0x416 => Some(0x1FF8_004C),
+        0x482 | 0x481 => Some(0x0BFA_07A0),
+        _ => None,
+    }
+}
+
+const fn option_bytes(pid: u16) -> Option<Area> {
+    let (address, length) = match pid {
+        0x440 | 0x442 | 0x444 | 0x445 | 0x448 | 0x410 | 0x414 | 0x430 | 0x422 => (0x1FFF_F800, 16),
+        0x411 | 0x413 | 0x419 | 0x423 | 0x433 | 0x431 | 0x421 => (0x1FFF_C000, 16),
+        0x449 | 0x451 => (0x1FFF_0000, 32),
+        0x417 | 0x447 | 0x416 => (0x1FF8_0000, 32),
+        0x466 | 0x460 | 0x468 | 0x469 | 0x479 => (0x1FFF_7800, 40),
+        0x435 | 0x462 | 0x415 | 0x461 | 0x470 | 0x495 => (0x1FFF_7800, 40),
+        // Only reachable through the flash registers on H7 and U5
+        _ => return None,
+    };
+    Some(Area { address, length })
+}
+
+/// Only the newer families tell the package apart
+const fn package_address(pid: u16) -> Option<u32> {
+    match pid {
+        0x466 | 0x460 | 0x468 | 0x469 | 0x479 => Some(0x1FFF_7500),
+        0x435 | 0x462 | 0x415 | 0x461 | 0x470 => Some(0x1FFF_7500),
+        _ => None,
+    }
+}
+
+/// Used when the product id is unknown, the alignment is safe for all
+/// families except the ones with flash words larger than a double-word.
+/// Without a page layout only full erase is possible.
+pub const GENERIC: DeviceProfile = device(0x000, "Unknown STM32", 8, &[]);
+
+const F2_F4_1M: &[PageRegion] = &[pages(4, 16 * K), pages(1, 64 * K), pages(7, 128 * K)];
+const F4_512K: &[PageRegion] = &[pages(4, 16 * K), pages(1, 64 * K), pages(3, 128 * K)];
+const F4_256K: &[PageRegion] = &[pages(4, 16 * K), pages(1, 64 * K), pages(1, 128 * K)];
+const F42_2M: &[PageRegion] = &[
+    pages(4, 16 * K),
+    pages(1, 64 * K),
+    pages(7, 128 * K),
+    pages(4, 16 * K),
+    pages(1, 64 * K),
+    pages(7, 128 * K),
+];
+const F74_1M: &[PageRegion] = &[pages(4, 32 * K), pages(1, 128 * K), pages(3, 256 * K)];
+const F76_2M: &[PageRegion] = &[pages(4, 32 * K), pages(1, 128 * K), pages(7, 256 * K)];
+
+/// Known devices, see AN2606 for the product ids
+pub const DEVICES: &[DeviceProfile] = &[
+    device(0x440, "STM32F030x8/F05x", 2, &[pages(64, K)]),
+    device(0x442, "STM32F030xC/F09x", 2, &[pages(128, 2 * K)]),
+    device(0x444, "STM32F03xx4/6", 2, &[pages(32, K)]),
+    device(0x445, "STM32F04xxx/F070x6", 2, &[pages(32, K)]),
+    device(0x448, "STM32F070xB/F071xx/F072xx", 2, &[pages(64, 2 * K)]),
+    device(0x410, "STM32F10xxx Medium-density", 2, &[pages(128, K)]),
+    device(0x414, "STM32F10xxx High-density", 2, &[pages(256, 2 * K)]),
+    device(0x430, "STM32F10xxx XL-density", 2, &[pages(512, 2 * K)]),
+    device(0x411, "STM32F2xxxx", 1, F2_F4_1M),
+    device(0x422, "STM32F302xB(C)/F303xB(C)", 2, &[pages(128, 2 * K)]),
+    device(0x413, "STM32F40xxx/41xxx", 1, F2_F4_1M),
+    device(0x419, "STM32F42xxx/43xxx", 1, F42_2M),
+    device(0x423, "STM32F401xB(C)", 1, F4_256K),
+    device(0x433, "STM32F401xD(E)", 1, F4_512K),
+    device(0x431, "STM32F411xx", 1, F4_512K),
+    device(0x421, "STM32F446xx", 1, F4_512K),
+    device(0x449, "STM32F74xxx/75xxx", 1, F74_1M),
+    device(0x451, "STM32F76xxx/77xxx", 1, F76_2M),
+    device(0x466, "STM32G03xxx/04xxx", 8, &[pages(32, 2 * K)]),
+    device(0x460, "STM32G07xxx/08xxx", 8, &[pages(64, 2 * K)]),
+    device(0x468, "STM32G431xx/441xx", 8, &[pages(64, 2 * K)]),
+    device(0x469, "STM32G47xxx/48xxx", 8, &[pages(256, 2 * K)]),
+    device(0x479, "STM32G491xx/4A1xx", 8, &[pages(256, 2 * K)]),
+    device(0x450, "STM32H74xxx/75xxx", 32, &[pages(16, 128 * K)]),
+    device(0x483, "STM32H72xxx/73xxx", 32, &[pages(8, 128 * K)]),
+    device(0x480, "STM32H7A3xx/B3xx", 16, &[pages(256, 8 * K)]),
+    device(0x417, "STM32L05xxx/06xxx", 4, &[pages(512, 128)]),
+    device(0x447, "STM32L07xxx/08xxx", 4, &[pages(1536, 128)]),
+    device(0x416, "STM32L1xxx6(8/B)", 4, &[pages(512, 256)]),
+    device(0x435, "STM32L43xxx/44xxx", 8, &[pages(128, 2 * K)]),
+    device(0x462, "STM32L45xxx/46xxx", 8, &[pages(256, 2 * K)]),
+    device(0x415, "STM32L47xxx/48xxx", 8, &[pages(512, 2 * K)]),
+    device(0x461, "STM32L496xx/4A6xx", 8, &[pages(512, 2 * K)]),
+    device(0x470, "STM32L4Rxxx/4Sxxx", 8, &[pages(512, 4 * K)]),
+    device(0x482, "STM32U575xx/585xx", 16, &[pages(256, 8 * K)]),
+    device(0x481, "STM32U59xxx/5Axxx", 16, &[pages(512, 8 * K)]),
+    device(0x495, "STM32WB5xxx/35xx", 8, &[pages(256, 4 * K)]),
+];
+
+pub fn lookup(pid: u16) -> Option<&'static DeviceProfile> {
+    DEVICES.iter().find(|d| d.pid == pid)
+}
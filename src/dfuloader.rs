@@ -1,5 +1,14 @@
+use std::time::Duration;
 use std::{error::Error, fmt::Display, fmt::Formatter};
-use crate::dfuloader::DfuLoaderError::ProtocolError;
+
+pub(crate) const ACK: u8 = 0x79;
+pub(crate) const NAK: u8 = 0x1F;
+/// Only the SPI and I2C protocols answer BUSY
+pub(crate) const BUSY: u8 = 0x76;
+
+/// How long an erase or unprotect may take before the device answers, a
+/// mass erase of 2 MB takes up to 32 s
+pub(crate) const ERASE_TIMEOUT: Duration = Duration::from_secs(40);
 
 pub trait DfuLoader {
     fn initialize(&mut self) -> Result<(), DfuLoaderError>;
@@ -13,15 +22,22 @@ pub trait DfuLoader {
 
     fn write_unprotect(&mut self) -> Result<(), DfuLoaderError>;
 
-    fn read_memory(&mut self, address: u32, size: u8) -> Result<Vec<u8>, DfuLoaderError>;
+    fn read_memory(&mut self, address: u32, size: usize) -> Result<Vec<u8>, DfuLoaderError>;
     fn write_memory(&mut self, address: u32, data: Vec<u8>) -> Result<(), DfuLoaderError>;
 
     fn erase_all(&mut self) -> Result<(), DfuLoaderError>;
 
+    /// Erase individual pages (or sectors) with the Extended Erase command
+    fn erase_pages(&mut self, pages: &[u16]) -> Result<(), DfuLoaderError>;
+
     fn go(&mut self, address: u32) -> Result<(), DfuLoaderError>;
+
+    /// CRC-32 of a word aligned memory area calculated by the device,
+    /// see [crate::programmer::crc32] for the algorithm
+    fn get_checksum(&mut self, address: u32, length: u32) -> Result<u32, DfuLoaderError>;
 }
 
-#[derive(Debug)]
+#[derive(Debug, PartialEq)]
 pub enum Functions {
     Get,
     GetVersion,
@@ -45,11 +61,52 @@ pub enum Functions {
 pub enum DfuLoaderError {
     SyncError(),
     AlreadySynced(),
-    ProtocolError(),
-    IOError(std::io::Error),
     NotImplemented(),
-    Timeout(),
-    CommandFailed(u8),
+    /// A request the protocol cannot express, like reading more than 256 bytes
+    InvalidRequest(&'static str),
+    /// An I/O error outside of a command, e.g. while synchronising
+    IOError(std::io::Error),
+    CommandFailed(CommandError),
+}
+
+/// A failed command, with the point in the exchange where it failed
+#[derive(Debug)]
+pub struct CommandError {
+    pub command: u8,
+    pub stage: Stage,
+    /// The address the command was sent for, if it got that far
+    pub address: Option<u32>,
+    pub failure: Failure,
+}
+
+/// The parts of a command exchange
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Stage {
+    /// Sending the command code
+    Command,
+    /// Sending the address
+    Address,
+    /// Sending a length, count or page list
+    Length,
+    /// Transferring the payload
+    Data,
+    /// Waiting for the final acknowledge, e.g. while erasing
+    Ack,
+}
+
+#[derive(Debug)]
+pub enum Failure {
+    /// The device answered NACK (0x1F)
+    Nack,
+    /// The device answered BUSY (0x76), only the SPI and I2C protocols have this
+    Busy,
+    /// An unexpected byte where ACK or NACK was expected
+    Garbage(u8),
+    /// A response with the wrong length or checksum
+    Malformed,
+    /// No response in time
+    Timeout,
+    IO(std::io::Error),
 }
 #[derive(Debug)]
 pub struct BootLoaderInfo {
@@ -57,31 +114,157 @@ pub struct BootLoaderInfo {
     pub supported_functions: Vec<Functions>,
 }
 
+impl BootLoaderInfo {
+    pub fn supports(&self, function: Functions) -> bool {
+        self.supported_functions.contains(&function)
+    }
+}
+
 #[derive(Debug)]
 pub struct BootloaderOptions {
     pub version: u8,
-    pub options: u16
+    pub options: u16,
 }
 
 #[derive(Debug)]
 pub struct BootloaderChipId {
-    pub chipid: u16
+    pub chipid: u16,
 }
 
-impl Error for DfuLoaderError {}
+/// The command in progress, so the protocols can put a failure in context
+#[derive(Debug, Default, Clone, Copy)]
+pub(crate) struct Exchange {
+    pub command: u8,
+    pub address: Option<u32>,
+}
+
+impl Exchange {
+    pub fn start(command: u8) -> Self {
+        Exchange {
+            command,
+            address: None,
+        }
+    }
+
+    pub fn fail(&self, stage: Stage, failure: impl Into<Failure>) -> DfuLoaderError {
+        DfuLoaderError::command(self.command, stage, self.address, failure.into())
+    }
+}
+
+impl DfuLoaderError {
+    pub fn command(command: u8, stage: Stage, address: Option<u32>, failure: Failure) -> Self {
+        DfuLoaderError::CommandFailed(CommandError {
+            command,
+            stage,
+            address,
+            failure,
+        })
+    }
+
+    /// What went wrong if a command failed
+    pub fn failure(&self) -> Option<&Failure> {
+        match self {
+            DfuLoaderError::CommandFailed(err) => Some(&err.failure),
+            _ => None,
+        }
+    }
+
+    pub fn is_timeout(&self) -> bool {
+        matches!(self.failure(), Some(Failure::Timeout))
+    }
+
+    /// A failure a retry may get past: a frame that was rejected, garbled or
+    /// lost, as opposed to a refused request or a broken link
+    pub fn is_transient(&self) -> bool {
+        matches!(
+            self.failure(),
+            Some(
+                Failure::Nack
+                    | Failure::Busy
+                    | Failure::Garbage(_)
+                    | Failure::Malformed
+                    | Failure::Timeout
+            )
+        )
+    }
+}
+
+impl Failure {
+    /// Classify a byte received where an ACK was expected
+    pub fn from_response(response: u8) -> Self {
+        match response {
+            NAK => Failure::Nack,
+            BUSY => Failure::Busy,
+            _ => Failure::Garbage(response),
+        }
+    }
+}
+
+impl Error for DfuLoaderError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            DfuLoaderError::IOError(err) => Some(err),
+            DfuLoaderError::CommandFailed(CommandError {
+                failure: Failure::IO(err),
+                ..
+            }) => Some(err),
+            _ => None,
+        }
+    }
+}
 
 impl Display for DfuLoaderError {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         match self {
-            DfuLoaderError::ProtocolError() => write!(f, "Protocol error"),
             DfuLoaderError::AlreadySynced() => write!(f, "Connection already synced, dfu ready"),
             DfuLoaderError::SyncError() => {
                 write!(f, "Failed to sync connection, no bootloader detected")
             }
-            DfuLoaderError::IOError(io_err) => write!(f, "I/O error: {}", io_err),
             DfuLoaderError::NotImplemented() => write!(f, "Not implemented"),
-            DfuLoaderError::Timeout() => write!(f, "Timeout"),
-            DfuLoaderError::CommandFailed(x) => write!(f, "Command failed: {:02X}", x),
+            DfuLoaderError::InvalidRequest(reason) => write!(f, "Invalid request: {}", reason),
+            DfuLoaderError::IOError(io_err) => write!(f, "I/O error: {}", io_err),
+            DfuLoaderError::CommandFailed(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl Display for CommandError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} (0x{:02X})",
+            Functions::from(self.command),
+            self.command
+        )?;
+        if let Some(address) = self.address {
+            write!(f, " at {:#010X}", address)?;
+        }
+        write!(f, " failed during {}: {}", self.stage, self.failure)
+    }
+}
+
+impl Display for Stage {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            Stage::Command => "command",
+            Stage::Address => "address",
+            Stage::Length => "length",
+            Stage::Data => "data",
+            Stage::Ack => "ack",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+impl Display for Failure {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Failure::Nack => write!(f, "NACK"),
+            Failure::Busy => write!(f, "BUSY"),
+            Failure::Garbage(x) => write!(f, "unexpected response 0x{:02X}", x),
+            Failure::Malformed => write!(f, "malformed response"),
+            Failure::Timeout => write!(f, "timeout"),
+            Failure::IO(io_err) => write!(f, "I/O error: {}", io_err),
         }
     }
 }
@@ -92,6 +275,15 @@ impl From<std::io::Error> for DfuLoaderError {
     }
 }
 
+impl From<std::io::Error> for Failure {
+    fn from(err: std::io::Error) -> Self {
+        match err.kind() {
+            std::io::ErrorKind::TimedOut => Failure::Timeout,
+            _ => Failure::IO(err),
+        }
+    }
+}
+
 impl From<u8> for Functions {
     fn from(value: u8) -> Self {
         match value {
@@ -110,7 +302,7 @@ impl From<u8> for Functions {
             0x82 => Functions::ReadoutProtect,
             0x92 => Functions::ReadoutUnprotect,
             0xA1 => Functions::GetChecksum,
-            _ => Functions::Unknown(value)
+            _ => Functions::Unknown(value),
         }
     }
 }
@@ -133,8 +325,8 @@ impl Display for Functions {
             Functions::ReadoutProtect => "ReadoutProtect",
             Functions::ReadoutUnprotect => "ReadoutUnprotect",
             Functions::GetChecksum => "GetChecksum",
-            Functions::Unknown(_) => "Unknown {}",
+            Functions::Unknown(x) => return write!(f, "Unknown {:02X}", x),
         };
         write!(f, "{}", name)
     }
-}
\ No newline at end of file
+}
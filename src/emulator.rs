@@ -0,0 +1,748 @@
+//! An in-process STM32 system memory bootloader, speaking the USART (AN3155)
+//! and SPI (AN4286) protocols against a simulated flash.
+//!
+//! The device state is shared, so a test can connect, program and then
+//! inspect the flash:
+//!
+//! ```ignore
+//! let device = EmulatedDevice::new(0x433).unwrap();
+//! let mut connection = SerialConnection::new(Box::new(device.usart()));
+//! ```
+
+use crate::device::{self, DeviceProfile, Page};
+use crate::dfuloader::{ACK, NAK};
+use crate::programmer::crc32;
+use crate::transport::{ByteStream, FullDuplex};
+use std::collections::VecDeque;
+use std::io;
+use std::io::{Read, Write};
+use std::sync::{Arc, Mutex, MutexGuard};
+
+const SYNC_USART: u8 = 0x7F;
+const SOF_SPI: u8 = 0x5A;
+const DUMMY_SPI: u8 = 0xA5;
+
+/// Commands of a bootloader with protocol version 3.1
+pub(crate) const DEFAULT_COMMANDS: &[u8] = &[
+    0x00, 0x01, 0x02, 0x11, 0x21, 0x31, 0x44, 0x63, 0x73, 0x82, 0x92,
+];
+
+/// Unique device id of every emulated device
+const DEFAULT_UID: [u8; 12] = [
+    0x34, 0x00, 0x22, 0x00, 0x13, 0x50, 0x4B, 0x4E, 0x34, 0x33, 0x36, 0x20,
+];
+
+/// Package data register of every emulated device that has one
+const DEFAULT_PACKAGE: u16 = 0x0002;
+
+/// A simulated device, cloning it gives another handle to the same device
+#[derive(Clone)]
+pub struct EmulatedDevice {
+    target: Arc<Mutex<Target>>,
+}
+
+struct Memory {
+    address: u32,
+    data: Vec<u8>,
+}
+
+struct Target {
+    profile: &'static DeviceProfile,
+    /// The product id Get ID answers, the one of the profile unless changed
+    pid: u16,
+    version: u8,
+    commands: Vec<u8>,
+    flash: Memory,
+    pages: Vec<Page>,
+    /// Read-only areas like the option bytes and device registers
+    system: Vec<Memory>,
+    readout_protected: bool,
+    write_protected: bool,
+    jumped_to: Option<u32>,
+    /// Incremented on every reset, the ports drop their sync when it changes
+    resets: u32,
+}
+
+impl EmulatedDevice {
+    /// A blank device of the family with this product id
+    pub fn new(pid: u16) -> Option<Self> {
+        let profile = device::lookup(pid)?;
+        let pages = profile.pages();
+        let size = pages.iter().map(|p| p.size as usize).sum();
+        let mut system = vec![];
+        if let Some(area) = profile.option_bytes {
+            // Readout protection level 0 and the reset defaults of an F4
+            let mut data = vec![0xFF; area.length as usize];
+            data[..4].copy_from_slice(&[0xEC, 0xAA, 0x13, 0x55]);
+            system.push(Memory {
+                address: area.address,
+                data,
+            });
+        }
+        if let Some(address) = profile.package_address {
+            system.push(Memory {
+                address,
+                data: DEFAULT_PACKAGE.to_le_bytes().to_vec(),
+            });
+        }
+        if let Some(address) = profile.flash_size_address {
+            let kib = (size / 1024) as u16;
+            system.push(Memory {
+                address,
+                data: kib.to_le_bytes().to_vec(),
+            });
+        }
+        if let Some(address) = profile.uid_address {
+            system.push(Memory {
+                address,
+                data: DEFAULT_UID.to_vec(),
+            });
+        }
+        let target = Target {
+            profile,
+            pid: profile.pid,
+            version: 0x31,
+            commands: DEFAULT_COMMANDS.to_vec(),
+            flash: Memory {
+                address: profile.flash_start,
+                data: vec![0xFF; size],
+            },
+            pages,
+            system,
+            readout_protected: false,
+            write_protected: false,
+            jumped_to: None,
+            resets: 0,
+        };
+        Some(EmulatedDevice {
+            target: Arc::new(Mutex::new(target)),
+        })
+    }
+
+    pub fn with_bootloader_version(self, version: u8) -> Self {
+        self.target().version = version;
+        self
+    }
+
+    /// Answer Get ID with another product id, e.g. one the loader has no
+    /// profile for. The memory stays that of the family it was created as.
+    pub fn with_product_id(self, pid: u16) -> Self {
+        self.target().pid = pid;
+        self
+    }
+
+    /// Replace the list of supported command opcodes
+    pub fn with_commands(self, commands: &[u8]) -> Self {
+        self.target().commands = commands.to_vec();
+        self
+    }
+
+    pub fn with_readout_protection(self, enabled: bool) -> Self {
+        self.target().readout_protected = enabled;
+        self
+    }
+
+    pub fn with_write_protection(self, enabled: bool) -> Self {
+        self.target().write_protected = enabled;
+        self
+    }
+
+    /// Add a read-only memory area, e.g. option bytes or a device register
+    pub fn with_memory(self, address: u32, data: &[u8]) -> Self {
+        {
+            let mut target = self.target();
+            target.system.retain(|m| m.address != address);
+            target.system.push(Memory {
+                address,
+                data: data.to_vec(),
+            });
+        }
+        self
+    }
+
+    /// Program the flash directly, bypassing the bootloader
+    pub fn with_flash(self, address: u32, data: &[u8]) -> Self {
+        {
+            let mut target = self.target();
+            let offset = (address - target.flash.address) as usize;
+            target.flash.data[offset..offset + data.len()].copy_from_slice(data);
+        }
+        self
+    }
+
+    /// A port speaking the USART protocol
+    pub fn usart(&self) -> UsartPort {
+        UsartPort {
+            device: self.clone(),
+            session: Session::new(Interface::Usart),
+            synced_at: None,
+            output: VecDeque::new(),
+        }
+    }
+
+    /// A bus speaking the SPI protocol
+    pub fn spi(&self) -> SpiBusPort {
+        SpiBusPort {
+            device: self.clone(),
+            session: Session::new(Interface::Spi),
+            synced_at: None,
+            output: VecDeque::new(),
+            deferred: VecDeque::new(),
+            awaiting_confirm: false,
+        }
+    }
+
+    pub fn profile(&self) -> &'static DeviceProfile {
+        self.target().profile
+    }
+
+    pub fn flash_size(&self) -> usize {
+        self.target().flash.data.len()
+    }
+
+    pub fn read_flash(&self, address: u32, length: usize) -> Vec<u8> {
+        let target = self.target();
+        let offset = (address - target.flash.address) as usize;
+        target.flash.data[offset..offset + length].to_vec()
+    }
+
+    pub fn is_readout_protected(&self) -> bool {
+        self.target().readout_protected
+    }
+
+    pub fn is_write_protected(&self) -> bool {
+        self.target().write_protected
+    }
+
+    /// The address of the last Go command
+    pub fn jumped_to(&self) -> Option<u32> {
+        self.target().jumped_to
+    }
+
+    /// Reset the device into the bootloader, the host has to sync again
+    pub fn reset(&self) {
+        let mut target = self.target();
+        target.jumped_to = None;
+        target.resets += 1;
+    }
+
+    fn target(&self) -> MutexGuard<'_, Target> {
+        self.target.lock().unwrap()
+    }
+}
+
+impl Target {
+    fn supports(&self, command: u8) -> bool {
+        self.commands.contains(&command)
+    }
+
+    fn read(&self, address: u32, length: usize) -> Option<Vec<u8>> {
+        std::iter::once(&self.flash)
+            .chain(&self.system)
+            .find_map(|m| {
+                let offset = address.checked_sub(m.address)? as usize;
+                m.data.get(offset..offset + length).map(|d| d.to_vec())
+            })
+    }
+
+    fn write(&mut self, address: u32, data: &[u8]) -> bool {
+        let alignment = self.profile.write_alignment;
+        if !address.is_multiple_of(alignment) || !(data.len() as u32).is_multiple_of(alignment) {
+            return false;
+        }
+        let Some(offset) = address.checked_sub(self.flash.address) else {
+            return false;
+        };
+        let Some(flash) = self
+            .flash
+            .data
+            .get_mut(offset as usize..offset as usize + data.len())
+        else {
+            return false;
+        };
+        // Families with ECC can not program a flash word twice
+        if alignment >= 8 && flash.iter().any(|&b| b != 0xFF) {
+            return false;
+        }
+        flash.iter_mut().zip(data).for_each(|(f, d)| *f &= d);
+        true
+    }
+
+    fn erase_page(&mut self, index: u16) -> bool {
+        let Some(page) = self.pages.get(index as usize).copied() else {
+            return false;
+        };
+        let offset = (page.address - self.flash.address) as usize;
+        self.flash.data[offset..offset + page.size as usize].fill(0xFF);
+        true
+    }
+
+    fn reset(&mut self) {
+        self.resets += 1;
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Interface {
+    Usart,
+    Spi,
+}
+
+/// What the bootloader sends back, the interface decides how it is framed
+enum Response {
+    Ack,
+    Nack,
+    Data(Vec<u8>),
+}
+
+/// What the bootloader expects next from the host
+enum Expect {
+    Command,
+    Address(u8),
+    ReadLength(u32),
+    WriteData(u32),
+    EraseCount,
+    ErasePages(u16),
+    ChecksumLength(u32),
+}
+
+/// The protocol state of a single connection, shared by both interfaces
+struct Session {
+    interface: Interface,
+    expect: Expect,
+    buffer: Vec<u8>,
+}
+
+impl Session {
+    fn new(interface: Interface) -> Self {
+        Session {
+            interface,
+            expect: Expect::Command,
+            buffer: vec![],
+        }
+    }
+
+    /// The number of bytes needed to complete the current frame
+    fn needed(&self) -> usize {
+        let b = &self.buffer;
+        match self.expect {
+            Expect::Command | Expect::ReadLength(_) => 2,
+            Expect::Address(_) | Expect::ChecksumLength(_) => 5,
+            Expect::WriteData(_) if b.is_empty() => 1,
+            Expect::WriteData(_) => b[0] as usize + 3,
+            Expect::EraseCount if self.interface == Interface::Spi => 3,
+            Expect::EraseCount if b.len() < 2 => 2,
+            Expect::EraseCount => match u16::from_be_bytes([b[0], b[1]]) {
+                n if n >= 0xFFF0 => 3,
+                n => 2 + 2 * (n as usize + 1) + 1,
+            },
+            Expect::ErasePages(n) => 2 * (n as usize + 1) + 1,
+        }
+    }
+
+    fn reset(&mut self) {
+        self.expect = Expect::Command;
+        self.buffer.clear();
+    }
+
+    fn feed(&mut self, byte: u8, target: &mut Target) -> Vec<Response> {
+        self.buffer.push(byte);
+        if self.buffer.len() < self.needed() {
+            return vec![];
+        }
+        let frame = std::mem::take(&mut self.buffer);
+        let expect = std::mem::replace(&mut self.expect, Expect::Command);
+        self.handle(expect, &frame, target)
+    }
+
+    fn handle(&mut self, expect: Expect, frame: &[u8], target: &mut Target) -> Vec<Response> {
+        use Response::*;
+
+        let checksum = |data: &[u8]| data.iter().fold(0u8, |a, b| a ^ b);
+        let address = |frame: &[u8]| {
+            (checksum(frame) == 0)
+                .then(|| u32::from_be_bytes([frame[0], frame[1], frame[2], frame[3]]))
+        };
+
+        match expect {
+            Expect::Command => {
+                let command = frame[0];
+                if frame[1] != command ^ 0xFF || !target.supports(command) {
+                    return vec![Nack];
+                }
+                let protected = target.readout_protected;
+                match command {
+                    0x00 => {
+                        let mut data = vec![target.commands.len() as u8, target.version];
+                        data.extend(&target.commands);
+                        vec![Ack, Data(data), Ack]
+                    }
+                    // Only the USART protocol has the option bytes for compatibility
+                    0x01 => match self.interface {
+                        Interface::Usart => vec![Ack, Data(vec![target.version, 0x00, 0x00]), Ack],
+                        Interface::Spi => vec![Ack, Data(vec![target.version]), Ack],
+                    },
+                    0x02 => {
+                        let pid = target.pid.to_be_bytes();
+                        vec![Ack, Data(vec![0x01, pid[0], pid[1]]), Ack]
+                    }
+                    0x11 | 0x21 | 0x31 | 0x44 | 0xA1 if protected => vec![Nack],
+                    0x11 | 0x21 | 0x31 | 0xA1 => {
+                        self.expect = Expect::Address(command);
+                        vec![Ack]
+                    }
+                    0x44 => {
+                        self.expect = Expect::EraseCount;
+                        vec![Ack]
+                    }
+                    0x73 => {
+                        target.write_protected = false;
+                        target.reset();
+                        vec![Ack, Ack]
+                    }
+                    0x82 => {
+                        target.readout_protected = true;
+                        target.reset();
+                        vec![Ack, Ack]
+                    }
+                    0x92 => {
+                        target.readout_protected = false;
+                        target.flash.data.fill(0xFF);
+                        target.reset();
+                        vec![Ack, Ack]
+                    }
+                    _ => vec![Nack],
+                }
+            }
+            Expect::Address(command) => {
+                let Some(address) = address(frame) else {
+                    return vec![Nack];
+                };
+                match command {
+                    0x11 => self.expect = Expect::ReadLength(address),
+                    0x31 => self.expect = Expect::WriteData(address),
+                    0xA1 => self.expect = Expect::ChecksumLength(address),
+                    // Go, the application starts after the acknowledge
+                    _ => target.jumped_to = Some(address),
+                }
+                vec![Ack]
+            }
+            Expect::ReadLength(address) => {
+                if frame[1] != frame[0] ^ 0xFF {
+                    return vec![Nack];
+                }
+                match target.read(address, frame[0] as usize + 1) {
+                    Some(data) => vec![Ack, Data(data)],
+                    None => vec![Nack],
+                }
+            }
+            Expect::WriteData(address) => {
+                let data = &frame[1..frame.len() - 1];
+                if checksum(frame) != 0 || target.write_protected || !target.write(address, data) {
+                    return vec![Nack];
+                }
+                vec![Ack]
+            }
+            Expect::EraseCount => {
+                let count = u16::from_be_bytes([frame[0], frame[1]]);
+                if checksum(frame) != 0 {
+                    return vec![Nack];
+                }
+                if count >= 0xFFF0 {
+                    if count != 0xFFFF || target.write_protected {
+                        return vec![Nack];
+                    }
+                    target.flash.data.fill(0xFF);
+                    return vec![Ack];
+                }
+                match self.interface {
+                    Interface::Spi => {
+                        self.expect = Expect::ErasePages(count);
+                        vec![Ack]
+                    }
+                    Interface::Usart => self.handle(Expect::ErasePages(count), &frame[2..], target),
+                }
+            }
+            Expect::ErasePages(_) => {
+                if checksum(frame) != 0 && self.interface == Interface::Spi {
+                    return vec![Nack];
+                }
+                let pages = &frame[..frame.len() - 1];
+                let erased = !target.write_protected
+                    && pages
+                        .chunks(2)
+                        .all(|p| target.erase_page(u16::from_be_bytes([p[0], p[1]])));
+                vec![if erased { Ack } else { Nack }]
+            }
+            Expect::ChecksumLength(address) => {
+                let length = u32::from_be_bytes([frame[0], frame[1], frame[2], frame[3]]);
+                if checksum(frame) != 0 {
+                    return vec![Nack];
+                }
+                match target.read(address, length as usize) {
+                    Some(data) => {
+                        let mut crc = crc32(&data).to_be_bytes().to_vec();
+                        crc.push(checksum(&crc));
+                        vec![Ack, Data(crc)]
+                    }
+                    None => vec![Nack],
+                }
+            }
+        }
+    }
+}
+
+/// The USART side of an emulated device, reads time out when the
+/// bootloader has nothing to send.
+pub struct UsartPort {
+    device: EmulatedDevice,
+    session: Session,
+    synced_at: Option<u32>,
+    output: VecDeque<u8>,
+}
+
+impl Write for UsartPort {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut target = self.device.target();
+        for &byte in buf {
+            if target.jumped_to.is_some() {
+                continue;
+            }
+            if self.synced_at != Some(target.resets) {
+                if byte == SYNC_USART {
+                    self.synced_at = Some(target.resets);
+                    self.session.reset();
+                    self.output.push_back(ACK);
+                }
+                continue;
+            }
+            for response in self.session.feed(byte, &mut target) {
+                match response {
+                    Response::Ack => self.output.push_back(ACK),
+                    Response::Nack => self.output.push_back(NAK),
+                    Response::Data(data) => self.output.extend(data),
+                }
+            }
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Read for UsartPort {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.output.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                "Operation timed out",
+            ));
+        }
+        let n = buf.len().min(self.output.len());
+        buf.iter_mut()
+            .zip(self.output.drain(..n))
+            .for_each(|(b, o)| *b = o);
+        Ok(n)
+    }
+}
+
+impl ByteStream for UsartPort {}
+
+/// The SPI side of an emulated device.
+///
+/// Every response starts with a dummy byte, an ACK or NACK is only
+/// completed after the host confirms it with an ACK byte. Until then the
+/// rest of the response is held back.
+pub struct SpiBusPort {
+    device: EmulatedDevice,
+    session: Session,
+    synced_at: Option<u32>,
+    output: VecDeque<u8>,
+    deferred: VecDeque<Response>,
+    awaiting_confirm: bool,
+}
+
+impl SpiBusPort {
+    fn respond(&mut self, response: Response) {
+        if self.awaiting_confirm {
+            self.deferred.push_back(response);
+            return;
+        }
+        self.output.push_back(DUMMY_SPI);
+        match response {
+            Response::Ack => self.output.push_back(ACK),
+            Response::Nack => self.output.push_back(NAK),
+            Response::Data(data) => return self.output.extend(data),
+        }
+        self.awaiting_confirm = true;
+    }
+
+    /// Handle a byte from the host, `sending` is set while a response was
+    /// being clocked out, the host only sends dummy bytes then.
+    fn receive(&mut self, byte: u8, sending: bool) {
+        let mut target = self.device.target();
+        if target.jumped_to.is_some() {
+            return;
+        }
+        if self.awaiting_confirm {
+            if byte == ACK {
+                self.awaiting_confirm = false;
+                self.output.clear();
+                drop(target);
+                while let Some(response) = self.deferred.pop_front() {
+                    self.respond(response);
+                    if self.awaiting_confirm {
+                        break;
+                    }
+                }
+            }
+            return;
+        }
+        if sending {
+            return;
+        }
+        if self.synced_at != Some(target.resets) {
+            if byte == SOF_SPI {
+                self.synced_at = Some(target.resets);
+                self.session.reset();
+                drop(target);
+                self.respond(Response::Ack);
+            }
+            return;
+        }
+        if byte == SOF_SPI
+            && matches!(self.session.expect, Expect::Command)
+            && self.session.buffer.is_empty()
+        {
+            return;
+        }
+        let responses = self.session.feed(byte, &mut target);
+        drop(target);
+        responses.into_iter().for_each(|r| self.respond(r));
+    }
+}
+
+impl FullDuplex for SpiBusPort {
+    fn transfer(&mut self, tx: &[u8], rx: &mut [u8]) -> io::Result<()> {
+        for (t, r) in tx.iter().zip(rx.iter_mut()) {
+            let sending = !self.output.is_empty();
+            *r = self.output.pop_front().unwrap_or(DUMMY_SPI);
+            self.receive(*t, sending);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dfuloader::{DfuLoader, DfuLoaderError, Failure, Stage};
+    use crate::image::{ImageError, MemoryImage};
+    use crate::programmer;
+    use crate::serial::SerialConnection;
+    use crate::spi::SpiConnection;
+
+    const F401RE: u16 = 0x433;
+    const L43X: u16 = 0x435;
+    const FLASH: u32 = 0x0800_0000;
+
+    fn serial(device: &EmulatedDevice) -> SerialConnection {
+        let mut connection = SerialConnection::new(Box::new(device.usart()));
+        connection.initialize().unwrap();
+        connection
+    }
+
+    fn spi(device: &EmulatedDevice) -> SpiConnection {
+        let mut connection = SpiConnection::new(Box::new(device.spi()));
+        connection.initialize().unwrap();
+        connection
+    }
+
+    fn write_image(connection: &mut dyn DfuLoader, image: &MemoryImage) {
+        for (address, data) in image.chunks(programmer::MAX_BLOCK_SIZE) {
+            connection.write_memory(address, data).unwrap();
+        }
+    }
+
+    #[test]
+    fn ecc_flash_rejects_unaligned_and_repeated_writes() {
+        let device = EmulatedDevice::new(L43X).unwrap();
+        let mut connection = serial(&device);
+
+        assert!(connection.write_memory(FLASH, vec![0x00; 4]).is_err());
+        connection.write_memory(FLASH, vec![0x00; 8]).unwrap();
+        let err = connection.write_memory(FLASH, vec![0x00; 8]).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "WriteMemory (0x31) at 0x08000000 failed during data: NACK"
+        );
+
+        let mut image = MemoryImage::from_binary(FLASH + 0x23, &[0x12; 5]).unwrap();
+        image
+            .align(8, |_, length| Ok::<_, ImageError>(vec![0xFF; length]))
+            .unwrap();
+        write_image(&mut connection, &image);
+        assert_eq!(
+            device.read_flash(FLASH + 0x20, 8),
+            vec![0xFF, 0xFF, 0xFF, 0x12, 0x12, 0x12, 0x12, 0x12]
+        );
+    }
+
+    #[test]
+    fn readout_protection_blocks_memory_access() {
+        let device = EmulatedDevice::new(F401RE)
+            .unwrap()
+            .with_readout_protection(true);
+        let mut connection = serial(&device);
+
+        let err = connection.read_memory(FLASH, 16).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "ReadMemory (0x11) failed during command: NACK"
+        );
+        let DfuLoaderError::CommandFailed(err) = err else {
+            panic!("unexpected error {:?}", err);
+        };
+        assert_eq!((err.command, err.stage), (0x11, Stage::Command));
+        assert!(matches!(err.failure, Failure::Nack));
+        assert!(connection.write_memory(FLASH, vec![0; 4]).is_err());
+        assert!(device.is_readout_protected());
+    }
+
+    #[test]
+    fn reads_option_bytes_and_extra_memory() {
+        let device = EmulatedDevice::new(F401RE)
+            .unwrap()
+            .with_memory(0x1FFF_7A10, &[0x11, 0x22, 0x33, 0x44]);
+        let mut connection = serial(&device);
+
+        assert_eq!(
+            connection.read_memory(0x1FFF_C000, 2).unwrap(),
+            vec![0xEC, 0xAA]
+        );
+        assert_eq!(
+            connection.read_memory(0x1FFF_7A10, 4).unwrap(),
+            vec![0x11, 0x22, 0x33, 0x44]
+        );
+        assert!(connection.read_memory(0x2000_0000, 4).is_err());
+    }
+
+    #[test]
+    fn go_jumps_to_application() {
+        let device = EmulatedDevice::new(F401RE)
+            .unwrap()
+            .with_write_protection(true);
+        let mut connection = spi(&device);
+
+        assert!(connection.write_memory(FLASH, vec![0; 4]).is_err());
+        assert!(device.is_write_protected());
+        connection.go(FLASH).unwrap();
+        assert_eq!(device.jumped_to(), Some(FLASH));
+
+        device.reset();
+        assert_eq!(device.jumped_to(), None);
+        spi(&device);
+    }
+}
@@ -0,0 +1,727 @@
+//! C ABI for embedding the programmer in other software, see
+//! `include/stm32loader.h`.
+//!
+//! Every function returns a [Stm32LoaderStatus], the message of the last
+//! failure on the calling thread, with the failed command and address, is
+//! available from [stm32loader_last_error].
+
+use crate::dfuloader::{DfuLoaderError, Failure};
+use crate::image::MemoryImage;
+use crate::programmer::{Alignment, EraseMode, Programmer, ProgrammerError, WriteOptions};
+use crate::progress::Phase;
+use crate::retry::RetryPolicy;
+use crate::serial::{Parity, SerialSettings};
+use crate::spi::{ChipSelect, SpiMode, SpiSettings};
+use crate::{serial, spi};
+use std::cell::RefCell;
+use std::ffi::{c_char, c_void, CStr, CString};
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::path::Path;
+use std::ptr;
+use std::slice;
+use std::time::Duration;
+
+/// An open connection to a device, created by [stm32loader_open]
+pub struct Stm32Loader {
+    programmer: Programmer,
+}
+
+/// Result of every call, the bootloader errors mirror [DfuLoaderError]
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Stm32LoaderStatus {
+    Ok = 0,
+    SyncError = 1,
+    AlreadySynced = 2,
+    NotImplemented = 3,
+    InvalidRequest = 4,
+    IoError = 5,
+    /// The device answered NACK
+    Nack = 6,
+    /// The device answered BUSY
+    Busy = 7,
+    /// An unexpected byte where ACK or NACK was expected
+    Garbage = 8,
+    /// A response with the wrong length or checksum
+    Malformed = 9,
+    Timeout = 10,
+    InvalidArgument = 100,
+    ImageError = 101,
+    NotBlank = 102,
+    VerifyFailed = 103,
+    NoPageLayout = 104,
+    InternalError = 105,
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Stm32LoaderErase {
+    None = 0,
+    All = 1,
+    ChangedPages = 2,
+}
+
+/// Options for the write functions, NULL selects the defaults: no erase,
+/// pad to the write alignment, blank check and no verify
+#[repr(C)]
+pub struct Stm32LoaderWriteOptions {
+    /// A Stm32LoaderErase, a plain integer so an unknown value from C is an
+    /// error instead of an invalid enum
+    pub erase: u32,
+    /// Read the current flash content around unaligned edges instead of padding
+    pub read_modify_write: bool,
+    pub blank_check: bool,
+    pub verify: bool,
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Stm32LoaderParity {
+    Even = 0,
+    None = 1,
+    Odd = 2,
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Stm32LoaderChipSelect {
+    ActiveLow = 0,
+    ActiveHigh = 1,
+    /// Not driven by the controller
+    None = 2,
+}
+
+/// Settings of the port for [stm32loader_open], NULL selects the defaults of
+/// [SerialSettings] and [SpiSettings]
+#[repr(C)]
+pub struct Stm32LoaderOpenOptions {
+    /// Baud rate of a serial port, 0 for the default of 115200
+    pub baud_rate: u32,
+    /// A Stm32LoaderParity
+    pub parity: u32,
+    /// SPI clock in Hz, the bootloader supports up to 8 MHz, 0 for the default
+    pub spi_clock_hz: u32,
+    /// SPI mode 0 to 3, the bootloader uses mode 0
+    pub spi_mode: u8,
+    /// A Stm32LoaderChipSelect
+    pub chip_select: u32,
+}
+
+#[repr(C)]
+pub struct Stm32LoaderDeviceInfo {
+    pub chip_id: u16,
+    pub bootloader_version: u8,
+    pub bootloader_options: u16,
+    pub write_alignment: u32,
+    /// NUL terminated device family name
+    pub name: [c_char; 32],
+}
+
+thread_local! {
+    static LAST_ERROR: RefCell<CString> = RefCell::new(CString::default());
+}
+
+impl From<&DfuLoaderError> for Stm32LoaderStatus {
+    fn from(err: &DfuLoaderError) -> Self {
+        match err {
+            DfuLoaderError::SyncError() => Stm32LoaderStatus::SyncError,
+            DfuLoaderError::AlreadySynced() => Stm32LoaderStatus::AlreadySynced,
+            DfuLoaderError::NotImplemented() => Stm32LoaderStatus::NotImplemented,
+            DfuLoaderError::InvalidRequest(_) => Stm32LoaderStatus::InvalidRequest,
+            DfuLoaderError::IOError(_) => Stm32LoaderStatus::IoError,
+            DfuLoaderError::CommandFailed(err) => match err.failure {
+                Failure::Nack => Stm32LoaderStatus::Nack,
+                Failure::Busy => Stm32LoaderStatus::Busy,
+                Failure::Garbage(_) => Stm32LoaderStatus::Garbage,
+                Failure::Malformed => Stm32LoaderStatus::Malformed,
+                Failure::Timeout => Stm32LoaderStatus::Timeout,
+                Failure::IO(_) => Stm32LoaderStatus::IoError,
+            },
+        }
+    }
+}
+
+impl From<&ProgrammerError> for Stm32LoaderStatus {
+    fn from(err: &ProgrammerError) -> Self {
+        match err {
+            ProgrammerError::Bootloader(err) => err.into(),
+            ProgrammerError::Image(_) => Stm32LoaderStatus::ImageError,
+            ProgrammerError::NotBlank(_) => Stm32LoaderStatus::NotBlank,
+            ProgrammerError::VerifyFailed(_) => Stm32LoaderStatus::VerifyFailed,
+            ProgrammerError::NoPageLayout(_) => Stm32LoaderStatus::NoPageLayout,
+            ProgrammerError::Journal(_) => Stm32LoaderStatus::IoError,
+        }
+    }
+}
+
+fn fail(status: Stm32LoaderStatus, message: impl ToString) -> Stm32LoaderStatus {
+    let message = CString::new(message.to_string().replace('\0', "")).unwrap_or_default();
+    LAST_ERROR.with(|e| *e.borrow_mut() = message);
+    status
+}
+
+/// Run `f` on the programmer behind `handle`, turning errors and panics into a status
+unsafe fn with_programmer(
+    handle: *mut Stm32Loader,
+    f: impl FnOnce(&mut Programmer) -> Result<(), ProgrammerError>,
+) -> Stm32LoaderStatus {
+    let Some(handle) = handle.as_mut() else {
+        return fail(Stm32LoaderStatus::InvalidArgument, "handle is NULL");
+    };
+    match catch_unwind(AssertUnwindSafe(|| f(&mut handle.programmer))) {
+        Ok(Ok(())) => Stm32LoaderStatus::Ok,
+        Ok(Err(err)) => fail((&err).into(), err),
+        Err(_) => fail(Stm32LoaderStatus::InternalError, "internal error"),
+    }
+}
+
+unsafe fn to_str<'a>(s: *const c_char) -> Option<&'a str> {
+    if s.is_null() {
+        return None;
+    }
+    CStr::from_ptr(s).to_str().ok()
+}
+
+unsafe fn to_settings(
+    options: *const Stm32LoaderOpenOptions,
+) -> Result<(SerialSettings, SpiSettings), Stm32LoaderStatus> {
+    let (serial, spi) = (SerialSettings::default(), SpiSettings::default());
+    let Some(options) = options.as_ref() else {
+        return Ok((serial, spi));
+    };
+    let serial = SerialSettings {
+        baud_rate: match options.baud_rate {
+            0 => serial.baud_rate,
+            baud_rate => baud_rate,
+        },
+        parity: match options.parity {
+            0 => Parity::Even,
+            1 => Parity::None,
+            2 => Parity::Odd,
+            _ => {
+                return Err(fail(
+                    Stm32LoaderStatus::InvalidArgument,
+                    "parity must be a Stm32LoaderParity",
+                ))
+            }
+        },
+        ..serial
+    };
+    let spi = SpiSettings {
+        clock_hz: match options.spi_clock_hz {
+            0 => spi.clock_hz,
+            clock_hz => clock_hz,
+        },
+        mode: match options.spi_mode {
+            0 => SpiMode::Mode0,
+            1 => SpiMode::Mode1,
+            2 => SpiMode::Mode2,
+            3 => SpiMode::Mode3,
+            _ => {
+                return Err(fail(
+                    Stm32LoaderStatus::InvalidArgument,
+                    "spi_mode must be 0 to 3",
+                ))
+            }
+        },
+        chip_select: match options.chip_select {
+            0 => ChipSelect::ActiveLow,
+            1 => ChipSelect::ActiveHigh,
+            2 => ChipSelect::None,
+            _ => {
+                return Err(fail(
+                    Stm32LoaderStatus::InvalidArgument,
+                    "chip_select must be a Stm32LoaderChipSelect",
+                ))
+            }
+        },
+    };
+    Ok((serial, spi))
+}
+
+unsafe fn to_options(
+    options: *const Stm32LoaderWriteOptions,
+) -> Result<WriteOptions, Stm32LoaderStatus> {
+    let Some(options) = options.as_ref() else {
+        return Ok(WriteOptions::default());
+    };
+    Ok(WriteOptions {
+        erase: match options.erase {
+            0 => EraseMode::None,
+            1 => EraseMode::All,
+            2 => EraseMode::ChangedPages,
+            _ => {
+                return Err(fail(
+                    Stm32LoaderStatus::InvalidArgument,
+                    "erase must be a Stm32LoaderErase",
+                ))
+            }
+        },
+        alignment: match options.read_modify_write {
+            true => Alignment::ReadModifyWrite,
+            false => Alignment::Pad,
+        },
+        blank_check: options.blank_check,
+        verify: options.verify,
+        ..WriteOptions::default()
+    })
+}
+
+/// Open a port and synchronise with the bootloader.
+///
+/// `interface` is "Serial", "SPI" or "Tcp", `port` the port name as on the
+/// command line, `options` the port settings or NULL for the defaults. On
+/// success `*handle` receives a handle for [stm32loader_close].
+///
+/// # Safety
+/// `interface` and `port` must be NUL terminated strings, `options` NULL or
+/// valid, `handle` must be writable.
+#[no_mangle]
+pub unsafe extern "C" fn stm32loader_open(
+    interface: *const c_char,
+    port: *const c_char,
+    options: *const Stm32LoaderOpenOptions,
+    handle: *mut *mut Stm32Loader,
+) -> Stm32LoaderStatus {
+    let (Some(interface), Some(port), false) = (to_str(interface), to_str(port), handle.is_null())
+    else {
+        return fail(Stm32LoaderStatus::InvalidArgument, "invalid argument");
+    };
+    let (serial_settings, spi_settings) = match to_settings(options) {
+        Ok(settings) => settings,
+        Err(status) => return status,
+    };
+
+    let connection = match interface {
+        "Serial" => serial::new_serial_connection_with(port, &serial_settings),
+        "SPI" => spi::new_spi_connection_with(port, &spi_settings),
+        "Tcp" => serial::new_tcp_connection(port),
+        _ => return fail(Stm32LoaderStatus::InvalidArgument, "unknown interface"),
+    };
+    let connection = match connection {
+        Ok(connection) => connection,
+        Err(err) => return fail(Stm32LoaderStatus::IoError, err),
+    };
+
+    let mut programmer = Programmer::new(connection);
+    if let Err(err) = programmer.connect() {
+        return fail((&err).into(), err);
+    }
+    *handle = Box::into_raw(Box::new(Stm32Loader { programmer }));
+    Stm32LoaderStatus::Ok
+}
+
+/// Close the port and free the handle, NULL is ignored
+///
+/// # Safety
+/// `handle` must come from [stm32loader_open] and must not be used afterwards.
+#[no_mangle]
+pub unsafe extern "C" fn stm32loader_close(handle: *mut Stm32Loader) {
+    if !handle.is_null() {
+        drop(Box::from_raw(handle));
+    }
+}
+
+/// The message of the last failure on this thread, valid until the next call
+#[no_mangle]
+pub extern "C" fn stm32loader_last_error() -> *const c_char {
+    LAST_ERROR.with(|e| e.borrow().as_ptr())
+}
+
+/// Identify the device, `info` may be NULL to only select the device profile
+///
+/// # Safety
+/// `handle` must be a valid handle, `info` NULL or writable.
+#[no_mangle]
+pub unsafe extern "C" fn stm32loader_identify(
+    handle: *mut Stm32Loader,
+    info: *mut Stm32LoaderDeviceInfo,
+) -> Stm32LoaderStatus {
+    with_programmer(handle, |programmer| {
+        let device = programmer.identify()?;
+        if let Some(info) = info.as_mut() {
+            info.chip_id = device.chip_id;
+            if let Some(bootloader) = &device.bootloader {
+                info.bootloader_version = bootloader.version;
+                info.bootloader_options = bootloader.options;
+            }
+            info.write_alignment = device.profile.write_alignment;
+            info.name = [0; 32];
+            for (to, from) in info
+                .name
+                .iter_mut()
+                .zip(device.profile.name.bytes().take(31))
+            {
+                *to = from as c_char;
+            }
+        }
+        Ok(())
+    })
+}
+
+/// Read the 96-bit unique device id into `uid`, the device has to be
+/// identified first so the address is known
+///
+/// # Safety
+/// `handle` must be a valid handle, `uid` must point to 12 writable bytes.
+#[no_mangle]
+pub unsafe extern "C" fn stm32loader_unique_id(
+    handle: *mut Stm32Loader,
+    uid: *mut u8,
+) -> Stm32LoaderStatus {
+    if uid.is_null() {
+        return fail(Stm32LoaderStatus::InvalidArgument, "uid is NULL");
+    }
+    with_programmer(handle, |programmer| {
+        let id = programmer
+            .unique_id()?
+            .ok_or(DfuLoaderError::InvalidRequest(
+                "unique id of an unknown device",
+            ))?;
+        std::slice::from_raw_parts_mut(uid, id.len()).copy_from_slice(&id);
+        Ok(())
+    })
+}
+
+/// Read the flash size of the part in bytes, the device has to be
+/// identified first so the address is known
+///
+/// # Safety
+/// `handle` must be a valid handle, `size` writable.
+#[no_mangle]
+pub unsafe extern "C" fn stm32loader_flash_size(
+    handle: *mut Stm32Loader,
+    size: *mut u32,
+) -> Stm32LoaderStatus {
+    let Some(size) = size.as_mut() else {
+        return fail(Stm32LoaderStatus::InvalidArgument, "size is NULL");
+    };
+    with_programmer(handle, |programmer| {
+        *size = programmer
+            .flash_size()?
+            .ok_or(DfuLoaderError::InvalidRequest(
+                "flash size of an unknown device",
+            ))?;
+        Ok(())
+    })
+}
+
+/// Report the progress of writes to `callback`, which receives `user_data`,
+/// the bytes written and the total. NULL removes the callback.
+///
+/// # Safety
+/// `handle` must be a valid handle, `user_data` is passed to `callback` as is.
+#[no_mangle]
+pub unsafe extern "C" fn stm32loader_set_progress(
+    handle: *mut Stm32Loader,
+    callback: Option<extern "C" fn(user_data: *mut c_void, done: usize, total: usize)>,
+    user_data: *mut c_void,
+) -> Stm32LoaderStatus {
+    with_programmer(handle, |programmer| {
+        match callback {
+            Some(callback) => programmer.set_progress(move |progress| {
+                if progress.phase == Phase::Write {
+                    callback(user_data, progress.done, progress.total)
+                }
+            }),
+            None => programmer.set_progress(|_| {}),
+        }
+        Ok(())
+    })
+}
+
+/// Try read and write chunks up to `attempts` times, waiting `backoff_ms`
+/// before the first retry and doubling the wait for every further retry.
+/// The default is 3 attempts with 50 ms backoff, 1 disables retrying.
+///
+/// # Safety
+/// `handle` must be a valid handle.
+#[no_mangle]
+pub unsafe extern "C" fn stm32loader_set_retry(
+    handle: *mut Stm32Loader,
+    attempts: u32,
+    backoff_ms: u32,
+) -> Stm32LoaderStatus {
+    if attempts == 0 {
+        return fail(
+            Stm32LoaderStatus::InvalidArgument,
+            "attempts must be at least 1",
+        );
+    }
+    with_programmer(handle, |programmer| {
+        programmer.set_retry_policy(RetryPolicy {
+            attempts,
+            backoff: Duration::from_millis(backoff_ms.into()),
+        });
+        Ok(())
+    })
+}
+
+/// Erase the whole flash
+///
+/// # Safety
+/// `handle` must be a valid handle.
+#[no_mangle]
+pub unsafe extern "C" fn stm32loader_erase_all(handle: *mut Stm32Loader) -> Stm32LoaderStatus {
+    with_programmer(handle, |programmer| Ok(programmer.erase_all()?))
+}
+
+/// Write `length` bytes from `data` to `address`
+///
+/// # Safety
+/// `handle` must be a valid handle, `data` must point to `length` bytes and
+/// `options` must be NULL or valid.
+#[no_mangle]
+pub unsafe extern "C" fn stm32loader_write(
+    handle: *mut Stm32Loader,
+    address: u32,
+    data: *const u8,
+    length: usize,
+    options: *const Stm32LoaderWriteOptions,
+) -> Stm32LoaderStatus {
+    if data.is_null() && length > 0 {
+        return fail(Stm32LoaderStatus::InvalidArgument, "data is NULL");
+    }
+    let data = match length {
+        0 => &[],
+        _ => slice::from_raw_parts(data, length),
+    };
+    let options = match to_options(options) {
+        Ok(options) => options,
+        Err(status) => return status,
+    };
+    with_programmer(handle, |programmer| {
+        let image = MemoryImage::from_binary(address, data)?;
+        programmer.write(&image, &options)?;
+        Ok(())
+    })
+}
+
+/// Write an Intel hex or raw binary file, see [MemoryImage::load]. `address`
+/// may be NULL for Intel hex files, a raw binary needs it.
+///
+/// # Safety
+/// `handle` must be a valid handle, `path` a NUL terminated string, `address`
+/// and `options` NULL or valid.
+#[no_mangle]
+pub unsafe extern "C" fn stm32loader_write_file(
+    handle: *mut Stm32Loader,
+    path: *const c_char,
+    address: *const u32,
+    options: *const Stm32LoaderWriteOptions,
+) -> Stm32LoaderStatus {
+    let Some(path) = to_str(path) else {
+        return fail(Stm32LoaderStatus::InvalidArgument, "invalid path");
+    };
+    let address = address.as_ref().copied();
+    let options = match to_options(options) {
+        Ok(options) => options,
+        Err(status) => return status,
+    };
+    with_programmer(handle, |programmer| {
+        let image = MemoryImage::load(Path::new(path), address)?;
+        programmer.write(&image, &options)?;
+        Ok(())
+    })
+}
+
+/// Compare the device content at `address` with `data`. On a difference
+/// the first differing address is stored in `failed_address` if not NULL.
+///
+/// # Safety
+/// `handle` must be a valid handle, `data` must point to `length` bytes and
+/// `failed_address` must be NULL or writable.
+#[no_mangle]
+pub unsafe extern "C" fn stm32loader_verify(
+    handle: *mut Stm32Loader,
+    address: u32,
+    data: *const u8,
+    length: usize,
+    failed_address: *mut u32,
+) -> Stm32LoaderStatus {
+    if data.is_null() && length > 0 {
+        return fail(Stm32LoaderStatus::InvalidArgument, "data is NULL");
+    }
+    let data = match length {
+        0 => &[],
+        _ => slice::from_raw_parts(data, length),
+    };
+    with_programmer(handle, |programmer| {
+        let image = MemoryImage::from_binary(address, data)?;
+        let result = programmer.verify(&image);
+        if let (Err(ProgrammerError::VerifyFailed(at)), false) = (&result, failed_address.is_null())
+        {
+            ptr::write(failed_address, *at);
+        }
+        result
+    })
+}
+
+/// Start the application at `address`
+///
+/// # Safety
+/// `handle` must be a valid handle.
+#[no_mangle]
+pub unsafe extern "C" fn stm32loader_go(
+    handle: *mut Stm32Loader,
+    address: u32,
+) -> Stm32LoaderStatus {
+    with_programmer(handle, |programmer| Ok(programmer.go(address)?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::emulator::EmulatedDevice;
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+    use std::thread;
+
+    const FLASH: u32 = 0x0800_0000;
+
+    /// Serve the emulated device on a local TCP port
+    fn serve(device: EmulatedDevice) -> CString {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut usart = device.usart();
+            let mut buffer = [0u8; 512];
+            while let Ok(n @ 1..) = stream.read(&mut buffer) {
+                usart.write_all(&buffer[..n]).unwrap();
+                while let Ok(n) = usart.read(&mut buffer) {
+                    stream.write_all(&buffer[..n]).unwrap();
+                }
+            }
+        });
+        CString::new(address).unwrap()
+    }
+
+    extern "C" fn count_progress(user_data: *mut c_void, done: usize, _total: usize) {
+        unsafe { *(user_data as *mut usize) = done };
+    }
+
+    #[test]
+    fn programs_device_through_c_abi() {
+        let device = EmulatedDevice::new(0x433).unwrap();
+        let port = serve(device.clone());
+        let mut handle = ptr::null_mut();
+        let data: Vec<u8> = (0..300).map(|i| i as u8).collect();
+        let mut progress = 0usize;
+
+        unsafe {
+            let status = stm32loader_open(c"Tcp".as_ptr(), port.as_ptr(), ptr::null(), &mut handle);
+            assert_eq!(status, Stm32LoaderStatus::Ok);
+
+            let mut info: Stm32LoaderDeviceInfo = std::mem::zeroed();
+            assert_eq!(
+                stm32loader_identify(handle, &mut info),
+                Stm32LoaderStatus::Ok
+            );
+            assert_eq!(info.chip_id, 0x433);
+            assert_eq!(
+                CStr::from_ptr(info.name.as_ptr()).to_str().unwrap(),
+                "STM32F401xD(E)"
+            );
+            let mut size = 0;
+            assert_eq!(
+                stm32loader_flash_size(handle, &mut size),
+                Stm32LoaderStatus::Ok
+            );
+            assert_eq!(size, 512 * 1024);
+            let mut uid = [0u8; 12];
+            assert_eq!(
+                stm32loader_unique_id(handle, uid.as_mut_ptr()),
+                Stm32LoaderStatus::Ok
+            );
+
+            let user_data = &mut progress as *mut usize as *mut c_void;
+            stm32loader_set_progress(handle, Some(count_progress), user_data);
+            let status = stm32loader_write(handle, FLASH, data.as_ptr(), data.len(), ptr::null());
+            assert_eq!(status, Stm32LoaderStatus::Ok);
+
+            let mut failed = 0;
+            let other = [0u8; 4];
+            let status = stm32loader_verify(handle, FLASH, other.as_ptr(), 4, &mut failed);
+            assert_eq!(status, Stm32LoaderStatus::VerifyFailed);
+            assert_eq!(failed, FLASH + 1);
+
+            stm32loader_close(handle);
+        }
+        assert_eq!(progress, 300);
+        assert_eq!(device.read_flash(FLASH, 300), data);
+    }
+
+    #[test]
+    fn header_is_up_to_date() {
+        let dir = Path::new(env!("CARGO_MANIFEST_DIR"));
+        let config = cbindgen::Config::from_file(dir.join("cbindgen.toml")).unwrap();
+        let mut generated = vec![];
+        cbindgen::generate_with_config(dir, config)
+            .unwrap()
+            .write(&mut generated);
+
+        let header = std::fs::read(dir.join("include/stm32loader.h")).unwrap();
+        assert!(
+            generated == header,
+            "include/stm32loader.h is out of date, regenerate it with \
+             cbindgen --config cbindgen.toml --output include/stm32loader.h"
+        );
+    }
+
+    #[test]
+    fn reports_invalid_arguments() {
+        unsafe {
+            let options = Stm32LoaderOpenOptions {
+                baud_rate: 0,
+                parity: Stm32LoaderParity::Even as u32,
+                spi_clock_hz: 1_000_000,
+                spi_mode: 4,
+                chip_select: Stm32LoaderChipSelect::ActiveLow as u32,
+            };
+            let invalid = [
+                Stm32LoaderOpenOptions { ..options },
+                Stm32LoaderOpenOptions {
+                    spi_mode: 0,
+                    parity: 3,
+                    ..options
+                },
+                Stm32LoaderOpenOptions {
+                    spi_mode: 0,
+                    chip_select: 0xFFFF_FFFF,
+                    ..options
+                },
+            ];
+            for options in &invalid {
+                let mut handle = ptr::null_mut();
+                assert_eq!(
+                    stm32loader_open(c"SPI".as_ptr(), c"spidev0.0".as_ptr(), options, &mut handle),
+                    Stm32LoaderStatus::InvalidArgument
+                );
+            }
+
+            let options = Stm32LoaderWriteOptions {
+                erase: 3,
+                read_modify_write: false,
+                blank_check: true,
+                verify: false,
+            };
+            assert_eq!(
+                stm32loader_write(ptr::null_mut(), FLASH, [0u8].as_ptr(), 1, &options),
+                Stm32LoaderStatus::InvalidArgument
+            );
+            let message = CStr::from_ptr(stm32loader_last_error());
+            assert_eq!(
+                message.to_str().unwrap(),
+                "erase must be a Stm32LoaderErase"
+            );
+
+            assert_eq!(
+                stm32loader_erase_all(ptr::null_mut()),
+                Stm32LoaderStatus::InvalidArgument
+            );
+            let message = CStr::from_ptr(stm32loader_last_error());
+            assert_eq!(message.to_str().unwrap(), "handle is NULL");
+        }
+    }
+}
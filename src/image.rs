@@ -0,0 +1,514 @@
+use std::collections::BTreeMap;
+use std::fs::read;
+use std::path::{Path, PathBuf};
+use std::{error::Error, fmt::Display, fmt::Formatter};
+
+/// Largest span [MemoryImage::fill_gaps] fills, far more than any STM32 has
+/// flash. Images with data in separate areas like flash and option bytes
+/// span hundreds of megabytes.
+pub const MAX_FILL_SPAN: u32 = 16 * 1024 * 1024;
+
+/// A sparse firmware image, every input format is loaded into this
+/// before it is written to the device.
+///
+/// Segments never overlap, adjacent segments are coalesced when added.
+#[derive(Debug, Default, Clone)]
+pub struct MemoryImage {
+    segments: BTreeMap<u32, Vec<u8>>,
+    entry_point: Option<u32>,
+}
+
+#[derive(Debug)]
+pub enum ImageError {
+    Overlap(u32),
+    AddressOverflow(u32),
+    ParseError(ihex::ReaderError),
+    MissingAddress(PathBuf),
+    /// The gaps between the start and end address are too large to fill
+    SpanTooLarge(u32, u32),
+    /// Less data than requested to align the segment at this address
+    ShortFill(u32),
+    IOError(std::io::Error),
+}
+
+impl MemoryImage {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Load a file based on its extension, `.hex` and `.ihex` are Intel hex,
+    /// anything else is a raw binary which needs an address.
+    ///
+    /// For Intel hex files the address moves the image so it starts there.
+    pub fn load(path: &Path, address: Option<u32>) -> Result<Self, ImageError> {
+        let content = read(path)?;
+        let is_hex = path
+            .extension()
+            .is_some_and(|e| e.eq_ignore_ascii_case("hex") || e.eq_ignore_ascii_case("ihex"));
+
+        if !is_hex {
+            let address = address.ok_or_else(|| ImageError::MissingAddress(path.to_path_buf()))?;
+            return Self::from_binary(address, &content);
+        }
+
+        let image = Self::from_ihex(&String::from_utf8_lossy(&content))?;
+        match address {
+            Some(address) => image.relocate(address),
+            None => Ok(image),
+        }
+    }
+
+    pub fn from_binary(address: u32, content: &[u8]) -> Result<Self, ImageError> {
+        let mut image = MemoryImage::new();
+        image.add_segment(address, content)?;
+        Ok(image)
+    }
+
+    /// Load an Intel hex file, the start linear address becomes the entry point
+    pub fn from_ihex(content: &str) -> Result<Self, ImageError> {
+        let mut image = MemoryImage::new();
+        let mut base = 0_u32;
+        for record in ihex::Reader::new(content) {
+            match record? {
+                ihex::Record::ExtendedLinearAddress(ela) => base = (ela as u32) << 16,
+                ihex::Record::ExtendedSegmentAddress(esa) => base = (esa as u32) << 4,
+                ihex::Record::StartLinearAddress(sla) => image.entry_point = Some(sla),
+                ihex::Record::Data { offset, value } => {
+                    image.add_segment(base + offset as u32, &value)?
+                }
+                _ => {}
+            }
+        }
+        Ok(image)
+    }
+
+    pub fn entry_point(&self) -> Option<u32> {
+        self.entry_point
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.segments.is_empty()
+    }
+
+    /// Total number of bytes in the image, gaps excluded
+    pub fn len(&self) -> usize {
+        self.segments.values().map(|s| s.len()).sum()
+    }
+
+    pub fn start_address(&self) -> Option<u32> {
+        self.segments.keys().next().copied()
+    }
+
+    /// The address of the first byte after the image
+    pub fn end_address(&self) -> Option<u32> {
+        self.segments
+            .iter()
+            .next_back()
+            .map(|(&a, s)| a + s.len() as u32)
+    }
+
+    /// Iterate over the contiguous segments in address order
+    pub fn segments(&self) -> impl Iterator<Item = (u32, &[u8])> {
+        self.segments.iter().map(|(&a, s)| (a, s.as_slice()))
+    }
+
+    /// Add data at an address, fails if any byte is already present in the image
+    pub fn add_segment(&mut self, address: u32, data: &[u8]) -> Result<(), ImageError> {
+        if data.is_empty() {
+            return Ok(());
+        }
+        let end = address
+            .checked_add(data.len() as u32)
+            .ok_or(ImageError::AddressOverflow(address))?;
+
+        if let Some((&a, s)) = self.segments.range(..end).next_back() {
+            if a + s.len() as u32 > address {
+                return Err(ImageError::Overlap(address.max(a)));
+            }
+        }
+
+        // Coalesce with the segment directly before and after this one
+        let mut start = address;
+        let mut merged = data.to_vec();
+        if let Some((&a, s)) = self.segments.range(..address).next_back() {
+            if a + s.len() as u32 == address {
+                let mut before = self.segments.remove(&a).unwrap();
+                before.extend(merged);
+                merged = before;
+                start = a;
+            }
+        }
+        if let Some(after) = self.segments.remove(&end) {
+            merged.extend(after);
+        }
+        self.segments.insert(start, merged);
+        Ok(())
+    }
+
+    /// Overwrite the image at an address, the bytes not yet in the image are added
+    pub fn patch(&mut self, address: u32, data: &[u8]) -> Result<(), ImageError> {
+        let end = address
+            .checked_add(data.len() as u32)
+            .ok_or(ImageError::AddressOverflow(address))?;
+
+        // Cut the patched range out of the segments it overlaps
+        let first = self
+            .segments
+            .range(..=address)
+            .next_back()
+            .map_or(address, |(&a, _)| a);
+        let overlapping: Vec<u32> = self.segments.range(first..end).map(|(&a, _)| a).collect();
+        for a in overlapping {
+            let segment = self.segments.remove(&a).unwrap();
+            let segment_end = a + segment.len() as u32;
+            if a < address {
+                let keep = (address - a).min(segment.len() as u32) as usize;
+                self.segments.insert(a, segment[..keep].to_vec());
+            }
+            if segment_end > end {
+                self.segments
+                    .insert(end, segment[(end - a) as usize..].to_vec());
+            }
+        }
+        self.add_segment(address, data)
+    }
+
+    /// The part of the image between `start` and `end`, without entry point.
+    /// Empty when `start` is not below `end`.
+    pub fn range(&self, start: u32, end: u32) -> MemoryImage {
+        let mut image = MemoryImage::new();
+        if start >= end {
+            return image;
+        }
+        let first = self
+            .segments
+            .range(..=start)
+            .next_back()
+            .map_or(start, |(&a, _)| a);
+        for (&address, segment) in self.segments.range(first..end) {
+            let from = start.max(address);
+            let to = end.min(address + segment.len() as u32);
+            if from < to {
+                let data = &segment[(from - address) as usize..(to - address) as usize];
+                image.segments.insert(from, data.to_vec());
+            }
+        }
+        image
+    }
+
+    /// Move the image so it starts at `address`, the entry point moves along
+    pub fn relocate(self, address: u32) -> Result<Self, ImageError> {
+        let Some(start) = self.start_address() else {
+            return Ok(self);
+        };
+        let mut image = MemoryImage::new();
+        for (a, data) in self.segments {
+            let moved = (a - start)
+                .checked_add(address)
+                .ok_or(ImageError::AddressOverflow(address))?;
+            image.add_segment(moved, &data)?;
+        }
+        image.entry_point = self
+            .entry_point
+            .and_then(|e| e.checked_sub(start))
+            .and_then(|e| e.checked_add(address));
+        Ok(image)
+    }
+
+    /// Merge another image into this one, fails on the first overlapping byte
+    pub fn merge(&mut self, other: MemoryImage) -> Result<(), ImageError> {
+        for (address, data) in other.segments {
+            self.add_segment(address, &data)?;
+        }
+        if self.entry_point.is_none() {
+            self.entry_point = other.entry_point;
+        }
+        Ok(())
+    }
+
+    /// Fill the gaps between segments, the result is a single segment.
+    /// Fails when the image spans more than [MAX_FILL_SPAN].
+    pub fn fill_gaps(&mut self, value: u8) -> Result<(), ImageError> {
+        let (Some(start), Some(end)) = (self.start_address(), self.end_address()) else {
+            return Ok(());
+        };
+        if end - start > MAX_FILL_SPAN {
+            return Err(ImageError::SpanTooLarge(start, end));
+        }
+        let mut data = vec![value; (end - start) as usize];
+        for (address, segment) in &self.segments {
+            let offset = (address - start) as usize;
+            data[offset..offset + segment.len()].copy_from_slice(segment);
+        }
+        self.segments.clear();
+        self.segments.insert(start, data);
+        Ok(())
+    }
+
+    /// Extend every segment so it starts and ends on a multiple of `alignment`.
+    ///
+    /// The missing bytes are requested from `fill` as (address, length), this
+    /// either pads with 0xFF or reads back the current content of the device.
+    /// Less data than requested is an [ImageError::ShortFill].
+    pub fn align<E: From<ImageError>>(
+        &mut self,
+        alignment: u32,
+        mut fill: impl FnMut(u32, usize) -> Result<Vec<u8>, E>,
+    ) -> Result<(), E> {
+        if alignment <= 1 {
+            return Ok(());
+        }
+
+        // Aligned ranges covering the image, overlapping ranges are joined
+        let mut ranges: Vec<(u32, u32)> = vec![];
+        for (&address, segment) in &self.segments {
+            let start = address - address % alignment;
+            let end = (address + segment.len() as u32).next_multiple_of(alignment);
+            match ranges.last_mut() {
+                Some((_, last_end)) if *last_end >= start => *last_end = end,
+                _ => ranges.push((start, end)),
+            }
+        }
+
+        for (start, end) in ranges {
+            let mut gaps = vec![];
+            let mut current = start;
+            for (&address, segment) in self.segments.range(start..end) {
+                if address > current {
+                    gaps.push((current, (address - current) as usize));
+                }
+                current = address + segment.len() as u32;
+            }
+            if end > current {
+                gaps.push((current, (end - current) as usize));
+            }
+
+            for (address, length) in gaps {
+                let data = fill(address, length)?;
+                if data.len() < length {
+                    return Err(ImageError::ShortFill(address).into());
+                }
+                self.add_segment(address, &data[..length])?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Split the image in chunks of at most `max_size` bytes that do not
+    /// cross a `max_size` aligned boundary, the largest writes the bootloader accepts.
+    ///
+    /// # Panics
+    /// If `max_size` is 0.
+    pub fn chunks(&self, max_size: usize) -> Vec<(u32, Vec<u8>)> {
+        assert!(max_size > 0, "chunks need a size");
+        let mut chunks = vec![];
+        for (&address, segment) in &self.segments {
+            let mut offset = 0;
+            while offset < segment.len() {
+                let current = address + offset as u32;
+                let to_boundary = max_size - (current as usize % max_size);
+                let size = to_boundary.min(segment.len() - offset);
+                chunks.push((current, segment[offset..offset + size].to_vec()));
+                offset += size;
+            }
+        }
+        chunks
+    }
+}
+
+impl Error for ImageError {}
+
+impl Display for ImageError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ImageError::Overlap(address) => write!(f, "Overlapping data at {:#010X}", address),
+            ImageError::AddressOverflow(address) => {
+                write!(f, "Data at {:#010X} exceeds the address space", address)
+            }
+            ImageError::ParseError(err) => write!(f, "Failed to parse image: {}", err),
+            ImageError::MissingAddress(path) => {
+                write!(
+                    f,
+                    "{} is a raw binary, an address is required",
+                    path.display()
+                )
+            }
+            ImageError::SpanTooLarge(start, end) => write!(
+                f,
+                "Data at {:#010X} and {:#010X} is too far apart to fill the gaps",
+                start, end
+            ),
+            ImageError::ShortFill(address) => {
+                write!(f, "Missing data to align the image at {:#010X}", address)
+            }
+            ImageError::IOError(io_err) => write!(f, "I/O error: {}", io_err),
+        }
+    }
+}
+
+impl From<std::io::Error> for ImageError {
+    fn from(err: std::io::Error) -> Self {
+        ImageError::IOError(err)
+    }
+}
+
+impl From<ihex::ReaderError> for ImageError {
+    fn from(err: ihex::ReaderError) -> Self {
+        ImageError::ParseError(err)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FLASH: u32 = 0x0800_0000;
+
+    fn segments(image: &MemoryImage) -> Vec<(u32, Vec<u8>)> {
+        image.segments().map(|(a, s)| (a, s.to_vec())).collect()
+    }
+
+    #[test]
+    fn coalesces_adjacent_segments_and_rejects_overlaps() {
+        let mut image = MemoryImage::from_binary(FLASH + 4, &[3, 4]).unwrap();
+        image.add_segment(FLASH + 6, &[5]).unwrap();
+        image.add_segment(FLASH, &[1, 2]).unwrap();
+        image.add_segment(FLASH + 2, &[0, 0]).unwrap();
+
+        assert_eq!(segments(&image), vec![(FLASH, vec![1, 2, 0, 0, 3, 4, 5])]);
+        assert!(matches!(
+            image.add_segment(FLASH + 6, &[9, 9]),
+            Err(ImageError::Overlap(a)) if a == FLASH + 6
+        ));
+        assert!(matches!(
+            image.add_segment(FLASH - 1, &[9, 9]),
+            Err(ImageError::Overlap(a)) if a == FLASH
+        ));
+        assert!(matches!(
+            image.add_segment(u32::MAX, &[9, 9]),
+            Err(ImageError::AddressOverflow(_))
+        ));
+        assert_eq!(image.len(), 7);
+    }
+
+    #[test]
+    fn relocates_with_the_entry_point() {
+        let image = MemoryImage::from_ihex(
+            ":0400000001020304F2\n:0400100005060708D2\n:0400000500000011E6\n:00000001FF\n",
+        )
+        .unwrap();
+        assert_eq!(image.entry_point(), Some(0x11));
+
+        let image = image.relocate(FLASH).unwrap();
+
+        assert_eq!(
+            segments(&image),
+            vec![(FLASH, vec![1, 2, 3, 4]), (FLASH + 0x10, vec![5, 6, 7, 8])]
+        );
+        assert_eq!(image.entry_point(), Some(FLASH + 0x11));
+    }
+
+    #[test]
+    fn merges_and_keeps_the_first_entry_point() {
+        let mut image = MemoryImage::from_binary(FLASH, &[1, 2]).unwrap();
+        let mut other = MemoryImage::from_binary(FLASH + 2, &[3]).unwrap();
+        other.entry_point = Some(FLASH);
+        image.merge(other).unwrap();
+
+        assert_eq!(segments(&image), vec![(FLASH, vec![1, 2, 3])]);
+        assert_eq!(image.entry_point(), Some(FLASH));
+        let overlapping = MemoryImage::from_binary(FLASH + 1, &[9]).unwrap();
+        assert!(image.merge(overlapping).is_err());
+    }
+
+    #[test]
+    fn fills_gaps_up_to_a_limit() {
+        let mut image = MemoryImage::from_binary(FLASH, &[1]).unwrap();
+        image.add_segment(FLASH + 3, &[2]).unwrap();
+        image.fill_gaps(0xFF).unwrap();
+        assert_eq!(segments(&image), vec![(FLASH, vec![1, 0xFF, 0xFF, 2])]);
+
+        image.add_segment(0x1FFF_C000, &[0xAA]).unwrap();
+        assert!(matches!(
+            image.fill_gaps(0xFF),
+            Err(ImageError::SpanTooLarge(FLASH, 0x1FFF_C001))
+        ));
+    }
+
+    #[test]
+    fn cuts_out_a_range() {
+        let mut image = MemoryImage::from_binary(FLASH, &[1, 2, 3, 4]).unwrap();
+        image.add_segment(FLASH + 8, &[5, 6]).unwrap();
+
+        let range = image.range(FLASH + 2, FLASH + 9);
+
+        assert_eq!(
+            segments(&range),
+            vec![(FLASH + 2, vec![3, 4]), (FLASH + 8, vec![5])]
+        );
+        assert!(image.range(FLASH + 4, FLASH + 8).is_empty());
+        assert!(image.range(FLASH + 2, FLASH + 2).is_empty());
+        assert!(image.range(FLASH + 9, FLASH + 2).is_empty());
+    }
+
+    #[test]
+    fn aligns_segments_with_the_fill() {
+        let mut image = MemoryImage::from_binary(FLASH + 3, &[1, 2]).unwrap();
+        image.add_segment(FLASH + 13, &[3]).unwrap();
+        let mut requested = vec![];
+
+        image
+            .align(4, |address, length| {
+                requested.push((address, length));
+                Ok::<_, ImageError>(vec![0xEE; length])
+            })
+            .unwrap();
+
+        assert_eq!(
+            requested,
+            vec![(FLASH, 3), (FLASH + 5, 3), (FLASH + 12, 1), (FLASH + 14, 2)]
+        );
+        assert_eq!(
+            segments(&image),
+            vec![
+                (FLASH, vec![0xEE, 0xEE, 0xEE, 1, 2, 0xEE, 0xEE, 0xEE]),
+                (FLASH + 12, vec![0xEE, 3, 0xEE, 0xEE]),
+            ]
+        );
+    }
+
+    #[test]
+    fn reports_a_short_fill() {
+        let mut image = MemoryImage::from_binary(FLASH + 1, &[1]).unwrap();
+
+        let result = image.align(4, |_, length| Ok::<_, ImageError>(vec![0; length - 1]));
+
+        assert!(matches!(result, Err(ImageError::ShortFill(FLASH))));
+    }
+
+    #[test]
+    fn chunks_do_not_cross_boundaries() {
+        let image = MemoryImage::from_binary(FLASH + 0xF0, &[0; 0x120]).unwrap();
+
+        let chunks: Vec<(u32, usize)> = image
+            .chunks(0x100)
+            .into_iter()
+            .map(|(a, d)| (a, d.len()))
+            .collect();
+
+        assert_eq!(
+            chunks,
+            vec![
+                (FLASH + 0xF0, 0x10),
+                (FLASH + 0x100, 0x100),
+                (FLASH + 0x200, 0x10)
+            ]
+        );
+    }
+
+    #[test]
+    #[should_panic]
+    fn chunks_need_a_size() {
+        MemoryImage::from_binary(FLASH, &[0]).unwrap().chunks(0);
+    }
+}
@@ -0,0 +1,140 @@
+//! Progress of a write recorded in a file, so an interrupted write can be
+//! resumed instead of starting over, see [crate::WriteOptions::journal].
+//!
+//! The file is plain text with one `key value` pair per line:
+//!
+//! ```text
+//! image 5E5FD2A1
+//! uid 3400220013504B4E34333620
+//! written 08004000
+//! ```
+
+use crate::image::MemoryImage;
+use crate::programmer::crc32;
+use std::fs;
+use std::io::{self, ErrorKind};
+use std::path::Path;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Journal {
+    /// Hash of the image being written, see [image_hash]
+    pub image: u32,
+    /// Unique id of the device being written, if it could be read
+    pub uid: Option<[u8; 12]>,
+    /// Everything below this address has been written
+    pub written: u32,
+}
+
+impl Journal {
+    pub fn new(image: &MemoryImage, uid: Option<[u8; 12]>) -> Self {
+        Journal {
+            image: image_hash(image),
+            uid,
+            written: image.start_address().unwrap_or(0),
+        }
+    }
+
+    /// Whether the journal is about the same image and device
+    pub fn is_for(&self, other: &Journal) -> bool {
+        self.image == other.image && self.uid == other.uid
+    }
+
+    /// Read a journal, `None` if there is none
+    pub fn load(path: &Path) -> io::Result<Option<Journal>> {
+        let content = match fs::read_to_string(path) {
+            Ok(content) => content,
+            Err(err) if err.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(err),
+        };
+
+        let invalid = || io::Error::new(ErrorKind::InvalidData, "malformed journal");
+        let (mut image, mut uid, mut written) = (None, None, None);
+        for line in content.lines() {
+            let (key, value) = line.split_once(' ').ok_or_else(invalid)?;
+            let hex = |value| u32::from_str_radix(value, 16).map_err(|_| invalid());
+            match key {
+                "image" => image = Some(hex(value)?),
+                "written" => written = Some(hex(value)?),
+                "uid" if value == "-" => uid = Some(None),
+                "uid" => uid = Some(Some(parse_uid(value).ok_or_else(invalid)?)),
+                _ => return Err(invalid()),
+            }
+        }
+
+        match (image, uid, written) {
+            (Some(image), Some(uid), Some(written)) => Ok(Some(Journal {
+                image,
+                uid,
+                written,
+            })),
+            _ => Err(invalid()),
+        }
+    }
+
+    /// Write the journal, replacing the file in one step so an interruption
+    /// cannot leave half a journal
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        let uid = match self.uid {
+            Some(uid) => uid.iter().map(|b| format!("{:02X}", b)).collect(),
+            None => "-".to_string(),
+        };
+        let content = format!(
+            "image {:08X}\nuid {}\nwritten {:08X}\n",
+            self.image, uid, self.written
+        );
+
+        let temporary = path.with_extension("tmp");
+        fs::write(&temporary, content)?;
+        fs::rename(&temporary, path)
+    }
+
+    /// Remove the journal after a completed write
+    pub fn remove(path: &Path) -> io::Result<()> {
+        match fs::remove_file(path) {
+            Err(err) if err.kind() == ErrorKind::NotFound => Ok(()),
+            result => result,
+        }
+    }
+}
+
+/// Hash of the addresses and content of an image
+pub fn image_hash(image: &MemoryImage) -> u32 {
+    let mut data = vec![];
+    for (address, segment) in image.segments() {
+        data.extend(address.to_le_bytes());
+        data.extend((segment.len() as u32).to_le_bytes());
+        data.extend(segment);
+    }
+    crc32(&data)
+}
+
+fn parse_uid(value: &str) -> Option<[u8; 12]> {
+    if value.len() != 24 {
+        return None;
+    }
+    let mut uid = [0u8; 12];
+    for (i, byte) in uid.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(value.get(2 * i..2 * i + 2)?, 16).ok()?;
+    }
+    Some(uid)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env::temp_dir;
+
+    #[test]
+    fn saves_and_loads() {
+        let path = temp_dir().join(format!("stm32loader-{}.journal", std::process::id()));
+        let image = MemoryImage::from_binary(0x0800_0000, &[1, 2, 3, 4]).unwrap();
+        let mut journal = Journal::new(&image, Some(*b"0123456789AB"));
+        journal.written = 0x0800_0100;
+
+        journal.save(&path).unwrap();
+        assert_eq!(Journal::load(&path).unwrap(), Some(journal));
+
+        Journal::remove(&path).unwrap();
+        assert_eq!(Journal::load(&path).unwrap(), None);
+    }
+}
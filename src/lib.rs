@@ -0,0 +1,66 @@
+//! Program STM32 devices through their built-in system bootloader.
+//!
+//! The bootloader protocols implement [DfuLoader], [serial] speaks the USART
+//! protocol and [spi] the SPI protocol over any of the [transport]s. Firmware
+//! is loaded into a [MemoryImage] and written with a [Programmer]:
+//!
+//! ```no_run
+//! use std::path::Path;
+//! use stm32loader::{serial, MemoryImage, Programmer, WriteOptions};
+//!
+//! # fn main() -> Result<(), Box<dyn std::error::Error>> {
+//! let mut programmer = Programmer::new(serial::new_serial_connection("/dev/ttyUSB0")?);
+//! programmer.connect()?;
+//! programmer.identify()?;
+//!
+//! let image = MemoryImage::load(Path::new("firmware.hex"), None)?;
+//! programmer.write(&image, &WriteOptions::default())?;
+//! if let Some(entry_point) = image.entry_point() {
+//!     programmer.go(entry_point)?;
+//! }
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! With the `async` feature [nonblocking] has the same protocols for tokio,
+//! the `can` feature adds the CAN protocol. The `ffi` feature exports a C ABI
+//! from the shared library. The `emulator` feature exposes the emulated
+//! bootloader the tests run against.
+
+#[cfg(feature = "can")]
+pub mod can;
+pub mod capture;
+pub mod device;
+pub mod dfuloader;
+#[cfg(any(test, feature = "emulator"))]
+pub mod emulator;
+#[cfg(feature = "ffi")]
+pub mod ffi;
+pub mod image;
+pub mod journal;
+#[cfg(feature = "async")]
+pub mod nonblocking;
+pub mod patch;
+pub mod programmer;
+pub mod progress;
+pub mod retry;
+pub mod serial;
+pub mod spi;
+pub mod trace;
+pub mod transport;
+mod usart;
+
+pub use capture::{Capture, Replay};
+pub use device::DeviceProfile;
+pub use dfuloader::{DfuLoader, DfuLoaderError};
+pub use image::{ImageError, MemoryImage};
+pub use journal::Journal;
+pub use patch::{Counters, Patch, PatchError};
+pub use programmer::{
+    Alignment, DeviceInfo, EraseMode, Programmer, ProgrammerError, WriteOptions, WriteReport,
+};
+pub use progress::{Phase, Progress, ProgressCallback};
+pub use retry::{RetryPolicy, RetrySummary};
+pub use serial::SerialConnection;
+pub use spi::SpiConnection;
+pub use transport::{ByteStream, FullDuplex, TcpTransport};
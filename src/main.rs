@@ -1,198 +1,1080 @@
-use clap::{Parser, Subcommand};
-use ihex::Reader;
+use clap::{ArgAction, Parser, Subcommand, ValueEnum};
+use log::{error, info, warn, Level, LevelFilter, Log, Metadata, Record};
+use serde_json::{json, Map, Value};
 use std::error::Error;
-use std::fs::{read_dir, read_to_string, DirEntry};
+use std::fmt::Display;
+use std::fs::{read_dir, File};
+use std::io::{self, IsTerminal, Write};
+use std::num::ParseIntError;
 use std::path::PathBuf;
-use std::process::exit;
-use std::time::Duration;
-
-mod dfuloader;
-mod serial;
-mod spi;
+use std::process::ExitCode;
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use std::{env, fs};
+use stm32loader::dfuloader::{CommandError, DfuLoaderError, Failure, Stage};
+use stm32loader::journal::image_hash;
+use stm32loader::serial::{Parity, ResetLine, SerialSettings};
+use stm32loader::spi::{ChipSelect, SpiMode, SpiSettings};
+use stm32loader::{
+    serial, spi, Alignment, ByteStream, Capture, Counters, DeviceProfile, DfuLoader, EraseMode,
+    FullDuplex, MemoryImage, Patch, Phase, Programmer, ProgrammerError, Progress, Replay,
+    RetryPolicy, SerialConnection, SpiConnection, TcpTransport, WriteOptions,
+};
 
 #[derive(Parser, Debug)]
 #[command(version, about)]
 struct Cli {
     #[arg(
         long = "type",
-        help = "Select the bootloader interface: Serial, SPI or I2C"
+        help = "Select the bootloader interface: Serial, SPI or Tcp"
     )]
     porttype: Option<String>,
 
-    #[arg(long = "port", help = "The name of a device port, e.g. spidev0.1")]
+    #[arg(
+        long = "port",
+        help = "The name of a device port, e.g. spidev0.1 or /dev/spidev0.1, or host:port for Tcp"
+    )]
     portname: Option<String>,
 
+    #[arg(
+        long = "baud",
+        help = format!(
+            "Baud rate of a serial port, slower rates are tried when the bootloader does not \
+             answer. Defaults to the last rate that worked on the port, or {}",
+            serial::DEFAULT_BAUD_RATE
+        )
+    )]
+    baud: Option<u32>,
+
+    #[arg(
+        long = "reset",
+        value_enum,
+        help = "Modem line of a serial port wired to the reset pin of the device, with BOOT0 \
+                held high. The device is reset before every sync attempt, so a slower baud rate \
+                still works after the bootloader locked on to one that failed"
+    )]
+    reset: Option<ResetArg>,
+
+    #[arg(
+        long = "parity",
+        value_enum,
+        default_value_t = ParityArg::Even,
+        help = "Parity of a serial port, the bootloader uses even parity"
+    )]
+    parity: ParityArg,
+
+    #[arg(
+        long = "timeout",
+        default_value_t = 100,
+        help = "Milliseconds to wait for a response on a serial port"
+    )]
+    timeout: u64,
+
+    #[arg(
+        long = "spi-clock",
+        default_value_t = 20_000,
+        help = "SPI clock in Hz, the bootloader supports up to 8 MHz"
+    )]
+    spi_clock: u32,
+
+    #[arg(
+        long = "spi-mode",
+        default_value_t = 0,
+        value_parser = clap::value_parser!(u8).range(0..=3),
+        help = "SPI mode, the bootloader uses mode 0"
+    )]
+    spi_mode: u8,
+
+    #[arg(
+        long = "spi-cs",
+        value_enum,
+        default_value_t = ChipSelectArg::ActiveLow,
+        help = "How the SPI chip select is driven"
+    )]
+    spi_cs: ChipSelectArg,
+
+    #[arg(
+        long = "retries",
+        default_value_t = 2,
+        help = "Repeat a read or write chunk this often after a NACK, checksum error or timeout"
+    )]
+    retries: u32,
+
+    #[arg(
+        long = "retry-backoff",
+        default_value_t = 50,
+        help = "Milliseconds to wait before the first retry, doubled for every further retry"
+    )]
+    retry_backoff: u64,
+
+    #[arg(
+        short = 'v',
+        long = "verbose",
+        action = ArgAction::Count,
+        help = "Log the protocol frames, -vv with their bytes"
+    )]
+    verbose: u8,
+
+    #[arg(
+        long = "trace",
+        help = "Write all protocol frames with their bytes and timestamps to a file"
+    )]
+    trace: Option<PathBuf>,
+
+    #[arg(
+        long = "capture",
+        help = "Record every byte exchanged with the device, with timing, to a file"
+    )]
+    capture: Option<PathBuf>,
+
+    #[arg(
+        long = "replay",
+        help = "Play the device side of a capture back instead of opening the port"
+    )]
+    replay: Option<PathBuf>,
+
+    #[arg(
+        long = "output",
+        value_enum,
+        default_value_t = OutputFormat::Text,
+        help = "Format of the results on stdout"
+    )]
+    output: OutputFormat,
+
     #[command(subcommand)]
     cmd: Commands,
 }
 
 #[derive(Subcommand, Debug, Clone)]
 enum Commands {
+    /// Identify the device and show everything the bootloader tells about it
+    Info,
     Read,
     Write {
-        filename: PathBuf,
+        #[arg(
+            required = true,
+            help = "Images to write, a raw binary needs an address: app.bin@0x08010000"
+        )]
+        images: Vec<ImageArg>,
 
         #[arg(long = "erase", help = "Perform full erase before writing")]
         erase: bool,
 
         #[arg(long = "go", help = "Execute go if the ihex file has a start address")]
         go: bool,
+
+        #[arg(
+            long = "skip-unchanged",
+            conflicts_with = "erase",
+            help = "Only erase and write the pages that differ from the image"
+        )]
+        skip_unchanged: bool,
+
+        #[arg(
+            long = "no-blank-check",
+            help = "Do not check that the flash is erased before writing"
+        )]
+        no_blank_check: bool,
+
+        #[arg(long = "verify", help = "Read back and compare after writing")]
+        verify: bool,
+
+        #[arg(long = "fill-gaps", help = "Fill the gaps between segments with 0xFF")]
+        fill_gaps: bool,
+
+        #[arg(
+            long = "patch",
+            value_name = "ADDRESS=VALUE",
+            help = "Patch the image for this device: hex:BYTES, u8/u16/u32:N, str:TEXT, \
+                    counter:FILE, uid, mac[:PREFIX] or csv:FILE:COLUMN"
+        )]
+        patches: Vec<Patch>,
+
+        #[arg(
+            long = "align",
+            value_enum,
+            default_value_t = AlignMode::Pad,
+            help = "How to complete writes that do not cover a full flash word"
+        )]
+        align: AlignMode,
+
+        #[arg(
+            long = "resume",
+            help = "Continue an interrupted write of the same image from the journal"
+        )]
+        resume: bool,
+
+        #[arg(
+            long = "journal",
+            help = "Where to record the progress, defaults to a file named after the image \
+                    content and the device in ~/.cache/stm32loader/journals"
+        )]
+        journal: Option<PathBuf>,
+
+        #[arg(
+            long = "no-journal",
+            conflicts_with_all = ["resume", "journal"],
+            help = "Do not record the progress"
+        )]
+        no_journal: bool,
     },
     Unprotect,
     EraseAll,
+    BlankCheck {
+        #[arg(help = "Start address, defaults to the start of the flash")]
+        address: Option<String>,
+
+        #[arg(help = "Length in hex, defaults to the end of the flash")]
+        length: Option<String>,
+    },
     Go {
-        address: String
+        address: String,
+    },
+}
+
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq)]
+enum OutputFormat {
+    /// Results as lines of text
+    Text,
+    /// One JSON object with the device, the results, timings and the error
+    Json,
+}
+
+#[derive(ValueEnum, Debug, Clone, Copy)]
+enum ParityArg {
+    Even,
+    None,
+    Odd,
+}
+
+#[derive(ValueEnum, Debug, Clone, Copy)]
+enum ResetArg {
+    Dtr,
+    Rts,
+}
+
+#[derive(ValueEnum, Debug, Clone, Copy)]
+enum ChipSelectArg {
+    ActiveLow,
+    ActiveHigh,
+    /// Not driven by the SPI controller
+    None,
+}
+
+#[derive(ValueEnum, Debug, Clone, Copy)]
+enum AlignMode {
+    /// Pad with 0xFF, the value of erased flash
+    Pad,
+    /// Read the current flash content around unaligned edges
+    ReadModifyWrite,
+}
+
+/// An image file with an optional address, written as `path[@address]`
+#[derive(Debug, Clone)]
+struct ImageArg {
+    path: PathBuf,
+    address: Option<u32>,
+}
+
+impl FromStr for ImageArg {
+    type Err = ParseIntError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.rsplit_once('@') {
+            Some((path, address)) => Ok(ImageArg {
+                path: PathBuf::from(path),
+                address: Some(parse_address(address)?),
+            }),
+            None => Ok(ImageArg {
+                path: PathBuf::from(s),
+                address: None,
+            }),
+        }
     }
 }
 
-fn main() -> Result<(), Box<dyn Error>> {
-    let cli = Cli::parse();
-    if cli.porttype.is_none() {
-        println!("Available serial ports:");
-        print_available_serial_ports();
-        println!();
+/// Status messages go to stderr so only results end up on stdout, the
+/// protocol frames are added with `-v`. A trace file gets everything.
+struct Logger {
+    level: LevelFilter,
+    start: Instant,
+    trace: Option<Mutex<File>>,
+}
 
-        println!("Available spi ports");
-        print_available_spi_ports();
+impl Logger {
+    fn install(verbose: u8, trace: Option<&PathBuf>) -> Result<(), Box<dyn Error>> {
+        let level = match verbose {
+            0 => LevelFilter::Info,
+            1 => LevelFilter::Debug,
+            _ => LevelFilter::Trace,
+        };
+        let trace = match trace {
+            Some(path) => Some(Mutex::new(File::create(path)?)),
+            None => None,
+        };
+        log::set_max_level(if trace.is_some() {
+            LevelFilter::Trace
+        } else {
+            level
+        });
+        log::set_boxed_logger(Box::new(Logger {
+            level,
+            start: Instant::now(),
+            trace,
+        }))?;
+        Ok(())
+    }
+}
 
-        ::std::process::exit(1);
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= self.level || self.trace.is_some()
     }
 
-    let porttype = cli.porttype.unwrap();
-    let portname = cli.portname.unwrap();
+    fn log(&self, record: &Record) {
+        let elapsed = self.start.elapsed().as_secs_f64();
+        if record.level() <= self.level {
+            match record.level() {
+                Level::Error => eprintln!("Error: {}", record.args()),
+                Level::Warn => eprintln!("Warning: {}", record.args()),
+                Level::Info => eprintln!("{}", record.args()),
+                _ => eprintln!("{:10.6} {}", elapsed, record.args()),
+            }
+        }
+        if let Some(trace) = &self.trace {
+            let mut file = trace.lock().unwrap();
+            let _ = writeln!(
+                file,
+                "{:10.6} {:5} {}",
+                elapsed,
+                record.level(),
+                record.args()
+            );
+        }
+    }
+
+    fn flush(&self) {
+        if let Some(trace) = &self.trace {
+            let _ = trace.lock().unwrap().flush();
+        }
+    }
+}
+
+/// The results of a run. As text they are printed when they come in, as
+/// JSON they are collected into one object printed at the end.
+struct Output {
+    format: OutputFormat,
+    document: Map<String, Value>,
+    timings: Map<String, Value>,
+    start: Instant,
+    failed: bool,
+}
+
+impl Output {
+    fn new(format: OutputFormat) -> Self {
+        Output {
+            format,
+            document: Map::new(),
+            timings: Map::new(),
+            start: Instant::now(),
+            failed: false,
+        }
+    }
+
+    /// A result, `text` is what gets printed in text mode
+    fn result(&mut self, key: &str, value: impl Into<Value>, text: impl Display) {
+        match self.format {
+            OutputFormat::Text => println!("{}", text),
+            OutputFormat::Json => self.detail(key, value),
+        }
+    }
 
-    let mut connection = match porttype.as_str() {
-        "Serial" => serial::new_serial_connection(&portname),
-        "SPI" => spi::new_spi_connection(&portname),
-        &_ => todo!("Missing type in code"),
+    /// A result only in the JSON output, text mode logs it instead
+    fn detail(&mut self, key: &str, value: impl Into<Value>) {
+        self.document.insert(key.to_string(), value.into());
     }
-    .expect("Failed to open connection");
 
-    match connection.initialize() {
-        Err(err) => match err {
-            dfuloader::DfuLoaderError::AlreadySynced() => {}
-            _ => {
-                print!("Error initializing: {:?}", err);
-                exit(1);
+    /// Seconds a step took, from `start` until now
+    fn timing(&mut self, step: &str, start: Instant) {
+        let seconds = start.elapsed().as_secs_f64();
+        self.timings.insert(step.to_string(), seconds.into());
+    }
+
+    /// The command ran but did not succeed, like a blank check of flash
+    /// that is not blank
+    fn fail(&mut self) {
+        self.failed = true;
+    }
+
+    fn finish(mut self, result: Result<(), Box<dyn Error>>) -> ExitCode {
+        let ok = result.is_ok() && !self.failed;
+        match self.format {
+            OutputFormat::Text => {
+                if let Err(err) = &result {
+                    error!("{}", err);
+                }
             }
-        },
-        Ok(()) => {}
-    };
-    println!("Connected to device on {}", portname);
+            OutputFormat::Json => {
+                self.timing("total", self.start);
+                self.detail("ok", ok);
+                if let Err(err) = &result {
+                    self.detail("error", err.to_string());
+                }
+                self.detail("timings", Value::Object(self.timings.clone()));
+                let document = Value::Object(self.document);
+                println!("{}", serde_json::to_string_pretty(&document).unwrap());
+            }
+        }
+        match ok {
+            true => ExitCode::SUCCESS,
+            false => ExitCode::FAILURE,
+        }
+    }
+}
+
+/// Draws the progress of a write on stderr: on a terminal a bar with the
+/// throughput and the time left, redrawn in place, otherwise a line for
+/// every finished phase
+struct ProgressBar {
+    terminal: bool,
+    drawn: Option<Instant>,
+}
+
+impl ProgressBar {
+    const WIDTH: usize = 30;
+
+    fn new() -> Self {
+        ProgressBar {
+            terminal: io::stderr().is_terminal(),
+            drawn: None,
+        }
+    }
+
+    fn update(&mut self, progress: &Progress) {
+        let finished = progress.is_finished();
+        // An erase is a single command, there is no rate to speak of
+        let rate = match progress.phase {
+            Phase::Erase => String::new(),
+            _ => format!("{}/s", human_bytes(progress.throughput())),
+        };
+        if !self.terminal {
+            if finished {
+                let line = format!(
+                    "{} {} bytes in {:.1} s {}",
+                    progress.phase,
+                    progress.total,
+                    progress.elapsed.as_secs_f64(),
+                    rate
+                );
+                info!("{}", line.trim_end());
+            }
+            return;
+        }
+
+        // Every chunk is too often for a slow terminal
+        let recently = self
+            .drawn
+            .is_some_and(|drawn| drawn.elapsed() < Duration::from_millis(100));
+        if recently && !finished {
+            return;
+        }
+        self.drawn = Some(Instant::now());
 
-    println!("Retrieve bootloader version");
-    let f = connection.get_version()?;
-    println!("  Bootloader protocol version: 0x{:x}", f.version);
+        let fraction = match progress.total {
+            0 => 1.0,
+            total => progress.done as f64 / total as f64,
+        };
+        let filled = (fraction * Self::WIDTH as f64) as usize;
+        let time = match (finished, progress.remaining()) {
+            (true, _) => format!("{:.1} s", progress.elapsed.as_secs_f64()),
+            (false, Some(remaining)) => {
+                let seconds = remaining.as_secs();
+                format!("ETA {}:{:02}", seconds / 60, seconds % 60)
+            }
+            (false, None) => String::new(),
+        };
+        eprint!(
+            "\r{:<6} [{}{}] {:3.0}% {:>12} {}\x1b[K",
+            progress.phase,
+            "#".repeat(filled),
+            " ".repeat(Self::WIDTH - filled),
+            fraction * 100.0,
+            rate,
+            time
+        );
+        if finished {
+            eprintln!();
+        }
+    }
+}
 
-    println!("Retrieve chip identification");
-    let chip_id = connection.get_id()?;
-    println!("  Chip ID 0x{:x}", chip_id.chipid);
+/// A byte count with a binary prefix
+fn human_bytes(bytes: f64) -> String {
+    match bytes {
+        b if b >= 1024.0 * 1024.0 => format!("{:.1} MiB", b / 1024.0 / 1024.0),
+        b if b >= 1024.0 => format!("{:.1} KiB", b / 1024.0),
+        b => format!("{:.0} B", b),
+    }
+}
+
+fn main() -> ExitCode {
+    let cli = Cli::parse();
+    if let Err(err) = Logger::install(cli.verbose, cli.trace.as_ref()) {
+        eprintln!("Error: {}", err);
+        return ExitCode::FAILURE;
+    }
+    let mut output = Output::new(cli.output);
+    let result = run(cli, &mut output);
+    output.finish(result)
+}
+
+fn run(cli: Cli, out: &mut Output) -> Result<(), Box<dyn Error>> {
+    if cli.porttype.is_none() {
+        let serial_ports = available_serial_ports();
+        out.result(
+            "serial_ports",
+            serial_ports.clone(),
+            format!("Available serial ports:\n{}\n", serial_ports.join("\n")),
+        );
+        let spi_ports = available_spi_ports();
+        let listing = match spi_ports.is_empty() {
+            true => "No devices found".to_string(),
+            false => spi_ports.join("\n"),
+        };
+        out.result(
+            "spi_ports",
+            spi_ports,
+            format!("Available spi ports\n{}", listing),
+        );
+
+        out.fail();
+        return Ok(());
+    }
+
+    let porttype = cli.porttype.unwrap();
+    let portname = match (cli.portname, &cli.replay) {
+        (Some(portname), _) => portname,
+        (None, Some(replay)) => replay.display().to_string(),
+        (None, None) => return Err("A port is required, see --port".into()),
+    };
+    let replay = match &cli.replay {
+        Some(path) => Some(Replay::load(path)?),
+        None => None,
+    };
+    let capture = match &cli.capture {
+        Some(path) => Some(Capture::create(path)?),
+        None => None,
+    };
 
-    println!("Retrieve supported functions");
-    let f = connection.supported_functions()?;
-    println!("  Bootloader version: 0x{:x}", f.version);
-    f.supported_functions.iter().for_each(|f| println!("  {}", f));
+    let connecting = Instant::now();
+    let mut synced = false;
+    let connection = match porttype.as_str() {
+        "Serial" => {
+            let settings = SerialSettings {
+                baud_rate: cli
+                    .baud
+                    .or_else(|| remembered_baud_rate(&portname))
+                    .unwrap_or(serial::DEFAULT_BAUD_RATE),
+                parity: match cli.parity {
+                    ParityArg::Even => Parity::Even,
+                    ParityArg::None => Parity::None,
+                    ParityArg::Odd => Parity::Odd,
+                },
+                timeout: Duration::from_millis(cli.timeout),
+            };
+            let reset = cli.reset.map(|line| match line {
+                ResetArg::Dtr => ResetLine::Dtr,
+                ResetArg::Rts => ResetLine::Rts,
+            });
+            let negotiated = match &replay {
+                // The rates do not matter to a replay, only how often sync is tried
+                Some(replay) => serial::negotiate(serial::FALLBACK_BAUD_RATES, |_| {
+                    Ok(Box::new(replay.clone()))
+                }),
+                None => {
+                    let baud_rates = serial::baud_rates(settings.baud_rate);
+                    serial::negotiate(&baud_rates, |baud_rate| {
+                        let settings = SerialSettings {
+                            baud_rate,
+                            ..settings
+                        };
+                        let mut port = serial::open_serial_port(&portname, &settings)?;
+                        if let Some(line) = reset {
+                            serial::reset_device(port.as_mut(), line)?;
+                        }
+                        Ok(recorded_stream(&capture, port))
+                    })
+                }
+            };
+            match negotiated {
+                Ok((connection, baud_rate)) => {
+                    if replay.is_none() {
+                        info!("Synced at {} baud", baud_rate);
+                        remember_baud_rate(&portname, baud_rate);
+                    }
+                    synced = true;
+                    Ok(Box::new(connection) as Box<dyn DfuLoader>)
+                }
+                Err(err) => Err(err),
+            }
+        }
+        "SPI" => {
+            let settings = SpiSettings {
+                clock_hz: cli.spi_clock,
+                mode: match cli.spi_mode {
+                    0 => SpiMode::Mode0,
+                    1 => SpiMode::Mode1,
+                    2 => SpiMode::Mode2,
+                    _ => SpiMode::Mode3,
+                },
+                chip_select: match cli.spi_cs {
+                    ChipSelectArg::ActiveLow => ChipSelect::ActiveLow,
+                    ChipSelectArg::ActiveHigh => ChipSelect::ActiveHigh,
+                    ChipSelectArg::None => ChipSelect::None,
+                },
+            };
+            let bus: Result<Box<dyn FullDuplex>, Box<dyn Error>> = match &replay {
+                Some(replay) => Ok(Box::new(replay.clone())),
+                None => spi::open_spidev(&portname, &settings)
+                    .map(|spi| recorded_bus(&capture, spi))
+                    .map_err(Into::into),
+            };
+            bus.map(|bus| Box::new(SpiConnection::new(bus)) as Box<dyn DfuLoader>)
+        }
+        "Tcp" => {
+            let stream: Result<Box<dyn ByteStream>, Box<dyn Error>> = match &replay {
+                Some(replay) => Ok(Box::new(replay.clone())),
+                None => TcpTransport::connect(&portname, Duration::from_millis(cli.timeout))
+                    .map(|stream| recorded_stream(&capture, stream))
+                    .map_err(Into::into),
+            };
+            stream.map(|stream| Box::new(SerialConnection::new(stream)) as Box<dyn DfuLoader>)
+        }
+        other => {
+            return Err(format!(
+                "Unsupported interface type {}, use Serial, SPI or Tcp",
+                other
+            )
+            .into())
+        }
+    }
+    .map_err(|err| format!("Failed to open connection: {}", err))?;
+    let mut programmer = Programmer::new(connection);
+    programmer.set_retry_policy(RetryPolicy {
+        attempts: cli.retries + 1,
+        backoff: Duration::from_millis(cli.retry_backoff),
+    });
 
-    println!("Read option bytes");
-    let v = connection.read_memory(0x1fffc008, 16)?;
-    println!("{:02X?}", v);
+    if !synced {
+        if let Err(err) = programmer.connect() {
+            return Err(format!("Initializing failed: {}", err).into());
+        }
+    }
+    info!("Connected to device on {}", portname);
+    out.detail("port", portname);
+    out.timing("connect", connecting);
 
+    let running = Instant::now();
     match cli.cmd {
+        Commands::Info => {
+            let identifying = Instant::now();
+            let info = programmer.identify()?;
+            out.result(
+                "chip_id",
+                info.chip_id,
+                format!("Chip ID: 0x{:03x}", info.chip_id),
+            );
+            out.result(
+                "name",
+                info.profile.name,
+                format!("Device: {}", info.profile.name),
+            );
+            if let Some(bootloader) = &info.bootloader {
+                out.result(
+                    "protocol_version",
+                    bootloader.version,
+                    format!("Bootloader protocol version: 0x{:x}", bootloader.version),
+                );
+                out.result(
+                    "options",
+                    bootloader.options,
+                    format!("Bootloader options: 0x{:04x}", bootloader.options),
+                );
+            }
+            out.result(
+                "bootloader_version",
+                info.commands.version,
+                format!("Bootloader version: 0x{:x}", info.commands.version),
+            );
+            let commands: Vec<String> = info
+                .commands
+                .supported_functions
+                .iter()
+                .map(|f| f.to_string())
+                .collect();
+            out.result(
+                "commands",
+                commands.clone(),
+                format!("Commands: {}", commands.join(", ")),
+            );
+            out.timing("identify", identifying);
+
+            // Read protection makes the device refuse these, what was found
+            // so far is still worth showing
+            match programmer.flash_size() {
+                Ok(Some(size)) => out.result(
+                    "flash_size",
+                    size,
+                    format!("Flash size: {} KiB", size / 1024),
+                ),
+                Ok(None) => {}
+                Err(err) => warn!("Reading the flash size failed: {}", err),
+            }
+            match programmer.unique_id() {
+                Ok(Some(uid)) => {
+                    let uid: String = uid.iter().map(|b| format!("{:02X}", b)).collect();
+                    out.result("unique_id", uid.clone(), format!("Unique ID: {}", uid))
+                }
+                Ok(None) => {}
+                Err(err) => warn!("Reading the unique id failed: {}", err),
+            }
+            match programmer.option_bytes() {
+                Ok(Some(option_bytes)) => out.result(
+                    "option_bytes",
+                    option_bytes.clone(),
+                    format!("Option bytes: {:02X?}", option_bytes),
+                ),
+                Ok(None) => {}
+                Err(err) => warn!("Reading the option bytes failed: {}", err),
+            }
+            match programmer.package() {
+                Ok(Some(package)) => {
+                    out.result("package", package, format!("Package: 0x{:02x}", package))
+                }
+                Ok(None) => {}
+                Err(err) => warn!("Reading the package failed: {}", err),
+            }
+        }
         Commands::Unprotect => {
-            println!("Remove write protection");
-            match connection.write_unprotect() {
-                Err(err) => match err {
-                    dfuloader::DfuLoaderError::CommandFailed(0xA5) => {}
-                    _ => return Err(Box::new(err)),
-                },
-                _ => {}
+            info!("Remove write protection");
+            match programmer.connection().write_unprotect() {
+                // The device may reset before the final acknowledge gets out
+                Err(DfuLoaderError::CommandFailed(CommandError {
+                    stage: Stage::Ack,
+                    failure: Failure::Timeout,
+                    ..
+                })) => {}
+                Ok(()) => {}
+                Err(err) => return Err(Box::new(err)),
             }
+            out.detail("unprotected", true);
         }
         Commands::Write {
-            filename,
+            images,
             erase,
             go,
+            skip_unchanged,
+            no_blank_check,
+            verify,
+            fill_gaps,
+            patches,
+            align,
+            resume,
+            journal,
+            no_journal,
         } => {
-            println!("Write {:?}", filename);
-
-            let ihex = read_to_string(filename.as_path())?;
-            let content = Reader::new(&ihex);
+            let mut image = MemoryImage::new();
+            for source in &images {
+                info!("Load {:?}", source.path);
+                image.merge(MemoryImage::load(&source.path, source.address)?)?;
+            }
+            if fill_gaps {
+                image.fill_gaps(0xFF)?;
+            }
+            if image.is_empty() {
+                info!("Nothing to write");
+                return Ok(());
+            }
+            for (address, data) in image.segments() {
+                info!(
+                    "Segment {:#08X} - {:#08X}",
+                    address,
+                    address + data.len() as u32
+                );
+            }
 
-            if erase {
-                println!("Sending full erase command");
-                connection.erase_all()?;
+            let mut options = WriteOptions {
+                erase: if erase {
+                    EraseMode::All
+                } else if skip_unchanged {
+                    EraseMode::ChangedPages
+                } else {
+                    EraseMode::None
+                },
+                alignment: match align {
+                    AlignMode::Pad => Alignment::Pad,
+                    AlignMode::ReadModifyWrite => Alignment::ReadModifyWrite,
+                },
+                blank_check: !no_blank_check,
+                verify,
+                journal: None,
+                resume,
+            };
+            detect(&mut programmer, out)?;
+            let counters = Counters::lock(&patches)?;
+            if !patches.is_empty() {
+                let uid = match patches.iter().any(Patch::needs_unique_id) {
+                    true => programmer.unique_id()?,
+                    false => None,
+                };
+                let mut patched = Map::new();
+                for patch in &patches {
+                    let data = patch.resolve(uid.as_ref())?;
+                    info!("Patch {:#08X} {:02X?}", patch.address, data);
+                    image.patch(patch.address, &data)?;
+                    patched.insert(format!("{:#010X}", patch.address), data.into());
+                }
+                out.detail("patches", patched);
+            }
+            options.journal = match (no_journal, journal) {
+                (true, _) => None,
+                (false, Some(journal)) => Some(journal),
+                (false, None) => default_journal(&image, programmer.unique_id().ok().flatten()),
+            };
+            let mut bar = ProgressBar::new();
+            programmer.set_progress(move |progress| bar.update(progress));
+            let report = match programmer.write(&image, &options) {
+                Ok(report) => report,
+                Err(ProgrammerError::NotBlank(address)) => {
+                    return Err(format!(
+                        "Flash is not blank at {:#08X}, use --erase or --skip-unchanged",
+                        address
+                    )
+                    .into());
+                }
+                Err(ProgrammerError::NoPageLayout(name)) if resume => {
+                    return Err(format!(
+                        "No page layout known for {}, an interrupted write cannot be \
+                         resumed, write the whole image again with --erase",
+                        name
+                    )
+                    .into());
+                }
+                Err(err) => return Err(Box::new(err)),
+            };
+            counters.commit()?;
+            match report.resumed_from {
+                Some(address) => info!("Resumed at {:#08X}", address),
+                None if resume => info!("Nothing to resume, wrote the whole image"),
+                None => {}
+            }
+            out.detail("resumed_from", report.resumed_from);
+            if skip_unchanged {
+                let pages = report.erased_pages.len();
+                out.result(
+                    "erased_pages",
+                    pages,
+                    format!("Erased {} changed pages", pages),
+                );
+            }
+            out.result(
+                "written",
+                report.written,
+                format!("{} bytes written", report.written),
+            );
+            if verify {
+                out.result("verified", true, "Verify OK");
+            }
+            let retries = programmer.retries();
+            if retries.retries > 0 {
+                out.result(
+                    "retries",
+                    json!({"retries": retries.retries, "chunks": retries.chunks}),
+                    format!(
+                        "{} retries needed for {} chunks",
+                        retries.retries, retries.chunks
+                    ),
+                );
             }
 
-            let mut address = 0_u32;
-            let mut bytes = 0;
-            for r in content {
-                let record = r?;
-                match record {
-                    ihex::Record::ExtendedLinearAddress(ela) => {
-                        address = (ela as u32) << 16;
-                        println!("Base Address {:#08X}", address);
-                    }
-                    ihex::Record::StartLinearAddress(sla) => {
-                        address = sla;
-                        println!("Entrypoint is at {:#08X}", sla);
-                    }
-                    ihex::Record::Data { offset, value } => {
-                        bytes += value.len() as u32;
-                        connection.write_memory(address + offset as u32, value)?;
-                        print!("Write {:#08X}\r", address + offset as u32);
-                    }
-                    ihex::Record::EndOfFile => {
-                        println!("EndOfFile, {} bytes written", bytes);
-                    }
-                    x => {
-                        println!("Ignored record: {:?}", x)
-                    }
+            if let Some(entry_point) = image.entry_point() {
+                info!("Entrypoint is at {:#08X}", entry_point);
+                out.detail("entry_point", entry_point);
+                if go {
+                    programmer.go(entry_point)?;
+                    out.detail("started", entry_point);
                 }
             }
         }
         Commands::Read => {
-            println!("Read test data");
-            let v = connection.read_memory(0x08000000, 16)?;
-            println!("{:02X?}", v);
+            info!("Read test data");
+            let v = programmer.connection().read_memory(0x08000000, 16)?;
+            out.result("data", v.clone(), format!("{:02X?}", v));
         }
         Commands::EraseAll => {
-            connection.erase_all()?;
+            programmer.erase_all()?;
+            out.detail("erased", true);
+        }
+        Commands::BlankCheck { address, length } => {
+            let profile = detect(&mut programmer, out)?;
+            let pages = profile.pages();
+            let start = match address {
+                Some(address) => parse_address(&address)?,
+                None => profile.flash_start,
+            };
+            let length = match (length, pages.last()) {
+                (Some(length), _) => parse_address(&length)?,
+                (None, Some(last)) if last.address + last.size > start => {
+                    last.address + last.size - start
+                }
+                (None, _) => return Err("Flash size unknown, a length is required".into()),
+            };
+
+            info!("Blank check {:#08X} - {:#08X}", start, start + length);
+            match programmer.blank_check(start, length)? {
+                Some(address) => {
+                    out.result("blank", false, format!("Not blank at {:#08X}", address));
+                    out.detail("not_blank_at", address);
+                    out.fail();
+                }
+                None => out.result("blank", true, "Blank"),
+            }
         }
         Commands::Go { address } => {
-            let without_prefix = address.trim_start_matches("0x");
-            let z = u32::from_str_radix(without_prefix, 16)?;
-            connection.go(z)?;
+            let address = parse_address(&address)?;
+            programmer.go(address)?;
+            out.detail("started", address);
         }
     }
+    out.timing("command", running);
 
-    return Ok({});
+    Ok(())
 }
 
-fn print_available_serial_ports() {
-    let ports = serialport::available_ports().expect("No ports found!");
-    for p in &ports {
-        println!("{}", p.port_name);
-    }
+/// Select the device profile, only the chip id and commands are asked for
+fn detect(
+    programmer: &mut Programmer,
+    out: &mut Output,
+) -> Result<&'static DeviceProfile, DfuLoaderError> {
+    let identifying = Instant::now();
+    let info = programmer.detect()?;
+    info!("Device: {} (0x{:03x})", info.profile.name, info.chip_id);
+    out.detail(
+        "device",
+        json!({
+            "chip_id": info.chip_id,
+            "name": info.profile.name,
+            "bootloader_version": info.commands.version,
+        }),
+    );
+    out.timing("identify", identifying);
+    Ok(info.profile)
 }
 
-fn print_available_spi_ports() {
-    let spidevices = read_dir("/dev");
-    if spidevices.is_err() {
-        println!("No devices found");
-        return;
+fn cache_dir() -> Option<PathBuf> {
+    let cache = match env::var_os("XDG_CACHE_HOME") {
+        Some(cache) => PathBuf::from(cache),
+        None => PathBuf::from(env::var_os("HOME")?).join(".cache"),
+    };
+    Some(cache.join("stm32loader"))
+}
+
+/// Where the baud rate that worked is remembered for every serial port
+fn baud_rate_cache() -> Option<PathBuf> {
+    Some(cache_dir()?.join("baud-rates"))
+}
+
+/// The journal of an image in the cache, not next to the image which may be
+/// read-only. Without a usable cache the write is not recorded. It is named
+/// after the image content and the device, so images of the same name or
+/// stations sharing the cache do not take over each other's journal.
+fn default_journal(image: &MemoryImage, uid: Option<[u8; 12]>) -> Option<PathBuf> {
+    let Some(dir) = cache_dir().map(|cache| cache.join("journals")) else {
+        warn!("No cache directory, the write is not recorded for --resume");
+        return None;
+    };
+    if let Err(err) = fs::create_dir_all(&dir) {
+        warn!(
+            "Cannot create {}, the write is not recorded for --resume: {}",
+            dir.display(),
+            err
+        );
+        return None;
+    }
+    let mut name = format!("{:08X}", image_hash(image));
+    if let Some(uid) = uid {
+        name.push('-');
+        name.extend(uid.iter().map(|b| format!("{:02X}", b)));
     }
-    let devices: Vec<DirEntry> = spidevices
-        .unwrap()
-        .filter_map(|x| Some(x.unwrap()))
-        .filter(|i| {
-            i.file_name()
-                .as_os_str()
-                .to_str()
-                .unwrap()
-                .starts_with("spidev")
+    Some(dir.join(name + ".journal"))
+}
+
+fn remembered_baud_rate(port: &str) -> Option<u32> {
+    let content = fs::read_to_string(baud_rate_cache()?).ok()?;
+    content
+        .lines()
+        .find_map(|line| match line.rsplit_once(' ') {
+            Some((name, rate)) if name == port => rate.parse().ok(),
+            _ => None,
         })
+}
+
+/// Remember the baud rate for the next run, this is only an optimisation so
+/// failures are ignored
+fn remember_baud_rate(port: &str, baud_rate: u32) {
+    let Some(path) = baud_rate_cache() else {
+        return;
+    };
+    let content = fs::read_to_string(&path).unwrap_or_default();
+    let mut lines: Vec<String> = content
+        .lines()
+        .filter(|line| line.rsplit_once(' ').is_some_and(|(name, _)| name != port))
+        .map(String::from)
         .collect();
-    for p in &devices {
-        println!("{:?}", p.file_name());
+    lines.push(format!("{} {}", port, baud_rate));
+
+    if let Some(dir) = path.parent() {
+        let _ = fs::create_dir_all(dir);
     }
+    let _ = fs::write(&path, lines.join("\n") + "\n");
+}
+
+/// Parse a hexadecimal address, with or without 0x prefix
+fn parse_address(address: &str) -> Result<u32, ParseIntError> {
+    let without_prefix = address.trim_start_matches("0x");
+    u32::from_str_radix(without_prefix, 16)
+}
+
+/// The transport itself, or recording into the capture when there is one
+fn recorded_stream<T: ByteStream + 'static>(
+    capture: &Option<Capture>,
+    stream: T,
+) -> Box<dyn ByteStream> {
+    match capture {
+        Some(capture) => Box::new(capture.record(stream)),
+        None => Box::new(stream),
+    }
+}
+
+fn recorded_bus<T: FullDuplex + 'static>(capture: &Option<Capture>, bus: T) -> Box<dyn FullDuplex> {
+    match capture {
+        Some(capture) => Box::new(capture.record(bus)),
+        None => Box::new(bus),
+    }
+}
+
+fn available_serial_ports() -> Vec<String> {
+    let ports = serialport::available_ports().expect("No ports found!");
+    ports.into_iter().map(|p| p.port_name).collect()
+}
+
+fn available_spi_ports() -> Vec<String> {
+    let Ok(spidevices) = read_dir("/dev") else {
+        return vec![];
+    };
+    spidevices
+        .map(|x| x.unwrap().file_name().to_string_lossy().into_owned())
+        .filter(|name| name.starts_with("spidev"))
+        .collect()
 }
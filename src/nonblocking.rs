@@ -0,0 +1,349 @@
+//! Async versions of [DfuLoader](crate::dfuloader::DfuLoader) and the
+//! transports, for use inside a tokio runtime.
+//!
+//! Nothing in here blocks the executor, waiting for a slow erase or a
+//! retry delay is done with tokio timers.
+
+use crate::dfuloader::DfuLoaderError::*;
+use crate::dfuloader::{
+    BootLoaderInfo, BootloaderChipId, BootloaderOptions, DfuLoaderError, Exchange, Failure, Stage,
+    ACK, ERASE_TIMEOUT, NAK,
+};
+use crate::serial::SerialSettings;
+use crate::trace;
+use crate::usart;
+use async_trait::async_trait;
+use serialport::{DataBits, StopBits};
+use std::error::Error;
+use std::future::Future;
+use std::io;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::time::{sleep, timeout};
+use tokio_serial::{SerialPortBuilderExt, SerialStream};
+
+/// How long to wait for a response byte
+pub(crate) const RESPONSE_TIMEOUT: Duration = Duration::from_millis(100);
+
+/// The async counterpart of [DfuLoader](crate::dfuloader::DfuLoader)
+#[async_trait]
+pub trait AsyncDfuLoader: Send {
+    async fn initialize(&mut self) -> Result<(), DfuLoaderError>;
+
+    async fn get_version(&mut self) -> Result<BootloaderOptions, DfuLoaderError>;
+
+    async fn supported_functions(&mut self) -> Result<BootLoaderInfo, DfuLoaderError>;
+
+    async fn get_id(&mut self) -> Result<BootloaderChipId, DfuLoaderError>;
+
+    async fn write_unprotect(&mut self) -> Result<(), DfuLoaderError>;
+
+    async fn read_memory(&mut self, address: u32, size: usize) -> Result<Vec<u8>, DfuLoaderError>;
+    async fn write_memory(&mut self, address: u32, data: Vec<u8>) -> Result<(), DfuLoaderError>;
+
+    async fn erase_all(&mut self) -> Result<(), DfuLoaderError>;
+
+    async fn erase_pages(&mut self, pages: &[u16]) -> Result<(), DfuLoaderError>;
+
+    async fn go(&mut self, address: u32) -> Result<(), DfuLoaderError>;
+
+    async fn get_checksum(&mut self, address: u32, length: u32) -> Result<u32, DfuLoaderError>;
+}
+
+/// An async bidirectional byte stream, the async counterpart of
+/// [ByteStream](crate::transport::ByteStream). Timeouts are handled by the
+/// protocol, so reads may wait forever.
+pub trait AsyncByteStream: AsyncRead + AsyncWrite + Send + Unpin {}
+
+impl AsyncByteStream for SerialStream {}
+
+impl AsyncByteStream for TcpStream {}
+
+impl AsyncByteStream for tokio::io::DuplexStream {}
+
+/// Open a serial port for the USART protocol, must be called from within a tokio runtime
+pub fn new_serial_connection(
+    device_name: &str,
+) -> Result<Box<dyn AsyncDfuLoader>, Box<dyn Error + Send + Sync>> {
+    new_serial_connection_with(device_name, &SerialSettings::default())
+}
+
+pub fn new_serial_connection_with(
+    device_name: &str,
+    settings: &SerialSettings,
+) -> Result<Box<dyn AsyncDfuLoader>, Box<dyn Error + Send + Sync>> {
+    let port = tokio_serial::new(device_name, settings.baud_rate)
+        .parity(settings.parity)
+        .data_bits(DataBits::Eight)
+        .stop_bits(StopBits::One)
+        .open_native_async()?;
+
+    let connection = AsyncSerialConnection::new(Box::new(port)).with_timeout(settings.timeout);
+    Ok(Box::new(connection))
+}
+
+/// Run the USART protocol over TCP, e.g. to a ser2net port, `address` is host:port
+pub async fn new_tcp_connection(
+    address: &str,
+) -> Result<Box<dyn AsyncDfuLoader>, Box<dyn Error + Send + Sync>> {
+    let stream = TcpStream::connect(address).await?;
+    stream.set_nodelay(true)?;
+
+    Ok(Box::new(AsyncSerialConnection::new(Box::new(stream))))
+}
+
+/// Fail with [io::ErrorKind::TimedOut] when `future` takes longer than `duration`
+pub(crate) async fn with_timeout<T>(
+    duration: Duration,
+    future: impl Future<Output = io::Result<T>>,
+) -> io::Result<T> {
+    timeout(duration, future)
+        .await
+        .unwrap_or_else(|_| Err(io::ErrorKind::TimedOut.into()))
+}
+
+/// The USART bootloader protocol (AN3155) over any async byte stream
+pub struct AsyncSerialConnection {
+    port: Box<dyn AsyncByteStream>,
+    exchange: Exchange,
+    timeout: Duration,
+}
+
+impl AsyncSerialConnection {
+    pub fn new(port: Box<dyn AsyncByteStream>) -> Self {
+        AsyncSerialConnection {
+            port,
+            exchange: Exchange::default(),
+            timeout: RESPONSE_TIMEOUT,
+        }
+    }
+
+    /// Wait this long for a response byte instead of 100 ms
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    async fn send_command(&mut self, command: u8) -> Result<(), DfuLoaderError> {
+        self.exchange = Exchange::start(command);
+        self.write(Stage::Command, &usart::command(command)).await?;
+        self.read_ack(Stage::Command).await
+    }
+
+    async fn send_address(&mut self, address: u32) -> Result<(), DfuLoaderError> {
+        self.exchange.address = Some(address);
+        self.write(Stage::Address, &usart::address(address)).await?;
+        self.read_ack(Stage::Address).await
+    }
+
+    async fn write(&mut self, stage: Stage, data: &[u8]) -> Result<(), DfuLoaderError> {
+        self.exchange.sent(stage, data);
+        let result = self.port.write_all(data).await;
+        result.map_err(|e| self.exchange.fail(stage, e))
+    }
+
+    async fn read_ack(&mut self, stage: Stage) -> Result<(), DfuLoaderError> {
+        self.receive_ack(stage, self.timeout).await
+    }
+
+    /// Erasing can take a while, wait longer for the final acknowledge
+    async fn wait_for_ack(&mut self) -> Result<(), DfuLoaderError> {
+        self.receive_ack(Stage::Ack, ERASE_TIMEOUT).await
+    }
+
+    async fn receive_ack(
+        &mut self,
+        stage: Stage,
+        duration: Duration,
+    ) -> Result<(), DfuLoaderError> {
+        let mut ack = [0u8; 1];
+        let result = with_timeout(duration, self.port.read_exact(&mut ack)).await;
+        result.map_err(|e| self.exchange.fail(stage, e))?;
+        self.exchange.received(stage, &ack);
+        if ack[0] != ACK {
+            return Err(self.exchange.fail(stage, Failure::from_response(ack[0])));
+        }
+        Ok(())
+    }
+
+    async fn read_bytes(&mut self, size: usize) -> Result<Vec<u8>, DfuLoaderError> {
+        let mut data = vec![0u8; size];
+        let result = with_timeout(self.timeout, self.port.read_exact(&mut data)).await;
+        result.map_err(|e| self.exchange.fail(Stage::Data, e))?;
+        self.exchange.received(Stage::Data, &data);
+        Ok(data)
+    }
+
+    fn reject(&self, (stage, failure): usart::Rejection) -> DfuLoaderError {
+        self.exchange.fail(stage, failure)
+    }
+}
+
+#[async_trait]
+impl AsyncDfuLoader for AsyncSerialConnection {
+    async fn initialize(&mut self) -> Result<(), DfuLoaderError> {
+        for _ in 0..10 {
+            trace::sync("->", &[usart::SYNC]);
+            self.port.write_all(&[usart::SYNC]).await?;
+
+            let mut response = [0u8; 1];
+            let result = with_timeout(self.timeout, self.port.read_exact(&mut response)).await;
+            if result.is_ok() {
+                trace::sync("<-", &response);
+            }
+            match result {
+                Err(e) if e.kind() == io::ErrorKind::TimedOut => (),
+                Ok(_) if response[0] == ACK || response[0] == NAK => return Ok(()),
+                Ok(_) => (),
+                Err(e) => return Err(e.into()),
+            }
+
+            sleep(Duration::from_millis(500)).await;
+        }
+        Err(SyncError())
+    }
+
+    async fn get_version(&mut self) -> Result<BootloaderOptions, DfuLoaderError> {
+        self.send_command(0x01).await?;
+
+        let response = self.read_bytes(4).await?;
+        usart::parse_version(&response).map_err(|rejection| self.reject(rejection))
+    }
+
+    async fn supported_functions(&mut self) -> Result<BootLoaderInfo, DfuLoaderError> {
+        self.send_command(0x00).await?;
+
+        let length = self.read_bytes(1).await?[0] as usize;
+        let response = self.read_bytes(length + 2).await?;
+        usart::parse_functions(&response).map_err(|rejection| self.reject(rejection))
+    }
+
+    async fn get_id(&mut self) -> Result<BootloaderChipId, DfuLoaderError> {
+        self.send_command(0x02).await?;
+
+        let length = self.read_bytes(1).await?[0];
+        usart::check_id_length(length).map_err(|rejection| self.reject(rejection))?;
+
+        let response = self.read_bytes(3).await?;
+        usart::parse_id(&response).map_err(|rejection| self.reject(rejection))
+    }
+
+    async fn write_unprotect(&mut self) -> Result<(), DfuLoaderError> {
+        self.send_command(0x73).await?;
+        self.wait_for_ack().await
+    }
+
+    async fn read_memory(&mut self, address: u32, size: usize) -> Result<Vec<u8>, DfuLoaderError> {
+        let length = usart::read_length(size)?;
+
+        self.send_command(0x11).await?;
+        self.send_address(address).await?;
+
+        self.write(Stage::Length, &length).await?;
+        self.read_ack(Stage::Length).await?;
+
+        self.read_bytes(size).await
+    }
+
+    async fn write_memory(&mut self, address: u32, data: Vec<u8>) -> Result<(), DfuLoaderError> {
+        let out = usart::write_data(data)?;
+
+        self.send_command(0x31).await?;
+        self.send_address(address).await?;
+
+        self.write(Stage::Data, &out).await?;
+        self.read_ack(Stage::Data).await
+    }
+
+    async fn erase_all(&mut self) -> Result<(), DfuLoaderError> {
+        self.send_command(0x44).await?;
+        self.write(Stage::Length, &usart::ERASE_ALL).await?;
+        self.wait_for_ack().await
+    }
+
+    async fn erase_pages(&mut self, pages: &[u16]) -> Result<(), DfuLoaderError> {
+        let erase_request = usart::erase_pages(pages)?;
+
+        self.send_command(0x44).await?;
+        self.write(Stage::Length, &erase_request).await?;
+
+        self.wait_for_ack().await
+    }
+
+    async fn go(&mut self, address: u32) -> Result<(), DfuLoaderError> {
+        self.send_command(0x21).await?;
+        self.send_address(address).await
+    }
+
+    async fn get_checksum(&mut self, address: u32, length: u32) -> Result<u32, DfuLoaderError> {
+        let size = usart::checksum_length(address, length)?;
+
+        self.send_command(0xA1).await?;
+        self.send_address(address).await?;
+
+        self.write(Stage::Length, &size).await?;
+        self.read_ack(Stage::Length).await?;
+
+        let response = self.read_bytes(5).await?;
+        usart::parse_checksum(&response).map_err(|rejection| self.reject(rejection))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dfuloader::Functions;
+    use crate::emulator::EmulatedDevice;
+    use std::io::{Read, Write};
+    use tokio::io::{duplex, DuplexStream};
+
+    const FLASH: u32 = 0x0800_0000;
+
+    /// Pump bytes between one end of an in-memory pipe and the emulated device
+    fn serve(device: EmulatedDevice) -> DuplexStream {
+        let (host, mut target) = duplex(1024);
+        tokio::spawn(async move {
+            let mut usart = device.usart();
+            let mut buffer = [0u8; 512];
+            while let Ok(n @ 1..) = target.read(&mut buffer).await {
+                usart.write_all(&buffer[..n]).unwrap();
+                while let Ok(n) = usart.read(&mut buffer) {
+                    target.write_all(&buffer[..n]).await.unwrap();
+                }
+            }
+        });
+        host
+    }
+
+    #[tokio::test]
+    async fn identifies_device() {
+        let device = EmulatedDevice::new(0x433).unwrap();
+        let mut connection = AsyncSerialConnection::new(Box::new(serve(device)));
+
+        connection.initialize().await.unwrap();
+        assert_eq!(connection.get_id().await.unwrap().chipid, 0x433);
+        let info = connection.supported_functions().await.unwrap();
+        assert!(info.supports(Functions::ExtendedErase));
+    }
+
+    #[tokio::test]
+    async fn writes_erases_and_reads_back() {
+        let device = EmulatedDevice::new(0x433).unwrap();
+        let mut connection: Box<dyn AsyncDfuLoader> =
+            Box::new(AsyncSerialConnection::new(Box::new(serve(device.clone()))));
+        connection.initialize().await.unwrap();
+
+        connection
+            .write_memory(FLASH, vec![1, 2, 3, 4])
+            .await
+            .unwrap();
+        assert_eq!(
+            connection.read_memory(FLASH, 4).await.unwrap(),
+            vec![1, 2, 3, 4]
+        );
+
+        connection.erase_pages(&[0]).await.unwrap();
+        assert_eq!(device.read_flash(FLASH, 4), vec![0xFF; 4]);
+    }
+}
@@ -0,0 +1,687 @@
+//! Per-device data patched into an image before it is written, so one
+//! generic firmware image can be personalised for every unit.
+//!
+//! A patch is `ADDRESS=VALUE` with a hexadecimal address and one of these
+//! values:
+//!
+//! ```text
+//! hex:0011AABB         the bytes as written
+//! u8:7 u16:0x1234 u32:100000
+//!                      an integer, little endian
+//! str:text             the ASCII text, not terminated
+//! counter:serial.txt   the decimal number in the file as u32, the file is
+//!                      incremented after the device was written, see
+//!                      [Counters]
+//! counter:serial.txt@u16be counter:serial.txt@SN-{:06}
+//!                      the number in another format, see [CounterFormat]
+//! uid                  the 96-bit unique device id
+//! mac mac:00:80:E1     a MAC address derived from the unique id, locally
+//!                      administered or with the given vendor prefix
+//! csv:units.csv:key    the `key` column of the row for the unique id
+//! ```
+//!
+//! The first column of a CSV file is the unique id in hex as `info` prints
+//! it, the first row names the columns. A cell is a value like above, text
+//! without a prefix. Fields are split at commas, there is no quoting.
+
+use log::info;
+use std::fs::{self, File, OpenOptions, TryLockError};
+use std::io::{self, ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::{error::Error, fmt::Display, fmt::Formatter};
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Patch {
+    pub address: u32,
+    pub value: PatchValue,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum PatchValue {
+    Bytes(Vec<u8>),
+    /// A serial number kept in a file
+    Counter {
+        path: PathBuf,
+        format: CounterFormat,
+    },
+    UniqueId,
+    /// A MAC address from the unique id, with this vendor prefix or else
+    /// locally administered
+    Mac(Option<[u8; 3]>),
+    /// A column of the row for the unique id
+    Csv {
+        path: PathBuf,
+        column: String,
+    },
+}
+
+#[derive(Debug)]
+pub enum PatchError {
+    Syntax(String),
+    /// The value is derived from the unique id but the device has none
+    NoUniqueId,
+    NotInCsv(PathBuf, String),
+    /// The counter file does not hold a number
+    BadCounter(PathBuf),
+    /// The counter is past the largest number its format can hold
+    CounterOverflow(PathBuf),
+    IOError(io::Error),
+}
+
+impl Patch {
+    /// Whether the value is derived from the unique device id
+    pub fn needs_unique_id(&self) -> bool {
+        matches!(
+            self.value,
+            PatchValue::UniqueId | PatchValue::Mac(_) | PatchValue::Csv { .. }
+        )
+    }
+
+    /// The bytes for the device with this unique id
+    pub fn resolve(&self, uid: Option<&[u8; 12]>) -> Result<Vec<u8>, PatchError> {
+        let uid = || uid.ok_or(PatchError::NoUniqueId);
+        match &self.value {
+            PatchValue::Bytes(bytes) => Ok(bytes.clone()),
+            PatchValue::Counter { path, format } => format
+                .encode(read_counter(path)?)
+                .ok_or_else(|| PatchError::CounterOverflow(path.clone())),
+            PatchValue::UniqueId => Ok(uid()?.to_vec()),
+            PatchValue::Mac(prefix) => Ok(mac_address(uid()?, *prefix).to_vec()),
+            PatchValue::Csv { path, column } => {
+                let cell = csv_cell(path, column, uid()?)?;
+                match parse_bytes(&cell) {
+                    Some(bytes) => bytes,
+                    None => Ok(cell.into_bytes()),
+                }
+            }
+        }
+    }
+}
+
+/// The counter files of a set of patches, locked from before the patches are
+/// resolved until the device was written, so stations sharing a file never
+/// program the same number. Dropping them without [Counters::commit], e.g.
+/// after a failed write, releases the numbers for the next device.
+///
+/// The locks are advisory locks on a `.lock` file next to each counter, the
+/// system drops them when the process ends. The counter itself is replaced in
+/// one step, so an interruption leaves the old or the new number, never an
+/// empty file.
+pub struct Counters {
+    files: Vec<(PathBuf, File)>,
+}
+
+impl Counters {
+    /// Lock every counter file of `patches` once, waiting while another
+    /// station holds one
+    pub fn lock(patches: &[Patch]) -> Result<Self, PatchError> {
+        let mut paths: Vec<&PathBuf> = patches
+            .iter()
+            .filter_map(|patch| match &patch.value {
+                PatchValue::Counter { path, .. } => Some(path),
+                _ => None,
+            })
+            .collect();
+        // Always in the same order, so two stations cannot each hold one file
+        // and wait for the other
+        paths.sort();
+        paths.dedup();
+
+        let mut files = vec![];
+        for path in paths {
+            let file = OpenOptions::new()
+                .write(true)
+                .create(true)
+                .truncate(false)
+                .open(with_suffix(path, "lock"))?;
+            match file.try_lock() {
+                Ok(()) => {}
+                Err(TryLockError::WouldBlock) => {
+                    info!("Waiting for another station to release {}", path.display());
+                    file.lock()?;
+                }
+                Err(TryLockError::Error(err)) => return Err(err.into()),
+            }
+            files.push((path.clone(), file));
+        }
+        Ok(Counters { files })
+    }
+
+    /// Count every file up by one after the device was written, so the next
+    /// one gets a new number
+    pub fn commit(self) -> Result<(), PatchError> {
+        for (path, _) in &self.files {
+            let next = read_counter(path)?
+                .checked_add(1)
+                .ok_or_else(|| PatchError::CounterOverflow(path.clone()))?;
+            write_counter(path, next)?;
+        }
+        Ok(())
+    }
+}
+
+/// `path` with `suffix` appended to the file name, e.g. serial.txt.lock
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(".");
+    name.push(suffix);
+    PathBuf::from(name)
+}
+
+/// Replace the counter through a synced temporary file, like
+/// [Journal::save](crate::journal::Journal::save)
+fn write_counter(path: &Path, value: u32) -> Result<(), PatchError> {
+    let temporary = with_suffix(path, "tmp");
+    let mut file = File::create(&temporary)?;
+    file.write_all(format!("{}\n", value).as_bytes())?;
+    file.sync_all()?;
+    fs::rename(&temporary, path)?;
+    // Make the rename itself durable
+    if let Some(directory) = path.parent().filter(|p| !p.as_os_str().is_empty()) {
+        File::open(directory)?.sync_all()?;
+    }
+    Ok(())
+}
+
+/// How a counter is written into the image, after the `@` of
+/// `counter:FILE@FORMAT`:
+///
+/// ```text
+/// u8 u16 u32           a little endian integer, u32 without a format
+/// u16be u32be          a big endian integer
+/// SN-{}                ASCII text with the decimal number for {}
+/// SN-{:06} SN-{:6}     the number in exactly 6 digits, padded with zeros or
+///                      spaces
+/// ```
+///
+/// A number that does not fit the format is a
+/// [PatchError::CounterOverflow], a fixed width is a fixed field in the
+/// image.
+#[derive(Debug, Clone, PartialEq)]
+pub enum CounterFormat {
+    /// An integer of `width` bytes
+    Integer { width: usize, big_endian: bool },
+    /// Text with the number between `prefix` and `suffix`, `width` 0 for
+    /// as many digits as it has
+    Text {
+        prefix: String,
+        width: usize,
+        zeros: bool,
+        suffix: String,
+    },
+}
+
+impl Default for CounterFormat {
+    fn default() -> Self {
+        CounterFormat::Integer {
+            width: 4,
+            big_endian: false,
+        }
+    }
+}
+
+impl CounterFormat {
+    /// The bytes of `number`, `None` if it does not fit
+    fn encode(&self, number: u32) -> Option<Vec<u8>> {
+        match self {
+            CounterFormat::Integer { width, big_endian } => {
+                let bytes = number.to_le_bytes();
+                if bytes[*width..].iter().any(|&b| b != 0) {
+                    return None;
+                }
+                let mut bytes = bytes[..*width].to_vec();
+                if *big_endian {
+                    bytes.reverse();
+                }
+                Some(bytes)
+            }
+            CounterFormat::Text {
+                prefix,
+                width,
+                zeros,
+                suffix,
+            } => {
+                let digits = match zeros {
+                    true => format!("{:0width$}", number, width = *width),
+                    false => format!("{:width$}", number, width = *width),
+                };
+                if *width > 0 && digits.len() > *width {
+                    return None;
+                }
+                Some(format!("{}{}{}", prefix, digits, suffix).into_bytes())
+            }
+        }
+    }
+}
+
+impl FromStr for CounterFormat {
+    type Err = PatchError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let integer = |width, big_endian| CounterFormat::Integer { width, big_endian };
+        match s {
+            "u8" => Ok(integer(1, false)),
+            "u16" => Ok(integer(2, false)),
+            "u32" => Ok(integer(4, false)),
+            "u16be" => Ok(integer(2, true)),
+            "u32be" => Ok(integer(4, true)),
+            _ => parse_template(s).ok_or_else(|| {
+                PatchError::Syntax(format!(
+                    "{} is not u8, u16, u32, u16be, u32be or text like SN-{{:06}}",
+                    s
+                ))
+            }),
+        }
+    }
+}
+
+/// ASCII text with exactly one `{}`, `{:N}` or `{:0N}`
+fn parse_template(template: &str) -> Option<CounterFormat> {
+    let (prefix, rest) = template.split_once('{')?;
+    let (spec, suffix) = rest.split_once('}')?;
+    if !template.is_ascii() || prefix.contains('}') || suffix.contains(['{', '}']) {
+        return None;
+    }
+    let (zeros, width) = match spec.strip_prefix(':') {
+        Some(width) => (width.starts_with('0'), width.parse().ok()?),
+        None if spec.is_empty() => (false, 0),
+        None => return None,
+    };
+    Some(CounterFormat::Text {
+        prefix: prefix.to_string(),
+        width,
+        zeros,
+        suffix: suffix.to_string(),
+    })
+}
+
+impl FromStr for Patch {
+    type Err = PatchError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let syntax = || PatchError::Syntax(format!("{} is not ADDRESS=VALUE", s));
+        let (address, value) = s.split_once('=').ok_or_else(syntax)?;
+        let address = u32::from_str_radix(address.trim_start_matches("0x"), 16)
+            .map_err(|_| PatchError::Syntax(format!("{} is not a hex address", address)))?;
+
+        let value = match value.split_once(':') {
+            _ if value == "uid" => PatchValue::UniqueId,
+            _ if value == "mac" => PatchValue::Mac(None),
+            Some(("mac", prefix)) => {
+                let prefix = parse_mac_prefix(prefix).ok_or_else(|| {
+                    PatchError::Syntax(format!("{} is not a vendor prefix like 00:80:E1", prefix))
+                })?;
+                PatchValue::Mac(Some(prefix))
+            }
+            Some(("counter", path_format)) => {
+                // The template may hold an @, a file name rarely does
+                let (path, format) = match path_format.split_once('@') {
+                    Some((path, format)) => (path, format.parse()?),
+                    None => (path_format, CounterFormat::default()),
+                };
+                PatchValue::Counter {
+                    path: PathBuf::from(path),
+                    format,
+                }
+            }
+            Some(("csv", path_column)) => {
+                let (path, column) = path_column.rsplit_once(':').ok_or_else(|| {
+                    PatchError::Syntax(format!("{} is not csv:FILE:COLUMN", value))
+                })?;
+                PatchValue::Csv {
+                    path: PathBuf::from(path),
+                    column: column.to_string(),
+                }
+            }
+            _ => match parse_bytes(value) {
+                Some(bytes) => PatchValue::Bytes(bytes?),
+                None => {
+                    return Err(PatchError::Syntax(format!(
+                        "{} is not hex:, u8:, u16:, u32:, str:, counter:, uid, mac or csv:",
+                        value
+                    )))
+                }
+            },
+        };
+        Ok(Patch { address, value })
+    }
+}
+
+/// The literal values, `None` without a known prefix
+fn parse_bytes(value: &str) -> Option<Result<Vec<u8>, PatchError>> {
+    let (kind, literal) = value.split_once(':')?;
+    let bytes = match kind {
+        "hex" => parse_hex(literal),
+        "u8" => parse_integer(literal, 1),
+        "u16" => parse_integer(literal, 2),
+        "u32" => parse_integer(literal, 4),
+        "str" => Some(literal.as_bytes().to_vec()),
+        _ => return None,
+    };
+    Some(
+        bytes.ok_or_else(|| {
+            PatchError::Syntax(format!("{} is not a valid {} value", literal, kind))
+        }),
+    )
+}
+
+/// A little endian integer of `width` bytes, decimal or hexadecimal with a
+/// 0x prefix
+fn parse_integer(literal: &str, width: usize) -> Option<Vec<u8>> {
+    let number = match literal.strip_prefix("0x") {
+        Some(hex) => u32::from_str_radix(hex, 16).ok()?,
+        None => literal.parse().ok()?,
+    };
+    let bytes = u32::to_le_bytes(number);
+    match bytes[width..].iter().all(|&b| b == 0) {
+        true => Some(bytes[..width].to_vec()),
+        false => None,
+    }
+}
+
+fn parse_hex(literal: &str) -> Option<Vec<u8>> {
+    if !literal.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..literal.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(literal.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+fn parse_mac_prefix(prefix: &str) -> Option<[u8; 3]> {
+    let bytes: Vec<u8> = prefix
+        .split(':')
+        .map(|b| u8::from_str_radix(b, 16).ok())
+        .collect::<Option<_>>()?;
+    bytes.try_into().ok()
+}
+
+fn read_counter(path: &Path) -> Result<u32, PatchError> {
+    let content = match fs::read_to_string(path) {
+        Ok(content) => content,
+        // A new counter starts at 0
+        Err(err) if err.kind() == ErrorKind::NotFound => return Ok(0),
+        Err(err) => return Err(err.into()),
+    };
+    // Only a missing file is a new counter, an empty one lost its number
+    content
+        .trim()
+        .parse()
+        .map_err(|_| PatchError::BadCounter(path.to_path_buf()))
+}
+
+/// The same unique id always gets the same address, the bytes come from a
+/// 64-bit FNV-1a hash of the id
+fn mac_address(uid: &[u8; 12], prefix: Option<[u8; 3]>) -> [u8; 6] {
+    let hash = uid.iter().fold(0xCBF2_9CE4_8422_2325u64, |hash, &b| {
+        (hash ^ u64::from(b)).wrapping_mul(0x0100_0000_01B3)
+    });
+    let hash = hash.to_le_bytes();
+    match prefix {
+        Some([a, b, c]) => [a, b, c, hash[0], hash[1], hash[2]],
+        // Locally administered, unicast
+        None => [0x02, hash[0], hash[1], hash[2], hash[3], hash[4]],
+    }
+}
+
+fn csv_cell(path: &Path, column: &str, uid: &[u8; 12]) -> Result<String, PatchError> {
+    let content = fs::read_to_string(path)?;
+    let uid: String = uid.iter().map(|b| format!("{:02X}", b)).collect();
+    let not_found = |what: String| PatchError::NotInCsv(path.to_path_buf(), what);
+
+    let mut rows = content
+        .lines()
+        .map(|line| line.split(',').map(str::trim).collect::<Vec<_>>());
+    let header = rows.next().unwrap_or_default();
+    let index = header
+        .iter()
+        .position(|&name| name == column)
+        .ok_or_else(|| not_found(format!("column {}", column)))?;
+    let row = rows
+        .find(|row| row.first().is_some_and(|id| id.eq_ignore_ascii_case(&uid)))
+        .ok_or_else(|| not_found(format!("unique id {}", uid)))?;
+    row.get(index)
+        .map(|cell| cell.to_string())
+        .ok_or_else(|| not_found(format!("column {} for {}", column, uid)))
+}
+
+impl Error for PatchError {}
+
+impl Display for PatchError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PatchError::Syntax(reason) => write!(f, "Invalid patch: {}", reason),
+            PatchError::NoUniqueId => {
+                write!(
+                    f,
+                    "The patch needs the unique id, which is unknown for this device"
+                )
+            }
+            PatchError::NotInCsv(path, what) => {
+                write!(f, "No {} in {}", what, path.display())
+            }
+            PatchError::BadCounter(path) => {
+                write!(f, "The counter {} does not hold a number", path.display())
+            }
+            PatchError::CounterOverflow(path) => {
+                write!(f, "The counter {} has run out of numbers", path.display())
+            }
+            PatchError::IOError(io_err) => write!(f, "I/O error: {}", io_err),
+        }
+    }
+}
+
+impl From<io::Error> for PatchError {
+    fn from(err: io::Error) -> Self {
+        PatchError::IOError(err)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::image::MemoryImage;
+    use std::env::temp_dir;
+
+    const UID: [u8; 12] = *b"0123456789AB";
+
+    fn temp_path(name: &str) -> PathBuf {
+        temp_dir().join(format!("stm32loader-{}-{}", std::process::id(), name))
+    }
+
+    fn resolve(patch: &str) -> Vec<u8> {
+        patch.parse::<Patch>().unwrap().resolve(Some(&UID)).unwrap()
+    }
+
+    #[test]
+    fn parses_literal_values() {
+        let patch: Patch = "0x0800F000=u32:0x12345678".parse().unwrap();
+        assert_eq!(patch.address, 0x0800_F000);
+        assert_eq!(patch.value, PatchValue::Bytes(vec![0x78, 0x56, 0x34, 0x12]));
+
+        assert_eq!(resolve("8000000=hex:0011aa"), vec![0x00, 0x11, 0xAA]);
+        assert_eq!(resolve("8000000=u16:258"), vec![2, 1]);
+        assert_eq!(resolve("8000000=str:SN"), b"SN".to_vec());
+        assert!("8000000=u8:256".parse::<Patch>().is_err());
+        assert!("8000000=hex:123".parse::<Patch>().is_err());
+        assert!("8000000=42".parse::<Patch>().is_err());
+    }
+
+    #[test]
+    fn derives_values_from_the_unique_id() {
+        assert_eq!(resolve("8000000=uid"), UID.to_vec());
+
+        let mac = resolve("8000000=mac");
+        assert_eq!(mac.len(), 6);
+        assert_eq!(mac[0], 0x02);
+        assert_eq!(resolve("8000000=mac"), mac);
+        assert_eq!(
+            &resolve("8000000=mac:00:80:E1")[..],
+            &[0x00, 0x80, 0xE1, mac[1], mac[2], mac[3]]
+        );
+
+        let patch: Patch = "8000000=uid".parse().unwrap();
+        assert!(matches!(patch.resolve(None), Err(PatchError::NoUniqueId)));
+    }
+
+    #[test]
+    fn overwrites_and_extends_the_image() {
+        let mut image = MemoryImage::from_binary(0x0800_0000, &[1, 2, 3, 4]).unwrap();
+        image.add_segment(0x0800_0008, &[9, 9]).unwrap();
+
+        image
+            .patch(0x0800_0002, &[0xA, 0xB, 0xC, 0xD, 0xE, 0xF, 0x10])
+            .unwrap();
+
+        let segments: Vec<_> = image.segments().collect();
+        assert_eq!(
+            segments,
+            vec![(
+                0x0800_0000,
+                &[1, 2, 0xA, 0xB, 0xC, 0xD, 0xE, 0xF, 0x10, 9][..]
+            )]
+        );
+    }
+
+    #[test]
+    fn counts_up_after_commit() {
+        let path = temp_path("counter");
+        let _ = fs::remove_file(&path);
+        let patch: Patch = format!("8000000=counter:{}", path.display())
+            .parse()
+            .unwrap();
+        // Both patches get the same number, the file counts up once
+        let patches = [
+            patch.clone(),
+            Patch {
+                address: 0x0800_0010,
+                ..patch.clone()
+            },
+        ];
+
+        let counters = Counters::lock(&patches).unwrap();
+        assert_eq!(patch.resolve(None).unwrap(), vec![0, 0, 0, 0]);
+        counters.commit().unwrap();
+        Counters::lock(&patches).unwrap().commit().unwrap();
+        assert_eq!(patch.resolve(None).unwrap(), vec![2, 0, 0, 0]);
+        assert_eq!(fs::read_to_string(&path).unwrap(), "2\n");
+
+        // Without a commit the number is free for the next device
+        drop(Counters::lock(&patches).unwrap());
+        assert_eq!(patch.resolve(None).unwrap(), vec![2, 0, 0, 0]);
+
+        fs::write(&path, format!("{}\n", u32::MAX)).unwrap();
+        let counters = Counters::lock(&patches).unwrap();
+        assert!(matches!(
+            counters.commit(),
+            Err(PatchError::CounterOverflow(_))
+        ));
+
+        fs::remove_file(&path).unwrap();
+        fs::remove_file(with_suffix(&path, "lock")).unwrap();
+    }
+
+    #[test]
+    fn formats_the_counter() {
+        let path = temp_path("formatted-counter");
+        let counter = |format: &str| {
+            format!("8000000=counter:{}@{}", path.display(), format)
+                .parse::<Patch>()
+                .and_then(|patch| patch.resolve(None))
+        };
+
+        fs::write(&path, "41\n").unwrap();
+        assert_eq!(counter("u8").unwrap(), vec![41]);
+        assert_eq!(counter("u16be").unwrap(), vec![0, 41]);
+        assert_eq!(counter("u32be").unwrap(), vec![0, 0, 0, 41]);
+        assert_eq!(counter("SN-{:06}").unwrap(), b"SN-000041".to_vec());
+        assert_eq!(counter("{:4}@line").unwrap(), b"  41@line".to_vec());
+        assert_eq!(counter("#{}").unwrap(), b"#41".to_vec());
+        assert!(matches!(
+            counter("{:01}"),
+            Err(PatchError::CounterOverflow(_))
+        ));
+
+        fs::write(&path, "256\n").unwrap();
+        assert!(matches!(counter("u8"), Err(PatchError::CounterOverflow(_))));
+        assert_eq!(counter("u16").unwrap(), vec![0, 1]);
+
+        for format in ["u24", "SN-{:x}", "{}-{}", "SN", "{:06"] {
+            assert!(matches!(counter(format), Err(PatchError::Syntax(_))));
+        }
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn locks_the_counter_until_it_is_committed() {
+        let path = temp_path("locked-counter");
+        let _ = fs::remove_file(&path);
+        let patch: Patch = format!("8000000=counter:{}", path.display())
+            .parse()
+            .unwrap();
+
+        let counters = Counters::lock(std::slice::from_ref(&patch)).unwrap();
+        let other_station = File::open(with_suffix(&path, "lock")).unwrap();
+        assert!(matches!(
+            other_station.try_lock(),
+            Err(TryLockError::WouldBlock)
+        ));
+        counters.commit().unwrap();
+        other_station.try_lock().unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "1\n");
+
+        fs::remove_file(&path).unwrap();
+        fs::remove_file(with_suffix(&path, "lock")).unwrap();
+    }
+
+    #[test]
+    fn refuses_an_empty_or_garbled_counter() {
+        let path = temp_path("empty-counter");
+        let patch: Patch = format!("8000000=counter:{}", path.display())
+            .parse()
+            .unwrap();
+
+        for content in ["", "12x\n"] {
+            fs::write(&path, content).unwrap();
+            assert!(matches!(
+                patch.resolve(None),
+                Err(PatchError::BadCounter(_))
+            ));
+            let counters = Counters::lock(std::slice::from_ref(&patch)).unwrap();
+            assert!(matches!(counters.commit(), Err(PatchError::BadCounter(_))));
+            assert_eq!(fs::read_to_string(&path).unwrap(), content);
+        }
+
+        fs::remove_file(&path).unwrap();
+        fs::remove_file(with_suffix(&path, "lock")).unwrap();
+    }
+
+    #[test]
+    fn looks_up_the_unique_id_in_a_csv_file() {
+        let path = temp_path("units.csv");
+        fs::write(
+            &path,
+            "uid, serial, key\n\
+             000000000000000000000000, 1, hex:00\n\
+             303132333435363738394142, 2, hex:C0FFEE\n",
+        )
+        .unwrap();
+
+        let csv = |column: &str| format!("8000000=csv:{}:{}", path.display(), column);
+        assert_eq!(resolve(&csv("serial")), b"2".to_vec());
+        assert_eq!(resolve(&csv("key")), vec![0xC0, 0xFF, 0xEE]);
+
+        let patch: Patch = csv("missing").parse().unwrap();
+        assert!(matches!(
+            patch.resolve(Some(&UID)),
+            Err(PatchError::NotInCsv(..))
+        ));
+        let patch: Patch = csv("serial").parse().unwrap();
+        assert!(patch.resolve(Some(&[0xFF; 12])).is_err());
+
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,1062 @@
+use crate::device::{self, DeviceProfile, Page};
+use crate::dfuloader::{BootLoaderInfo, BootloaderOptions, DfuLoader, DfuLoaderError, Functions};
+use crate::image::{ImageError, MemoryImage};
+use crate::journal::Journal;
+use crate::progress::{Meter, Phase, Progress, ProgressCallback};
+use crate::retry::{RetryPolicy, RetrySummary, Retrying};
+use std::path::{Path, PathBuf};
+use std::{error::Error, fmt::Display, fmt::Formatter};
+
+/// Largest block the bootloader reads or writes in one command
+pub const MAX_BLOCK_SIZE: usize = 256;
+
+/// How much is written between journal updates, saving after every block
+/// wears out the SD card of a small host. A resume rewrites at most this
+/// much, plus the page it ends in.
+const JOURNAL_INTERVAL: u32 = 4 * 1024;
+
+/// Drives a bootloader connection through the usual steps of programming
+/// a device: identify, erase, write, verify and go.
+///
+/// Nothing is printed, every step returns what it did so a front end can
+/// report it the way it likes.
+pub struct Programmer {
+    connection: Box<dyn DfuLoader>,
+    device: Option<DeviceInfo>,
+    progress: Option<ProgressCallback>,
+    retry: RetryPolicy,
+    retries: RetrySummary,
+}
+
+/// What the bootloader reported about itself and the chip
+#[derive(Debug)]
+pub struct DeviceInfo {
+    /// Only asked for by [Programmer::identify]
+    pub bootloader: Option<BootloaderOptions>,
+    pub chip_id: u16,
+    pub commands: BootLoaderInfo,
+    /// The matching profile, [device::GENERIC] for an unknown chip
+    pub profile: &'static DeviceProfile,
+}
+
+/// How to complete writes that do not cover a full flash word
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum Alignment {
+    /// Pad with 0xFF, the value of erased flash
+    #[default]
+    Pad,
+    /// Read the current flash content around unaligned edges
+    ReadModifyWrite,
+}
+
+/// What to erase before writing
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum EraseMode {
+    /// Erase nothing, the flash has to be blank where the image goes
+    #[default]
+    None,
+    /// Erase the whole flash
+    All,
+    /// Erase and write only the pages that differ from the image
+    ChangedPages,
+}
+
+#[derive(Debug, Clone)]
+pub struct WriteOptions {
+    pub erase: EraseMode,
+    pub alignment: Alignment,
+    /// Check the flash is erased before writing, ignored when erasing
+    pub blank_check: bool,
+    /// Read back and compare after writing
+    pub verify: bool,
+    /// Record the progress in this file, it is removed when the write completes
+    pub journal: Option<PathBuf>,
+    /// Continue where the journal says a previous write of the same image to
+    /// the same device stopped, after verifying what was written. Needs the
+    /// page layout to erase the chunk that was cut off, otherwise a write
+    /// that can be resumed fails with [ProgrammerError::NoPageLayout].
+    pub resume: bool,
+}
+
+impl Default for WriteOptions {
+    fn default() -> Self {
+        WriteOptions {
+            erase: EraseMode::None,
+            alignment: Alignment::Pad,
+            blank_check: true,
+            verify: false,
+            journal: None,
+            resume: false,
+        }
+    }
+}
+
+/// The result of [Programmer::write]
+#[derive(Debug)]
+pub struct WriteReport {
+    /// The image as written, extended to the write alignment
+    pub image: MemoryImage,
+    /// Pages erased because they differ from the image, or were left partly
+    /// written by the interrupted write that was resumed
+    pub erased_pages: Vec<Page>,
+    /// Number of bytes written to the device
+    pub written: usize,
+    /// Where the write continued from a journal
+    pub resumed_from: Option<u32>,
+}
+
+#[derive(Debug)]
+pub enum ProgrammerError {
+    Bootloader(DfuLoaderError),
+    Image(ImageError),
+    /// The flash is not erased at this address
+    NotBlank(u32),
+    /// The device content differs from the image at this address
+    VerifyFailed(u32),
+    /// No page layout is known for the device, so pages cannot be erased
+    NoPageLayout(&'static str),
+    /// The journal could not be read or written
+    Journal(std::io::Error),
+}
+
+impl Programmer {
+    pub fn new(connection: Box<dyn DfuLoader>) -> Self {
+        Programmer {
+            connection,
+            device: None,
+            progress: None,
+            retry: RetryPolicy::default(),
+            retries: RetrySummary::default(),
+        }
+    }
+
+    /// Report the progress of the erase, write and verify phases of
+    /// [Programmer::write] and of [Programmer::verify] to `callback`
+    pub fn set_progress(&mut self, callback: impl FnMut(&Progress) + 'static) {
+        self.progress = Some(Box::new(callback));
+    }
+
+    /// Retry read and write chunks that fail on the way, see [Retrying]
+    pub fn set_retry_policy(&mut self, policy: RetryPolicy) {
+        self.retry = policy;
+    }
+
+    /// The retries needed since the programmer was created
+    pub fn retries(&self) -> RetrySummary {
+        self.retries
+    }
+
+    /// Synchronise with the bootloader, a bootloader that is already
+    /// synchronised is fine too
+    pub fn connect(&mut self) -> Result<(), DfuLoaderError> {
+        match self.connection.initialize() {
+            Err(DfuLoaderError::AlreadySynced()) | Ok(()) => Ok(()),
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Ask the bootloader for its version, the chip id and the supported commands
+    pub fn identify(&mut self) -> Result<&DeviceInfo, DfuLoaderError> {
+        let bootloader = self.connection.get_version()?;
+        let device = self.detect()?;
+        device.bootloader = Some(bootloader);
+        Ok(device)
+    }
+
+    /// Ask only for the chip id and the supported commands, all that is
+    /// needed to select the device profile
+    pub fn detect(&mut self) -> Result<&mut DeviceInfo, DfuLoaderError> {
+        let chip_id = self.connection.get_id()?.chipid;
+        let commands = self.connection.supported_functions()?;
+        let profile = device::lookup(chip_id).unwrap_or(&device::GENERIC);
+
+        Ok(self.device.insert(DeviceInfo {
+            bootloader: None,
+            chip_id,
+            commands,
+            profile,
+        }))
+    }
+
+    /// The device found by [Programmer::identify] or [Programmer::detect], if
+    /// one of them was called
+    pub fn device(&self) -> Option<&DeviceInfo> {
+        self.device.as_ref()
+    }
+
+    /// The profile of the identified device, [device::GENERIC] before identification
+    pub fn profile(&self) -> &'static DeviceProfile {
+        self.device.as_ref().map_or(&device::GENERIC, |d| d.profile)
+    }
+
+    /// Direct access to the bootloader connection for anything not covered here
+    pub fn connection(&mut self) -> &mut dyn DfuLoader {
+        self.connection.as_mut()
+    }
+
+    pub fn erase_all(&mut self) -> Result<(), DfuLoaderError> {
+        self.connection.erase_all()
+    }
+
+    /// Check that a memory area is erased, see [blank_check]. The Get Checksum
+    /// command is used when the identified device supports it.
+    pub fn blank_check(
+        &mut self,
+        address: u32,
+        length: u32,
+    ) -> Result<Option<u32>, DfuLoaderError> {
+        let use_checksum = self
+            .device
+            .as_ref()
+            .is_some_and(|d| d.commands.supports(Functions::GetChecksum));
+        blank_check(&mut self.retrying(), address, length, use_checksum)
+    }
+
+    /// Write an image, erasing and checking the flash as requested
+    pub fn write(
+        &mut self,
+        image: &MemoryImage,
+        options: &WriteOptions,
+    ) -> Result<WriteReport, ProgrammerError> {
+        let mut image = image.clone();
+        if image.is_empty() {
+            return Ok(WriteReport {
+                image,
+                erased_pages: vec![],
+                written: 0,
+                resumed_from: None,
+            });
+        }
+
+        let mut erase = options.erase;
+        let mut resumed_from = None;
+        let mut erased_pages = vec![];
+        let mut kept = MemoryImage::new();
+        // Whether the rest of a resumed write was already made blank
+        let mut blank = false;
+        // Without a readable id the journal is only valid for the image
+        let mut journal = options
+            .journal
+            .as_ref()
+            .map(|path| (path, Journal::new(&image, self.unique_id().ok().flatten())));
+
+        let alignment = self.profile().write_alignment;
+        match (options.alignment, erase) {
+            // Flash erased as a whole reads as 0xFF anyway
+            (Alignment::Pad, _) | (Alignment::ReadModifyWrite, EraseMode::All) => {
+                image.align(alignment, |_, length| {
+                    Ok::<_, ProgrammerError>(vec![0xFF; length])
+                })?;
+            }
+            (Alignment::ReadModifyWrite, _) => {
+                let mut connection = self.retrying();
+                image.align(alignment, |address, length| {
+                    Ok::<_, ProgrammerError>(connection.read_memory(address, length)?)
+                })?;
+            }
+        }
+
+        // What is left to write, all of the image unless a write is resumed
+        let mut rest = image.clone();
+        if let (Some((path, journal)), true) = (&mut journal, options.resume) {
+            if let Some(mut address) = self.resume_point(path, journal, &image, erase)? {
+                // The chunk after the journal entry may be partly written and
+                // cannot be erased on its own
+                if self.profile().pages.is_empty() {
+                    return Err(ProgrammerError::NoPageLayout(self.profile().name));
+                }
+                // What is left was erased before the interruption
+                if erase == EraseMode::All {
+                    erase = EraseMode::None;
+                }
+                if erase == EraseMode::None {
+                    let (start, pages, outside) = self.erase_partly_written(&image, address)?;
+                    address = start;
+                    erased_pages = pages;
+                    kept = outside;
+                    blank = true;
+                }
+                rest = image.range(address, u32::MAX);
+                journal.written = address;
+                resumed_from = Some(address);
+            }
+        }
+
+        if erase == EraseMode::All {
+            let flash = self.profile().pages().iter().map(|p| p.size as usize).sum();
+            let meter = Meter::start(Phase::Erase, flash, &mut self.progress);
+            self.connection.erase_all()?;
+            meter.finish(&mut self.progress);
+        }
+
+        if erase == EraseMode::None && options.blank_check && !blank {
+            for (address, data) in rest.segments() {
+                if let Some(address) = self.blank_check(address, data.len() as u32)? {
+                    return Err(ProgrammerError::NotBlank(address));
+                }
+            }
+        }
+
+        let to_write = if erase == EraseMode::ChangedPages {
+            let (to_write, pages) = self.erase_changed_pages(&rest)?;
+            erased_pages = pages;
+            to_write
+        } else {
+            let mut to_write = rest;
+            to_write.merge(kept)?;
+            to_write
+        };
+
+        if let Some((path, journal)) = &journal {
+            journal.save(path).map_err(ProgrammerError::Journal)?;
+        }
+
+        let meter = Meter::start(Phase::Write, to_write.len(), &mut self.progress);
+        let mut done = 0;
+        for (address, data) in to_write.chunks(MAX_BLOCK_SIZE) {
+            done += data.len();
+            let end = address + data.len() as u32;
+            self.retrying().write_memory(address, data)?;
+            match &mut journal {
+                Some((path, journal))
+                    if end.saturating_sub(journal.written) >= JOURNAL_INTERVAL =>
+                {
+                    journal.written = end;
+                    journal.save(path).map_err(ProgrammerError::Journal)?;
+                }
+                _ => {}
+            }
+            meter.report(done, &mut self.progress);
+        }
+
+        if options.verify {
+            self.verify(&image)?;
+        }
+        if let Some((path, _)) = journal {
+            Journal::remove(path).map_err(ProgrammerError::Journal)?;
+        }
+
+        Ok(WriteReport {
+            image,
+            erased_pages,
+            written: to_write.len(),
+            resumed_from,
+        })
+    }
+
+    /// The 96-bit unique device id, `None` when the profile does not say
+    /// where it is
+    pub fn unique_id(&mut self) -> Result<Option<[u8; 12]>, DfuLoaderError> {
+        let Some(address) = self.profile().uid_address else {
+            return Ok(None);
+        };
+        let uid = self.retrying().read_memory(address, 12)?;
+        Ok(uid.try_into().ok())
+    }
+
+    /// The flash size of this part in bytes from the flash size register,
+    /// `None` when the profile does not say where it is
+    pub fn flash_size(&mut self) -> Result<Option<u32>, DfuLoaderError> {
+        let Some(address) = self.profile().flash_size_address else {
+            return Ok(None);
+        };
+        let data = self.retrying().read_memory(address, 2)?;
+        let kib = u16::from_le_bytes([data[0], data[1]]);
+        Ok(Some(u32::from(kib) * 1024))
+    }
+
+    /// The raw option bytes, `None` when the profile does not say where they are
+    pub fn option_bytes(&mut self) -> Result<Option<Vec<u8>>, DfuLoaderError> {
+        let Some(area) = self.profile().option_bytes else {
+            return Ok(None);
+        };
+        let data = self
+            .retrying()
+            .read_memory(area.address, area.length as usize)?;
+        Ok(Some(data))
+    }
+
+    /// The package code from the package data register, `None` when the
+    /// family has none
+    pub fn package(&mut self) -> Result<Option<u8>, DfuLoaderError> {
+        let Some(address) = self.profile().package_address else {
+            return Ok(None);
+        };
+        let data = self.retrying().read_memory(address, 2)?;
+        Ok(Some(data[0] & 0x1F))
+    }
+
+    /// Compare the device content with the image
+    pub fn verify(&mut self, image: &MemoryImage) -> Result<(), ProgrammerError> {
+        let meter = Meter::start(Phase::Verify, image.len(), &mut self.progress);
+        let mut connection = Retrying::new(self.connection.as_mut(), self.retry, &mut self.retries);
+        let mut done = 0;
+        let first_difference = verify_chunks(&mut connection, image, |length| {
+            done += length;
+            meter.report(done, &mut self.progress);
+        })?;
+        match first_difference {
+            Some(address) => Err(ProgrammerError::VerifyFailed(address)),
+            None => Ok(()),
+        }
+    }
+
+    pub fn go(&mut self, address: u32) -> Result<(), DfuLoaderError> {
+        self.connection.go(address)
+    }
+
+    /// Where a write can continue after an interruption: the address in
+    /// `saved` if the journal is about the same image and device and the
+    /// device content up to there matches the image
+    fn resume_point(
+        &mut self,
+        path: &Path,
+        current: &Journal,
+        image: &MemoryImage,
+        erase: EraseMode,
+    ) -> Result<Option<u32>, ProgrammerError> {
+        let saved = match Journal::load(path).map_err(ProgrammerError::Journal)? {
+            Some(saved) if saved.is_for(current) => saved,
+            _ => return Ok(None),
+        };
+
+        // Pages are compared as a whole, so a page that was erased but only
+        // partly written has to be erased and written again
+        let mut address = saved.written;
+        if erase == EraseMode::ChangedPages {
+            if let Some(page) = self
+                .profile()
+                .pages()
+                .into_iter()
+                .find(|p| p.address <= address && address < p.address + p.size)
+            {
+                address = page.address;
+            }
+        }
+
+        let written = image.range(0, address);
+        if !self.has_content(&written)? {
+            return Ok(None);
+        }
+        Ok(Some(address))
+    }
+
+    /// Whether the device has the content of the image, word aligned segments
+    /// are compared by checksum when the device supports it
+    fn has_content(&mut self, image: &MemoryImage) -> Result<bool, DfuLoaderError> {
+        let use_checksum = self
+            .device
+            .as_ref()
+            .is_some_and(|d| d.commands.supports(Functions::GetChecksum));
+        for (address, data) in image.segments() {
+            let length = data.len() as u32;
+            if use_checksum && address.is_multiple_of(4) && length.is_multiple_of(4) {
+                if self.connection.get_checksum(address, length)? != crc32(data) {
+                    return Ok(false);
+                }
+            } else {
+                let segment = image.range(address, address + length);
+                if verify(&mut self.retrying(), &segment)?.is_some() {
+                    return Ok(false);
+                }
+            }
+        }
+        Ok(true)
+    }
+
+    /// Erase the pages of the rest of an interrupted write that are not
+    /// blank, power was cut in the middle of a chunk. A write cannot continue
+    /// in the middle of an erased page, so the returned address moves back to
+    /// the start of the first one. Also returns the erased pages and what
+    /// they held outside the image.
+    fn erase_partly_written(
+        &mut self,
+        image: &MemoryImage,
+        mut address: u32,
+    ) -> Result<(u32, Vec<Page>, MemoryImage), ProgrammerError> {
+        let rest = image.range(address, u32::MAX);
+        let mut written = vec![];
+        for page in self.profile().pages() {
+            let content = rest.range(page.address, page.address + page.size);
+            for (start, data) in content.segments() {
+                if self.blank_check(start, data.len() as u32)?.is_some() {
+                    written.push(page);
+                    break;
+                }
+            }
+        }
+        let Some(first) = written.first() else {
+            return Ok((address, written, MemoryImage::new()));
+        };
+        address = address.min(first.address);
+
+        let rest = image.range(address, u32::MAX);
+        let mut outside = MemoryImage::new();
+        for page in &written {
+            outside.merge(self.content_outside(page, &rest)?)?;
+        }
+        let indices: Vec<u16> = written.iter().map(|p| p.index).collect();
+        let size = written.iter().map(|p| p.size as usize).sum();
+        let meter = Meter::start(Phase::Erase, size, &mut self.progress);
+        self.connection.erase_pages(&indices)?;
+        meter.finish(&mut self.progress);
+        Ok((address, written, outside))
+    }
+
+    /// The device content of a page where the image has no data, without
+    /// the flash words that are erased anyway
+    fn content_outside(
+        &mut self,
+        page: &Page,
+        image: &MemoryImage,
+    ) -> Result<MemoryImage, ProgrammerError> {
+        let alignment = self.profile().write_alignment as usize;
+        let end = page.address + page.size;
+        let mut gaps = vec![];
+        let mut address = page.address;
+        for (start, data) in image.range(page.address, end).segments() {
+            gaps.push((address, start));
+            address = start + data.len() as u32;
+        }
+        gaps.push((address, end));
+
+        let mut content = MemoryImage::new();
+        let mut connection = self.retrying();
+        for (mut address, end) in gaps {
+            while address < end {
+                let length = MAX_BLOCK_SIZE.min((end - address) as usize);
+                let data = connection.read_memory(address, length)?;
+                for (offset, word) in (0..).step_by(alignment).zip(data.chunks(alignment)) {
+                    if word.iter().any(|&b| b != 0xFF) {
+                        content.add_segment(address + offset, word)?;
+                    }
+                }
+                address += length as u32;
+            }
+        }
+        Ok(content)
+    }
+
+    /// The connection with retries for read and write chunks
+    fn retrying(&mut self) -> Retrying<'_> {
+        Retrying::new(self.connection.as_mut(), self.retry, &mut self.retries)
+    }
+
+    /// Erase the pages that differ from the image, returns the part of the
+    /// image that has to be written together with what the changed pages
+    /// held outside the image. Data outside the flash is always written.
+    fn erase_changed_pages(
+        &mut self,
+        image: &MemoryImage,
+    ) -> Result<(MemoryImage, Vec<Page>), ProgrammerError> {
+        let profile = self.profile();
+        let pages = profile.pages();
+        let (Some(first), Some(last)) = (pages.first(), pages.last()) else {
+            return Err(ProgrammerError::NoPageLayout(profile.name));
+        };
+
+        let mut to_write = image.range(0, first.address);
+        to_write.merge(image.range(last.address + last.size, u32::MAX))?;
+
+        let changed = changed_pages(&mut self.retrying(), profile, image)?;
+        if changed.is_empty() {
+            return Ok((to_write, changed));
+        }
+
+        // The erase takes the whole page, keep what the image does not cover
+        for page in &changed {
+            to_write.merge(self.content_outside(page, image)?)?;
+        }
+
+        let indices: Vec<u16> = changed.iter().map(|p| p.index).collect();
+        let size = changed.iter().map(|p| p.size as usize).sum();
+        let meter = Meter::start(Phase::Erase, size, &mut self.progress);
+        self.connection.erase_pages(&indices)?;
+        meter.finish(&mut self.progress);
+        for page in &changed {
+            to_write.merge(image.range(page.address, page.address + page.size))?;
+        }
+        Ok((to_write, changed))
+    }
+}
+
+impl Error for ProgrammerError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            ProgrammerError::Bootloader(err) => Some(err),
+            ProgrammerError::Image(err) => Some(err),
+            ProgrammerError::Journal(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl Display for ProgrammerError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ProgrammerError::Bootloader(err) => write!(f, "{}", err),
+            ProgrammerError::Image(err) => write!(f, "{}", err),
+            ProgrammerError::NotBlank(address) => {
+                write!(f, "Flash is not blank at {:#010X}", address)
+            }
+            ProgrammerError::VerifyFailed(address) => {
+                write!(f, "Verify failed at {:#010X}", address)
+            }
+            ProgrammerError::NoPageLayout(name) => {
+                write!(f, "No page layout known for {}", name)
+            }
+            ProgrammerError::Journal(err) => write!(f, "Journal: {}", err),
+        }
+    }
+}
+
+impl From<DfuLoaderError> for ProgrammerError {
+    fn from(err: DfuLoaderError) -> Self {
+        ProgrammerError::Bootloader(err)
+    }
+}
+
+impl From<ImageError> for ProgrammerError {
+    fn from(err: ImageError) -> Self {
+        ProgrammerError::Image(err)
+    }
+}
+
+/// Compare the image with the device content, returns the first differing address
+pub fn verify(
+    connection: &mut dyn DfuLoader,
+    image: &MemoryImage,
+) -> Result<Option<u32>, DfuLoaderError> {
+    verify_chunks(connection, image, |_| {})
+}
+
+/// [verify], calling `compared` with the length of every chunk that matched
+fn verify_chunks(
+    connection: &mut dyn DfuLoader,
+    image: &MemoryImage,
+    mut compared: impl FnMut(usize),
+) -> Result<Option<u32>, DfuLoaderError> {
+    for (address, data) in image.chunks(MAX_BLOCK_SIZE) {
+        let content = connection.read_memory(address, data.len())?;
+        if let Some(offset) = data.iter().zip(&content).position(|(a, b)| a != b) {
+            return Ok(Some(address + offset as u32));
+        }
+        compared(data.len());
+    }
+    Ok(None)
+}
+
+/// Find the pages where the device content differs from the image.
+///
+/// Only the bytes covered by the image are compared, [Programmer::write]
+/// keeps the rest of a changed page when it erases it.
+pub fn changed_pages(
+    connection: &mut dyn DfuLoader,
+    profile: &DeviceProfile,
+    image: &MemoryImage,
+) -> Result<Vec<Page>, DfuLoaderError> {
+    let mut changed = vec![];
+    for page in profile.pages() {
+        let content = image.range(page.address, page.address + page.size);
+        if !content.is_empty() && verify(connection, &content)?.is_some() {
+            changed.push(page);
+        }
+    }
+    Ok(changed)
+}
+
+/// Check that a memory area is erased, returns the first address that is not 0xFF.
+///
+/// With `use_checksum` the CRC of the area is compared with the CRC of erased
+/// flash first, only when they differ the area is read to find the address.
+pub fn blank_check(
+    connection: &mut dyn DfuLoader,
+    address: u32,
+    length: u32,
+    use_checksum: bool,
+) -> Result<Option<u32>, DfuLoaderError> {
+    if use_checksum && address.is_multiple_of(4) && length.is_multiple_of(4) && length > 0 {
+        let erased = crc32(&vec![0xFF; length as usize]);
+        if connection.get_checksum(address, length)? == erased {
+            return Ok(None);
+        }
+    }
+
+    let mut blank = MemoryImage::new();
+    blank
+        .add_segment(address, &vec![0xFF; length as usize])
+        .map_err(|_| DfuLoaderError::InvalidRequest("area exceeds the address space"))?;
+    verify(connection, &blank)
+}
+
+/// The CRC-32 calculated by the STM32 CRC peripheral, which the Get Checksum
+/// command uses: polynomial 0x04C11DB7, initial value 0xFFFFFFFF, fed with
+/// little endian 32-bit words without reflection or final xor.
+pub fn crc32(data: &[u8]) -> u32 {
+    let mut crc = 0xFFFF_FFFF_u32;
+    for word in data.chunks(4) {
+        let mut bytes = [0u8; 4];
+        bytes[..word.len()].copy_from_slice(word);
+        crc ^= u32::from_le_bytes(bytes);
+        for _ in 0..32 {
+            crc = if crc & 0x8000_0000 != 0 {
+                (crc << 1) ^ 0x04C1_1DB7
+            } else {
+                crc << 1
+            };
+        }
+    }
+    crc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::emulator::{EmulatedDevice, DEFAULT_COMMANDS};
+    use crate::serial::SerialConnection;
+    use std::cell::RefCell;
+    use std::env::temp_dir;
+    use std::rc::Rc;
+
+    const FLASH: u32 = 0x0800_0000;
+    const UID: [u8; 12] = *b"0123456789AB";
+
+    fn journal_path(name: &str) -> PathBuf {
+        temp_dir().join(format!(
+            "stm32loader-{}-{}.journal",
+            std::process::id(),
+            name
+        ))
+    }
+
+    fn programmer(device: &EmulatedDevice) -> Programmer {
+        let connection = SerialConnection::new(Box::new(device.usart()));
+        let mut programmer = Programmer::new(Box::new(connection));
+        programmer.connect().unwrap();
+        programmer.identify().unwrap();
+        programmer
+    }
+
+    #[test]
+    fn identifies_device_profile() {
+        let device = EmulatedDevice::new(0x433).unwrap();
+        let programmer = programmer(&device);
+
+        let info = programmer.device().unwrap();
+        assert_eq!(info.chip_id, 0x433);
+        assert_eq!(programmer.profile().pid, 0x433);
+        assert!(info.bootloader.is_some());
+    }
+
+    #[test]
+    fn detects_device_and_reads_its_registers() {
+        let device = EmulatedDevice::new(0x435)
+            .unwrap()
+            .with_memory(0x1FFF_7590, &UID);
+        let connection = SerialConnection::new(Box::new(device.usart()));
+        let mut programmer = Programmer::new(Box::new(connection));
+        programmer.connect().unwrap();
+
+        let info = programmer.detect().unwrap();
+        assert_eq!(info.chip_id, 0x435);
+        assert!(info.bootloader.is_none());
+
+        let option_bytes = programmer.option_bytes().unwrap().unwrap();
+        assert_eq!(option_bytes.len(), 40);
+        assert_eq!(&option_bytes[..4], &[0xEC, 0xAA, 0x13, 0x55]);
+        assert_eq!(programmer.package().unwrap(), Some(2));
+        assert_eq!(programmer.flash_size().unwrap(), Some(256 * 1024));
+        assert_eq!(programmer.unique_id().unwrap(), Some(UID));
+    }
+
+    #[test]
+    fn writes_aligned_and_verifies() {
+        let device = EmulatedDevice::new(0x435).unwrap();
+        let mut programmer = programmer(&device);
+        let image = MemoryImage::from_binary(FLASH + 2, &[1, 2, 3]).unwrap();
+        let options = WriteOptions {
+            verify: true,
+            ..WriteOptions::default()
+        };
+
+        let report = programmer.write(&image, &options).unwrap();
+
+        assert_eq!(report.written, 8);
+        assert_eq!(
+            device.read_flash(FLASH, 8),
+            vec![0xFF, 0xFF, 1, 2, 3, 0xFF, 0xFF, 0xFF]
+        );
+    }
+
+    #[test]
+    fn reports_the_progress_of_every_phase() {
+        let device = EmulatedDevice::new(0x433).unwrap();
+        let mut programmer = programmer(&device);
+        let reports = Rc::new(RefCell::new(vec![]));
+        let recorded = reports.clone();
+        programmer.set_progress(move |p| recorded.borrow_mut().push((p.phase, p.done, p.total)));
+        let image = MemoryImage::from_binary(FLASH, &[0x55; 300]).unwrap();
+        let options = WriteOptions {
+            erase: EraseMode::All,
+            verify: true,
+            ..WriteOptions::default()
+        };
+
+        programmer.write(&image, &options).unwrap();
+
+        let flash = device.flash_size();
+        assert_eq!(
+            *reports.borrow(),
+            vec![
+                (Phase::Erase, 0, flash),
+                (Phase::Erase, flash, flash),
+                (Phase::Write, 0, 300),
+                (Phase::Write, 256, 300),
+                (Phase::Write, 300, 300),
+                (Phase::Verify, 0, 300),
+                (Phase::Verify, 256, 300),
+                (Phase::Verify, 300, 300),
+            ]
+        );
+    }
+
+    #[test]
+    fn refuses_to_write_over_programmed_flash() {
+        let device = EmulatedDevice::new(0x433)
+            .unwrap()
+            .with_flash(FLASH + 0x20, &[0x00]);
+        let mut programmer = programmer(&device);
+        let image = MemoryImage::from_binary(FLASH, &[0x55; 64]).unwrap();
+
+        let result = programmer.write(&image, &WriteOptions::default());
+
+        assert!(matches!(result, Err(ProgrammerError::NotBlank(a)) if a == FLASH + 0x20));
+    }
+
+    #[test]
+    fn erases_only_changed_pages() {
+        let device = EmulatedDevice::new(0x433)
+            .unwrap()
+            .with_flash(FLASH, &[0x55; 0x8000]);
+        let mut programmer = programmer(&device);
+        let mut content = vec![0x55; 0x8000];
+        content[0x4000] = 0xAA;
+        let image = MemoryImage::from_binary(FLASH, &content).unwrap();
+        let options = WriteOptions {
+            erase: EraseMode::ChangedPages,
+            verify: true,
+            ..WriteOptions::default()
+        };
+
+        let report = programmer.write(&image, &options).unwrap();
+
+        assert_eq!(report.erased_pages.len(), 1);
+        assert_eq!(report.erased_pages[0].address, FLASH + 0x4000);
+        assert_eq!(report.written, 0x4000);
+    }
+
+    #[test]
+    fn keeps_the_rest_of_a_changed_page() {
+        let device = EmulatedDevice::new(0x435)
+            .unwrap()
+            .with_flash(FLASH, &[0x55; 16])
+            .with_flash(FLASH + 0x7F0, &[0x12, 0x34, 0x56, 0x78]);
+        let mut programmer = programmer(&device);
+        let image = MemoryImage::from_binary(FLASH, &[0xAA; 8]).unwrap();
+        let options = WriteOptions {
+            erase: EraseMode::ChangedPages,
+            verify: true,
+            ..WriteOptions::default()
+        };
+
+        let report = programmer.write(&image, &options).unwrap();
+
+        assert_eq!(report.erased_pages.len(), 1);
+        assert_eq!(device.read_flash(FLASH, 8), vec![0xAA; 8]);
+        assert_eq!(device.read_flash(FLASH + 8, 8), vec![0x55; 8]);
+        assert_eq!(
+            device.read_flash(FLASH + 0x7F0, 8),
+            vec![0x12, 0x34, 0x56, 0x78, 0xFF, 0xFF, 0xFF, 0xFF]
+        );
+        assert_eq!(report.written, 24);
+    }
+
+    #[test]
+    fn resumes_from_the_journal() {
+        let content: Vec<u8> = (0..0x400).map(|i| i as u8).collect();
+        let device = EmulatedDevice::new(0x433)
+            .unwrap()
+            .with_memory(0x1FFF_7A10, &UID)
+            .with_flash(FLASH, &content[..0x200]);
+        let mut programmer = programmer(&device);
+        let image = MemoryImage::from_binary(FLASH, &content).unwrap();
+        let path = journal_path("resume");
+        let mut journal = Journal::new(&image, Some(UID));
+        journal.written = FLASH + 0x200;
+        journal.save(&path).unwrap();
+        let options = WriteOptions {
+            journal: Some(path.clone()),
+            resume: true,
+            verify: true,
+            ..WriteOptions::default()
+        };
+
+        let report = programmer.write(&image, &options).unwrap();
+
+        assert_eq!(report.resumed_from, Some(FLASH + 0x200));
+        assert_eq!(report.written, 0x200);
+        assert_eq!(report.image.len(), 0x400);
+        assert_eq!(device.read_flash(FLASH, 0x400), content);
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn rewrites_the_page_of_a_partly_written_chunk() {
+        let content: Vec<u8> = (0..0x1000).map(|i| i as u8).collect();
+        // The chunk after the journal entry got half way
+        let device = EmulatedDevice::new(0x435)
+            .unwrap()
+            .with_memory(0x1FFF_7590, &UID)
+            .with_flash(FLASH, &content[..0x980]);
+        let mut programmer = programmer(&device);
+        let image = MemoryImage::from_binary(FLASH, &content).unwrap();
+        let path = journal_path("partly-written");
+        let mut journal = Journal::new(&image, Some(UID));
+        journal.written = FLASH + 0x900;
+        journal.save(&path).unwrap();
+        let options = WriteOptions {
+            journal: Some(path.clone()),
+            resume: true,
+            verify: true,
+            ..WriteOptions::default()
+        };
+
+        let report = programmer.write(&image, &options).unwrap();
+
+        assert_eq!(report.resumed_from, Some(FLASH + 0x800));
+        assert_eq!(report.erased_pages.len(), 1);
+        assert_eq!(report.erased_pages[0].address, FLASH + 0x800);
+        assert_eq!(report.written, 0x800);
+        assert_eq!(device.read_flash(FLASH, 0x1000), content);
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn refuses_to_resume_without_a_page_layout() {
+        let content: Vec<u8> = (0..0x400).map(|i| i as u8).collect();
+        // The chunk after the journal entry got half way
+        let device = EmulatedDevice::new(0x433)
+            .unwrap()
+            .with_product_id(0x123)
+            .with_flash(FLASH, &content[..0x280]);
+        let mut programmer = programmer(&device);
+        let image = MemoryImage::from_binary(FLASH, &content).unwrap();
+        let path = journal_path("no-layout");
+        let mut journal = Journal::new(&image, None);
+        journal.written = FLASH + 0x200;
+        journal.save(&path).unwrap();
+
+        for erase in [EraseMode::None, EraseMode::All] {
+            let options = WriteOptions {
+                erase,
+                journal: Some(path.clone()),
+                resume: true,
+                ..WriteOptions::default()
+            };
+            assert!(matches!(
+                programmer.write(&image, &options),
+                Err(ProgrammerError::NoPageLayout(_))
+            ));
+        }
+
+        assert_eq!(device.read_flash(FLASH, 0x280), content[..0x280]);
+        Journal::remove(&path).unwrap();
+    }
+
+    #[test]
+    fn does_not_trust_a_journal_the_device_does_not_match() {
+        let device = EmulatedDevice::new(0x433)
+            .unwrap()
+            .with_memory(0x1FFF_7A10, &UID);
+        let mut programmer = programmer(&device);
+        let image = MemoryImage::from_binary(FLASH, &[0x55; 0x400]).unwrap();
+        let path = journal_path("mismatch");
+        let mut journal = Journal::new(&image, Some(UID));
+        journal.written = FLASH + 0x200;
+        journal.save(&path).unwrap();
+        let options = WriteOptions {
+            journal: Some(path.clone()),
+            resume: true,
+            ..WriteOptions::default()
+        };
+
+        let report = programmer.write(&image, &options).unwrap();
+
+        assert_eq!(report.resumed_from, None);
+        assert_eq!(report.written, 0x400);
+        assert_eq!(device.read_flash(FLASH, 0x400), vec![0x55; 0x400]);
+    }
+
+    fn connection(device: &EmulatedDevice) -> SerialConnection {
+        let mut connection = SerialConnection::new(Box::new(device.usart()));
+        connection.initialize().unwrap();
+        connection
+    }
+
+    #[test]
+    fn verify_reports_first_difference() {
+        let device = EmulatedDevice::new(0x433)
+            .unwrap()
+            .with_flash(FLASH + 5, &[0x00]);
+        let mut connection = connection(&device);
+        let image = MemoryImage::from_binary(FLASH, &[0xFF; 16]).unwrap();
+
+        assert_eq!(verify(&mut connection, &image).unwrap(), Some(FLASH + 5));
+    }
+
+    #[test]
+    fn changed_pages_only_lists_differences() {
+        let content: Vec<u8> = (0..0x100).map(|i| (i * 7 + 3) as u8).collect();
+        let device = EmulatedDevice::new(0x433)
+            .unwrap()
+            .with_flash(FLASH, &content)
+            .with_flash(FLASH + 0x4000, &content);
+        let mut connection = connection(&device);
+        let profile = device::lookup(0x433).unwrap();
+
+        let mut changed = MemoryImage::from_binary(FLASH, &content).unwrap();
+        changed
+            .merge(MemoryImage::from_binary(FLASH + 0x4000, &[0x00; 0x100]).unwrap())
+            .unwrap();
+
+        let pages = changed_pages(&mut connection, profile, &changed).unwrap();
+        assert_eq!(pages.iter().map(|p| p.index).collect::<Vec<_>>(), vec![1]);
+    }
+
+    #[test]
+    fn blank_check_with_and_without_checksum() {
+        for commands in [
+            DEFAULT_COMMANDS.to_vec(),
+            [DEFAULT_COMMANDS, &[0xA1]].concat(),
+        ] {
+            let device = EmulatedDevice::new(0x433)
+                .unwrap()
+                .with_commands(&commands)
+                .with_flash(FLASH + 0x123, &[0x7F]);
+            let use_checksum = commands.contains(&0xA1);
+            let mut connection = connection(&device);
+
+            let blank = blank_check(&mut connection, FLASH, 0x100, use_checksum);
+            assert_eq!(blank.unwrap(), None);
+            let blank = blank_check(&mut connection, FLASH, 0x1000, use_checksum);
+            assert_eq!(blank.unwrap(), Some(FLASH + 0x123));
+        }
+    }
+
+    #[test]
+    fn crc32_matches_the_crc_unit() {
+        assert_eq!(crc32(&[]), 0xFFFF_FFFF);
+        // The value the CRC unit gives for the word 0x12345678 after a reset
+        assert_eq!(crc32(&0x1234_5678_u32.to_le_bytes()), 0xDF8A_8A2B);
+    }
+}
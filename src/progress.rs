@@ -0,0 +1,131 @@
+//! Progress of the long running steps of [Programmer::write], see
+//! [Programmer::set_progress].
+//!
+//! [Programmer::write]: crate::Programmer::write
+//! [Programmer::set_progress]: crate::Programmer::set_progress
+
+use std::fmt::{Display, Formatter};
+use std::time::{Duration, Instant};
+
+/// Called with the progress of the current phase
+pub type ProgressCallback = Box<dyn FnMut(&Progress)>;
+
+/// The steps of a write that report progress, in the order they run
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Phase {
+    Erase,
+    Write,
+    Verify,
+}
+
+/// How far a phase is, reported at its start, after every chunk and at its end
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Progress {
+    pub phase: Phase,
+    /// Bytes done in this phase
+    pub done: usize,
+    /// Bytes to do in this phase
+    pub total: usize,
+    /// Time since the phase started
+    pub elapsed: Duration,
+}
+
+impl Progress {
+    /// Bytes per second so far
+    pub fn throughput(&self) -> f64 {
+        match self.elapsed.as_secs_f64() {
+            seconds if seconds > 0.0 => self.done as f64 / seconds,
+            _ => 0.0,
+        }
+    }
+
+    /// Estimated time until the phase is done, `None` until there is a rate
+    /// to estimate from
+    pub fn remaining(&self) -> Option<Duration> {
+        let throughput = self.throughput();
+        if throughput == 0.0 {
+            return None;
+        }
+        let left = self.total.saturating_sub(self.done) as f64;
+        Some(Duration::from_secs_f64(left / throughput))
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.done >= self.total
+    }
+}
+
+impl Display for Phase {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            Phase::Erase => "Erase",
+            Phase::Write => "Write",
+            Phase::Verify => "Verify",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// Times a phase and reports it to the callback, if there is one
+pub(crate) struct Meter {
+    phase: Phase,
+    total: usize,
+    start: Instant,
+}
+
+impl Meter {
+    /// Start a phase, reports that nothing is done yet
+    pub(crate) fn start(
+        phase: Phase,
+        total: usize,
+        callback: &mut Option<ProgressCallback>,
+    ) -> Self {
+        let meter = Meter {
+            phase,
+            total,
+            start: Instant::now(),
+        };
+        meter.report(0, callback);
+        meter
+    }
+
+    pub(crate) fn report(&self, done: usize, callback: &mut Option<ProgressCallback>) {
+        if let Some(callback) = callback {
+            callback(&Progress {
+                phase: self.phase,
+                done,
+                total: self.total,
+                elapsed: self.start.elapsed(),
+            });
+        }
+    }
+
+    pub(crate) fn finish(&self, callback: &mut Option<ProgressCallback>) {
+        self.report(self.total, callback);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn estimates_the_remaining_time() {
+        let progress = Progress {
+            phase: Phase::Write,
+            done: 1000,
+            total: 3000,
+            elapsed: Duration::from_secs(2),
+        };
+
+        assert_eq!(progress.throughput(), 500.0);
+        assert_eq!(progress.remaining(), Some(Duration::from_secs(4)));
+
+        let started = Progress {
+            done: 0,
+            elapsed: Duration::ZERO,
+            ..progress
+        };
+        assert_eq!(started.remaining(), None);
+    }
+}
@@ -0,0 +1,237 @@
+//! Repeat read and write chunks that got lost or corrupted on the way.
+//!
+//! A flipped bit on a long cable makes the device NACK a frame or makes a
+//! response unreadable. That does not have to end a long programming run:
+//! [Retrying] re-synchronises with the bootloader and sends the chunk again.
+
+use crate::dfuloader::{
+    BootLoaderInfo, BootloaderChipId, BootloaderOptions, DfuLoader, DfuLoaderError,
+};
+use std::thread::sleep;
+use std::time::Duration;
+
+/// How often to try a read or write chunk before giving up
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RetryPolicy {
+    /// Tries per chunk including the first, 1 disables retrying
+    pub attempts: u32,
+    /// Wait before the first retry, doubled for every further retry
+    pub backoff: Duration,
+}
+
+impl RetryPolicy {
+    /// Try every chunk once
+    pub const NONE: RetryPolicy = RetryPolicy {
+        attempts: 1,
+        backoff: Duration::ZERO,
+    };
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            attempts: 3,
+            backoff: Duration::from_millis(50),
+        }
+    }
+}
+
+/// How many retries were needed
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct RetrySummary {
+    /// Number of repeated commands
+    pub retries: usize,
+    /// Number of chunks that needed at least one retry
+    pub chunks: usize,
+}
+
+/// A connection that retries [DfuLoader::read_memory] and
+/// [DfuLoader::write_memory] according to a [RetryPolicy], every other
+/// command is passed through.
+///
+/// Only failures caused by a bad frame are retried, see
+/// [DfuLoaderError::is_transient]. Note that a write that timed out after the
+/// device took it fails again on flash that cannot be written twice.
+pub struct Retrying<'a> {
+    connection: &'a mut dyn DfuLoader,
+    policy: RetryPolicy,
+    summary: &'a mut RetrySummary,
+}
+
+impl<'a> Retrying<'a> {
+    pub fn new(
+        connection: &'a mut dyn DfuLoader,
+        policy: RetryPolicy,
+        summary: &'a mut RetrySummary,
+    ) -> Self {
+        Retrying {
+            connection,
+            policy,
+            summary,
+        }
+    }
+
+    fn retry<T>(
+        &mut self,
+        mut operation: impl FnMut(&mut dyn DfuLoader) -> Result<T, DfuLoaderError>,
+    ) -> Result<T, DfuLoaderError> {
+        let mut backoff = self.policy.backoff;
+        let mut attempt = 1;
+        loop {
+            match operation(self.connection) {
+                Err(err) if err.is_transient() && attempt < self.policy.attempts => {
+                    if attempt == 1 {
+                        self.summary.chunks += 1;
+                    }
+                    self.summary.retries += 1;
+                    attempt += 1;
+
+                    sleep(backoff);
+                    backoff *= 2;
+                    // The device may still wait for the rest of the frame,
+                    // if it does not come back the next attempt fails too
+                    let _ = self.connection.initialize();
+                }
+                result => return result,
+            }
+        }
+    }
+}
+
+impl DfuLoader for Retrying<'_> {
+    fn initialize(&mut self) -> Result<(), DfuLoaderError> {
+        self.connection.initialize()
+    }
+
+    fn get_version(&mut self) -> Result<BootloaderOptions, DfuLoaderError> {
+        self.connection.get_version()
+    }
+
+    fn supported_functions(&mut self) -> Result<BootLoaderInfo, DfuLoaderError> {
+        self.connection.supported_functions()
+    }
+
+    fn get_id(&mut self) -> Result<BootloaderChipId, DfuLoaderError> {
+        self.connection.get_id()
+    }
+
+    fn write_unprotect(&mut self) -> Result<(), DfuLoaderError> {
+        self.connection.write_unprotect()
+    }
+
+    fn read_memory(&mut self, address: u32, size: usize) -> Result<Vec<u8>, DfuLoaderError> {
+        self.retry(|connection| connection.read_memory(address, size))
+    }
+
+    fn write_memory(&mut self, address: u32, data: Vec<u8>) -> Result<(), DfuLoaderError> {
+        self.retry(|connection| connection.write_memory(address, data.clone()))
+    }
+
+    fn erase_all(&mut self) -> Result<(), DfuLoaderError> {
+        self.connection.erase_all()
+    }
+
+    fn erase_pages(&mut self, pages: &[u16]) -> Result<(), DfuLoaderError> {
+        self.connection.erase_pages(pages)
+    }
+
+    fn go(&mut self, address: u32) -> Result<(), DfuLoaderError> {
+        self.connection.go(address)
+    }
+
+    fn get_checksum(&mut self, address: u32, length: u32) -> Result<u32, DfuLoaderError> {
+        self.connection.get_checksum(address, length)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::emulator::{EmulatedDevice, UsartPort};
+    use crate::serial::SerialConnection;
+    use crate::transport::ByteStream;
+    use std::io::{self, Read, Write};
+
+    const FLASH: u32 = 0x0800_0000;
+
+    /// A line that flips a bit in the checksum of the next `errors` address
+    /// frames, so the device NACKs them
+    struct Noisy {
+        port: UsartPort,
+        errors: usize,
+    }
+
+    impl Read for Noisy {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.port.read(buf)
+        }
+    }
+
+    impl Write for Noisy {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            if buf.len() != 5 || self.errors == 0 {
+                return self.port.write(buf);
+            }
+            self.errors -= 1;
+            let mut frame = buf.to_vec();
+            frame[4] ^= 0x01;
+            self.port.write(&frame)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            self.port.flush()
+        }
+    }
+
+    impl ByteStream for Noisy {}
+
+    fn flaky(device: &EmulatedDevice, errors: usize) -> SerialConnection {
+        let mut connection = SerialConnection::new(Box::new(Noisy {
+            port: device.usart(),
+            errors,
+        }));
+        connection.initialize().unwrap();
+        connection
+    }
+
+    #[test]
+    fn repeats_rejected_chunks() {
+        let device = EmulatedDevice::new(0x433).unwrap();
+        let mut connection = flaky(&device, 2);
+        let mut summary = RetrySummary::default();
+        let policy = RetryPolicy {
+            attempts: 3,
+            backoff: Duration::from_millis(1),
+        };
+        let mut retrying = Retrying::new(&mut connection, policy, &mut summary);
+
+        retrying.write_memory(FLASH, vec![1, 2, 3, 4]).unwrap();
+        assert_eq!(retrying.read_memory(FLASH, 4).unwrap(), vec![1, 2, 3, 4]);
+
+        assert_eq!(
+            summary,
+            RetrySummary {
+                retries: 2,
+                chunks: 1
+            }
+        );
+    }
+
+    #[test]
+    fn gives_up_after_the_last_attempt() {
+        let device = EmulatedDevice::new(0x433).unwrap();
+        let mut connection = flaky(&device, 2);
+        let mut summary = RetrySummary::default();
+        let policy = RetryPolicy {
+            attempts: 2,
+            backoff: Duration::ZERO,
+        };
+        let mut retrying = Retrying::new(&mut connection, policy, &mut summary);
+
+        let err = retrying.write_memory(FLASH, vec![1, 2, 3, 4]).unwrap_err();
+
+        assert!(err.is_transient());
+        assert_eq!(summary.retries, 1);
+        assert_eq!(device.read_flash(FLASH, 4), vec![0xFF; 4]);
+    }
+}
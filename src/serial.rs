@@ -1,257 +1,505 @@
-use crate::dfuloader::{BootLoaderInfo, BootloaderChipId, BootloaderOptions, DfuLoader};
-use crate::dfuloader::DfuLoaderError;
 use crate::dfuloader::DfuLoaderError::*;
-use crate::dfuloader::Functions;
-use crate::Duration;
-use serialport::Parity::Even;
+use crate::dfuloader::{BootLoaderInfo, BootloaderChipId, BootloaderOptions, DfuLoader};
+use crate::dfuloader::{DfuLoaderError, Exchange, Failure, Stage, ACK, ERASE_TIMEOUT, NAK};
+use crate::trace;
+use crate::transport::{ByteStream, TcpTransport};
+use crate::usart;
 use serialport::{DataBits, SerialPort, StopBits};
 use std::error::Error;
 use std::io::{Read, Write};
-use std::{io, thread};
 use std::thread::sleep;
-use ihex::checksum;
+use std::time::{Duration, Instant};
+use std::{io, thread};
+
+pub use serialport::Parity;
+
+/// Line settings of a serial port, always 8 data bits and 1 stop bit
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SerialSettings {
+    /// The bootloader detects the baud rate from the first byte, up to
+    /// 115200 on most families and higher on some
+    pub baud_rate: u32,
+    /// The bootloader uses even parity, some USB bridges need it overridden
+    pub parity: Parity,
+    /// How long to wait for a response byte
+    pub timeout: Duration,
+}
+
+impl Default for SerialSettings {
+    fn default() -> Self {
+        SerialSettings {
+            baud_rate: DEFAULT_BAUD_RATE,
+            parity: Parity::Even,
+            timeout: Duration::from_millis(100),
+        }
+    }
+}
+
+/// The baud rate of [SerialSettings::default], which most families support
+pub const DEFAULT_BAUD_RATE: u32 = 115200;
+
+/// Baud rates to fall back to when the bootloader does not answer, fastest first
+pub const FALLBACK_BAUD_RATES: &[u32] = &[921600, 460800, 115200, 57600, 9600];
+
+/// A modem control line of the serial port wired to the reset pin of the
+/// device, with BOOT0 held high so it starts the bootloader after a reset
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ResetLine {
+    Dtr,
+    Rts,
+}
+
+/// Reset the device by asserting the reset line for a moment, then wait for
+/// the bootloader to start
+pub fn reset_device(port: &mut dyn SerialPort, line: ResetLine) -> serialport::Result<()> {
+    let mut set = |level| match line {
+        ResetLine::Dtr => port.write_data_terminal_ready(level),
+        ResetLine::Rts => port.write_request_to_send(level),
+    };
+    set(true)?;
+    sleep(Duration::from_millis(10));
+    set(false)?;
+    sleep(Duration::from_millis(100));
+    Ok(())
+}
+
+pub fn new_serial_connection(device_name: &str) -> Result<Box<dyn DfuLoader>, Box<dyn Error>> {
+    new_serial_connection_with(device_name, &SerialSettings::default())
+}
+
+pub fn new_serial_connection_with(
+    device_name: &str,
+    settings: &SerialSettings,
+) -> Result<Box<dyn DfuLoader>, Box<dyn Error>> {
+    let port = open_serial_port(device_name, settings)?;
 
-const ACK: u8 = 0x79;
-const NAK: u8 = 0x1F;
+    Ok(Box::new(SerialConnection::new(Box::new(port))))
+}
 
-pub fn new_serial_connection(device_name: &String) -> Result<Box<dyn DfuLoader>, Box<dyn Error>> {
-    let port = serialport::new(device_name, 9600)
-        .parity(Even)
+/// Open a serial port with the line settings of the bootloader, for a
+/// [SerialConnection] over a wrapped port
+pub fn open_serial_port(
+    device_name: &str,
+    settings: &SerialSettings,
+) -> serialport::Result<Box<dyn SerialPort>> {
+    serialport::new(device_name, settings.baud_rate)
+        .parity(settings.parity)
         .data_bits(DataBits::Eight)
         .stop_bits(StopBits::One)
-        .timeout(Duration::from_millis(100))
-        .open()?;
-
-    return Ok(Box::new(SerialConnection { port: port }));
+        .timeout(settings.timeout)
+        .open()
 }
 
-pub struct SerialConnection {
-    port: Box<dyn SerialPort>,
+/// Open the port at the baud rate in `settings` and synchronise, stepping
+/// down through the slower rates in [FALLBACK_BAUD_RATES] while the
+/// bootloader does not answer. With a `reset` line the device is reset
+/// before every attempt. Returns the connection and the baud rate that
+/// worked.
+///
+/// The bootloader locks on to the baud rate of the first sync byte it sees.
+/// Without a reset the slower rates help when a faster one never reached it,
+/// e.g. the port cannot do the rate or the line drops the bytes. When it did
+/// lock on to a rate that then failed, every other rate fails as well until
+/// the device is reset.
+pub fn negotiate_serial_connection(
+    device_name: &str,
+    settings: &SerialSettings,
+    reset: Option<ResetLine>,
+) -> Result<(Box<dyn DfuLoader>, u32), Box<dyn Error>> {
+    let baud_rates = baud_rates(settings.baud_rate);
+    let (connection, baud_rate) = negotiate(&baud_rates, |baud_rate| {
+        let mut port = open_serial_port(
+            device_name,
+            &SerialSettings {
+                baud_rate,
+                ..*settings
+            },
+        )?;
+        if let Some(line) = reset {
+            reset_device(port.as_mut(), line)?;
+        }
+        Ok(Box::new(port))
+    })?;
+    Ok((Box::new(connection), baud_rate))
 }
 
-impl DfuLoader for SerialConnection {
-    fn initialize(&mut self) -> Result<(), DfuLoaderError> {
-        for _ in 0..10 {
-            let data: [u8; 1] = [0x7F];
-            self.port.write_all(&data)?;
+/// The baud rates to try: `first`, then the fallback rates below it
+pub fn baud_rates(first: u32) -> Vec<u32> {
+    let mut rates = vec![first];
+    rates.extend(FALLBACK_BAUD_RATES.iter().filter(|&&rate| rate < first));
+    rates
+}
 
-            let mut response = [0u8; 1];
-            match self.port.read_exact(&mut response) {
-                Err(e) if e.kind() == io::ErrorKind::TimedOut => (),
-                Ok(_) => match response[0] {
-                    ACK => return Ok(()),
-                    NAK => return Ok(()),
-                    _ => {}
-                },
-                Err(e) => {return Err(DfuLoaderError::from(e)) }
+/// Try the baud rates in order until the bootloader answers the sync byte
+/// and a Get command, `open` opens the port at a baud rate and resets the
+/// device if it can, see [negotiate_serial_connection]
+pub fn negotiate(
+    baud_rates: &[u32],
+    mut open: impl FnMut(u32) -> Result<Box<dyn ByteStream>, Box<dyn Error>>,
+) -> Result<(SerialConnection, u32), Box<dyn Error>> {
+    let mut last_error: Box<dyn Error> = Box::new(SyncError());
+    for &baud_rate in baud_rates {
+        let port = match open(baud_rate) {
+            Ok(port) => port,
+            Err(err) => {
+                last_error = err;
+                continue;
             }
+        };
 
-            thread::sleep(Duration::from_millis(500));
+        let mut connection = SerialConnection::new(port);
+        match connection
+            .sync(3)
+            .and_then(|_| connection.supported_functions())
+        {
+            Ok(_) => return Ok((connection, baud_rate)),
+            Err(err) => {
+                last_error = format!(
+                    "Sync failed at {} baud ({}), reset the device and retry at a lower baud rate",
+                    baud_rate, err
+                )
+                .into()
+            }
         }
-        Err(Timeout())
     }
+    Err(last_error)
+}
 
-    /// Implements the Get (0x00) command for a serial connection
-    fn get_version(&mut self) -> Result<BootloaderOptions, DfuLoaderError> {
-        send_command(&mut self.port, 0x01)?;
+/// Run the USART protocol over TCP, e.g. to a ser2net port, `address` is host:port
+pub fn new_tcp_connection(address: &str) -> Result<Box<dyn DfuLoader>, Box<dyn Error>> {
+    let stream = TcpTransport::connect(address, Duration::from_millis(100))?;
 
-        let mut version = [0u8; 4];
-        if self.port.read_exact(&mut version).is_err() {
-            return Err(ProtocolError())
-        }
+    Ok(Box::new(SerialConnection::new(Box::new(stream))))
+}
 
-        if version[3] != ACK {
-            return Err(ProtocolError())
+/// The USART bootloader protocol (AN3155) over any byte stream
+pub struct SerialConnection {
+    port: Box<dyn ByteStream>,
+    exchange: Exchange,
+}
+
+impl SerialConnection {
+    pub fn new(port: Box<dyn ByteStream>) -> Self {
+        SerialConnection {
+            port,
+            exchange: Exchange::default(),
         }
+    }
+}
 
-        Ok(BootloaderOptions {
-            version: version[0],
-            options: (version[1] as u16) << 8 | version[2] as u16,
-        })
+impl DfuLoader for SerialConnection {
+    fn initialize(&mut self) -> Result<(), DfuLoaderError> {
+        self.sync(10)
     }
 
     /// Implements the Get Version (0x01) command for a serial connection
-    fn supported_functions(&mut self) -> Result<BootLoaderInfo, DfuLoaderError> {
-        send_command(&mut self.port, 0x00)?;
-
-        let mut length = [0u8; 1];
-        if self.port.read_exact(&mut length).is_err() {
-            return Err(ProtocolError())
-        }
+    fn get_version(&mut self) -> Result<BootloaderOptions, DfuLoaderError> {
+        self.send_command(0x01)?;
 
-        let mut response = [0u8; 255];
-        let mut n = 0;
-        loop {
-            let mut buffer = [0u8; 255];
-            match self.port.read(&mut buffer) {
-                Ok(v) => {
-                    response[n..n+v].copy_from_slice(&buffer[0..v]);
-                    n = n + v;
-                }
-                Err(_) => {
-                    return Err(ProtocolError())
-                }
-            }
-            if n == length[0] as usize + 2 {
-                break
-            }
-        }
+        let response = self.read_bytes(4)?;
+        usart::parse_version(&response).map_err(|rejection| self.reject(rejection))
+    }
 
-        if response[n-1] != 0x79 {
-            return Err(ProtocolError())
-        }
+    /// Implements the Get (0x00) command for a serial connection
+    fn supported_functions(&mut self) -> Result<BootLoaderInfo, DfuLoaderError> {
+        self.send_command(0x00)?;
 
-        let mut function: Vec<Functions> = vec![];
-        response[1..n-2].iter().for_each(|&x | function.push(Functions::from(x)));
-        let bootloader_info = BootLoaderInfo {
-            version: response[0],
-            supported_functions: function
-        };
-        Ok(bootloader_info)
+        let length = self.read_bytes(1)?;
+        let response = self.read_bytes(length[0] as usize + 2)?;
+        usart::parse_functions(&response).map_err(|rejection| self.reject(rejection))
     }
 
     /// Implement the Get ID command for a serial connection
     fn get_id(&mut self) -> Result<BootloaderChipId, DfuLoaderError> {
-        send_command(&mut self.port, 0x02)?;
+        self.send_command(0x02)?;
 
-        let mut length = [0u8; 1];
-        if self.port.read_exact(&mut length).is_err() {
-            return Err(ProtocolError())
-        }
+        let length = self.read_bytes(1)?;
+        usart::check_id_length(length[0]).map_err(|rejection| self.reject(rejection))?;
 
-        let response = read_bytes(&mut self.port, (length[0] + 2) as usize)?;
-        if response[2] != ACK {
-            return Err(ProtocolError())
-        }
-
-        if (response.len() != 3) {
-            // STM32 should always return two bytes + ack
-            return Err(ProtocolError())
-        }
-
-        Ok(BootloaderChipId {
-            chipid: (response[0]  as u16) << 8 | response[1] as u16
-        })
+        let response = self.read_bytes(3)?;
+        usart::parse_id(&response).map_err(|rejection| self.reject(rejection))
     }
 
     fn write_unprotect(&mut self) -> Result<(), DfuLoaderError> {
         Err(NotImplemented())
     }
 
-    fn read_memory(&mut self, address: u32, size: u8) -> Result<Vec<u8>, DfuLoaderError> {
-        send_command(&mut self.port, 0x11)?;
+    fn read_memory(&mut self, address: u32, size: usize) -> Result<Vec<u8>, DfuLoaderError> {
+        let length = usart::read_length(size)?;
 
-        let mut data = [0u8; 5];
-        data[0..4].copy_from_slice(address.to_be_bytes().as_ref());
-        data[4] = calculate_checksum(&data[0..4]);
-        self.port.write_all(data.as_ref())?;
-        read_ack(&mut self.port)?;
+        self.send_command(0x11)?;
+        self.send_address(address)?;
 
-        let mut length = [size -1 , 0xFF ^ (size-1)];
-        self.port.write_all(length.as_ref())?;
-        read_ack(&mut self.port)?;
+        self.write(Stage::Length, &length)?;
+        self.read_ack(Stage::Length)?;
 
-        read_bytes(&mut self.port, size as usize)
+        self.read_bytes(size)
     }
 
     fn write_memory(&mut self, address: u32, data: Vec<u8>) -> Result<(), DfuLoaderError> {
-        if data.len() > 256 || data.len() == 0 {
-            return Err(ProtocolError())
-        }
+        let out = usart::write_data(data)?;
 
-        send_command(&mut self.port, 0x31)?;
+        self.send_command(0x31)?;
+        self.send_address(address)?;
 
-        let mut data = [0u8; 5];
-        data[0..4].copy_from_slice(address.to_be_bytes().as_ref());
-        data[4] = calculate_checksum(&data[0..4]);
-        self.port.write_all(data.as_ref())?;
-        read_ack(&mut self.port)?;
+        self.write(Stage::Data, &out)?;
 
-        let mut out = vec![(data.len() - 1) as u8];
-        out.extend(data);
-        let checksum = calculate_checksum(out.as_ref());
-        out.push(checksum);
+        self.read_ack(Stage::Data)
+    }
 
-        self.port.write_all(out.as_ref())?;
+    fn erase_all(&mut self) -> Result<(), DfuLoaderError> {
+        self.send_command(0x44)?;
+        self.write(Stage::Length, &usart::ERASE_ALL)?;
 
-        read_ack(&mut self.port)
+        self.wait_for_ack()
     }
 
-    fn erase_all(&mut self) -> Result<(), DfuLoaderError> {
-        send_command(&mut self.port, 0x44)?;
-
-        // Perform global erase
-        let erase_request = [0xFFu8, 0xFF, 0x00];
-        self.port.write_all(&erase_request)?;
-
-        // This can take a while, so loop on timeouts
-        for _ in 0..20 {
-            match read_ack(&mut self.port) {
-                Ok(_) => return Ok(()),
-                Err(DfuLoaderError::IOError(e)) if e.kind() == io::ErrorKind::TimedOut => (),
-                Err(e) => return Err(e),
-            }
-            sleep(Duration::from_millis(1000));
-        }
-        Err(DfuLoaderError::Timeout())
+    fn erase_pages(&mut self, pages: &[u16]) -> Result<(), DfuLoaderError> {
+        let erase_request = usart::erase_pages(pages)?;
+
+        self.send_command(0x44)?;
+        self.write(Stage::Length, &erase_request)?;
+
+        self.wait_for_ack()
     }
 
     fn go(&mut self, address: u32) -> Result<(), DfuLoaderError> {
-        send_command(&mut self.port, 0x21)?;
+        self.send_command(0x21)?;
+        self.send_address(address)
+    }
+
+    /// Implements the Get Checksum (0xA1) command for a serial connection
+    fn get_checksum(&mut self, address: u32, length: u32) -> Result<u32, DfuLoaderError> {
+        let size = usart::checksum_length(address, length)?;
+
+        self.send_command(0xA1)?;
+        self.send_address(address)?;
+
+        self.write(Stage::Length, &size)?;
+        self.read_ack(Stage::Length)?;
 
-        let mut data = [0u8; 5];
-        data[0..4].copy_from_slice(address.to_be_bytes().as_ref());
-        data[4] = calculate_checksum(&data[0..4]);
-        self.port.write_all(data.as_ref())?;
-        read_ack(&mut self.port)
+        let response = self.read_bytes(5)?;
+        usart::parse_checksum(&response).map_err(|rejection| self.reject(rejection))
     }
 }
 
-fn calculate_checksum(data: &[u8]) -> u8 {
-    let mut checksum = data[0];
-    data[1..].iter().for_each(|v| checksum = checksum ^ v);
+impl SerialConnection {
+    /// Send the sync byte until the bootloader answers, up to `attempts` times
+    fn sync(&mut self, attempts: usize) -> Result<(), DfuLoaderError> {
+        for _ in 0..attempts {
+            let data: [u8; 1] = [usart::SYNC];
+            trace::sync("->", &data);
+            self.port.write_all(&data)?;
 
-    checksum
-}
+            let mut response = [0u8; 1];
+            match self.port.read_exact(&mut response) {
+                Err(e) if e.kind() == io::ErrorKind::TimedOut => (),
+                Ok(_) => {
+                    trace::sync("<-", &response);
+                    if response[0] == ACK || response[0] == NAK {
+                        return Ok(());
+                    }
+                }
+                Err(e) => return Err(DfuLoaderError::from(e)),
+            }
 
-fn send_command(mut port: &mut Box<dyn SerialPort>, command: u8) -> Result<(), DfuLoaderError> {
-    let get_command: [u8; 2] = [command, command ^ 0xFF];
-    if port.write_all(&get_command).is_err() {
-        return Err(ProtocolError())
+            thread::sleep(Duration::from_millis(500));
+        }
+        Err(SyncError())
     }
 
-    read_ack(&mut port)
-}
+    fn send_command(&mut self, command: u8) -> Result<(), DfuLoaderError> {
+        self.exchange = Exchange::start(command);
+        self.write(Stage::Command, &usart::command(command))?;
 
-fn read_ack(port: &mut Box<dyn SerialPort>) -> Result<(), DfuLoaderError> {
-    let mut ack = [0u8; 1];
-    port.read_exact(&mut ack)?;
+        self.read_ack(Stage::Command)
+    }
 
-    if ack[0] != 0x79 {
-        return Err(CommandFailed(ack[0]))
+    fn send_address(&mut self, address: u32) -> Result<(), DfuLoaderError> {
+        self.exchange.address = Some(address);
+        self.write(Stage::Address, &usart::address(address))?;
+        self.read_ack(Stage::Address)
     }
 
-    Ok(())
-}
+    fn write(&mut self, stage: Stage, data: &[u8]) -> Result<(), DfuLoaderError> {
+        self.exchange.sent(stage, data);
+        self.port
+            .write_all(data)
+            .map_err(|e| self.exchange.fail(stage, e))
+    }
 
-fn read_bytes(port: &mut Box<dyn SerialPort>, size: usize) -> Result<Vec<u8>, DfuLoaderError> {
-    let mut data = vec![];
-    let mut n = 0;
-    loop {
-        let mut buffer = [0u8; 255];
-        match port.read(&mut buffer) {
-            Ok(v) => {
-                data.append(&mut buffer[0..v].to_vec());
-                n = n + v;
-            }
-            Err(e) => {
-                return Err(DfuLoaderError::from(e))
-            }
+    fn read_ack(&mut self, stage: Stage) -> Result<(), DfuLoaderError> {
+        let mut ack = [0u8; 1];
+        self.port
+            .read_exact(&mut ack)
+            .map_err(|e| self.exchange.fail(stage, e))?;
+        self.exchange.received(stage, &ack);
+
+        if ack[0] != ACK {
+            return Err(self.exchange.fail(stage, Failure::from_response(ack[0])));
         }
-        if n == size {
-            break
+
+        Ok(())
+    }
+
+    /// Erasing can take a while, so loop on timeouts for up to [ERASE_TIMEOUT]
+    fn wait_for_ack(&mut self) -> Result<(), DfuLoaderError> {
+        let deadline = Instant::now() + ERASE_TIMEOUT;
+        loop {
+            match self.read_ack(Stage::Ack) {
+                Err(e) if e.is_timeout() && Instant::now() < deadline => (),
+                result => return result,
+            }
         }
     }
-    Ok(data)
+
+    fn read_bytes(&mut self, size: usize) -> Result<Vec<u8>, DfuLoaderError> {
+        let mut data = vec![0u8; size];
+        self.port
+            .read_exact(&mut data)
+            .map_err(|e| self.exchange.fail(Stage::Data, e))?;
+        self.exchange.received(Stage::Data, &data);
+        Ok(data)
+    }
+
+    fn reject(&self, (stage, failure): usart::Rejection) -> DfuLoaderError {
+        self.exchange.fail(stage, failure)
+    }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dfuloader::Functions;
+    use crate::emulator::{EmulatedDevice, DEFAULT_COMMANDS};
+    use crate::image::MemoryImage;
+    use crate::programmer;
+
+    const F401RE: u16 = 0x433;
+    const FLASH: u32 = 0x0800_0000;
+
+    fn serial(device: &EmulatedDevice) -> SerialConnection {
+        let mut connection = SerialConnection::new(Box::new(device.usart()));
+        connection.initialize().unwrap();
+        connection
+    }
+
+    #[test]
+    fn identifies_device() {
+        let device = EmulatedDevice::new(F401RE)
+            .unwrap()
+            .with_bootloader_version(0x31);
+        let mut connection = serial(&device);
+
+        assert_eq!(connection.get_version().unwrap().version, 0x31);
+        assert_eq!(connection.get_id().unwrap().chipid, F401RE);
+
+        let info = connection.supported_functions().unwrap();
+        assert_eq!(info.version, 0x31);
+        assert_eq!(info.supported_functions.len(), DEFAULT_COMMANDS.len());
+        assert!(info.supports(Functions::ReadoutUnprotect));
+        assert!(!info.supports(Functions::GetChecksum));
+    }
+
+    #[test]
+    fn initialize_on_synced_device() {
+        let device = EmulatedDevice::new(F401RE).unwrap();
+        let mut connection = serial(&device);
+        connection.initialize().unwrap();
+        assert_eq!(connection.get_id().unwrap().chipid, F401RE);
+    }
+
+    /// A port at a baud rate the device does not detect
+    struct Silent;
+
+    impl Read for Silent {
+        fn read(&mut self, _: &mut [u8]) -> io::Result<usize> {
+            Err(io::ErrorKind::TimedOut.into())
+        }
+    }
+
+    impl Write for Silent {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl ByteStream for Silent {}
+
+    #[test]
+    fn falls_back_to_a_slower_baud_rate() {
+        let device = EmulatedDevice::new(F401RE).unwrap();
+        let rates = baud_rates(921600);
+        assert_eq!(rates, vec![921600, 460800, 115200, 57600, 9600]);
+
+        let (mut connection, baud_rate) = negotiate(&rates, |rate| match rate {
+            921600 => Err("unsupported baud rate".into()),
+            460800 => Ok(Box::new(Silent)),
+            _ => Ok(Box::new(device.usart())),
+        })
+        .unwrap();
+
+        assert_eq!(baud_rate, 115200);
+        assert_eq!(connection.get_id().unwrap().chipid, F401RE);
+    }
+
+    #[test]
+    fn reports_the_baud_rate_sync_failed_at() {
+        let message = match negotiate(&[460800], |_| Ok(Box::new(Silent))) {
+            Err(err) => err.to_string(),
+            Ok(_) => panic!("synced with a silent device"),
+        };
+
+        assert!(message.starts_with("Sync failed at 460800 baud"));
+        assert!(message.ends_with("reset the device and retry at a lower baud rate"));
+    }
+
+    #[test]
+    fn writes_and_verifies_image() {
+        let device = EmulatedDevice::new(F401RE).unwrap();
+        let mut connection = serial(&device);
+        let data: Vec<u8> = (0..1000).map(|i| (i * 7 + 3) as u8).collect();
+        let image = MemoryImage::from_binary(FLASH + 0x10, &data).unwrap();
+
+        for (address, data) in image.chunks(programmer::MAX_BLOCK_SIZE) {
+            connection.write_memory(address, data).unwrap();
+        }
+
+        assert_eq!(programmer::verify(&mut connection, &image).unwrap(), None);
+        assert_eq!(device.read_flash(FLASH, 0x10), vec![0xFF; 0x10]);
+        assert_eq!(device.read_flash(FLASH + 0x10, 1000), data);
+    }
+
+    #[test]
+    fn erases_pages_and_everything() {
+        let device = EmulatedDevice::new(F401RE)
+            .unwrap()
+            .with_flash(FLASH, &[0x00; 4])
+            .with_flash(FLASH + 0x4000, &[0x00; 4])
+            .with_flash(FLASH + 0x8000, &[0x00; 4]);
+        let mut connection = serial(&device);
+
+        connection.erase_pages(&[0, 2]).unwrap();
+        assert_eq!(device.read_flash(FLASH, 4), vec![0xFF; 4]);
+        assert_eq!(device.read_flash(FLASH + 0x4000, 4), vec![0x00; 4]);
+        assert_eq!(device.read_flash(FLASH + 0x8000, 4), vec![0xFF; 4]);
+
+        connection.erase_all().unwrap();
+        assert_eq!(device.read_flash(FLASH + 0x4000, 4), vec![0xFF; 4]);
+    }
+
+    #[test]
+    fn reads_the_checksum_of_an_area() {
+        // The CRC unit gives 0xDF8A8A2B for the word 0x12345678
+        let device = EmulatedDevice::new(F401RE)
+            .unwrap()
+            .with_commands(&[DEFAULT_COMMANDS, &[0xA1]].concat())
+            .with_flash(FLASH, &0x1234_5678_u32.to_le_bytes());
+
+        assert_eq!(serial(&device).get_checksum(FLASH, 4).unwrap(), 0xDF8A_8A2B);
+    }
+}
@@ -1,86 +1,212 @@
-use crate::dfuloader::{BootLoaderInfo, BootloaderChipId, BootloaderOptions, DfuLoader};
-use crate::dfuloader::DfuLoaderError;
 use crate::dfuloader::DfuLoaderError::*;
 use crate::dfuloader::Functions;
-use core::time;
-use spidev::{SpiModeFlags, Spidev, SpidevOptions, SpidevTransfer};
+use crate::dfuloader::{BootLoaderInfo, BootloaderChipId, BootloaderOptions, DfuLoader};
+use crate::dfuloader::{DfuLoaderError, Exchange, Failure, Stage, ACK, ERASE_TIMEOUT, NAK};
+use crate::trace;
+use crate::transport::FullDuplex;
+use spidev::{SpiModeFlags, Spidev, SpidevOptions};
 use std::error::Error;
-use std::io::{Read, Write};
-use std::thread;
-use crate::dfuloader::Functions::Get;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+/// How long the device may take to acknowledge a frame
+const ACK_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Polls for an acknowledge that go back to back, most frames are answered
+/// within them and only an erase or a slow write takes longer
+const FAST_POLLS: u32 = 100;
+
+/// The pause between later polls, so a long erase does not keep the bus busy
+const POLL_INTERVAL: Duration = Duration::from_millis(1);
+
+/// Settings of a spidev bus, the bootloader always uses 8 bit words
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SpiSettings {
+    /// The bootloader supports up to 8 MHz
+    pub clock_hz: u32,
+    /// The bootloader uses mode 0
+    pub mode: SpiMode,
+    pub chip_select: ChipSelect,
+}
+
+/// Clock polarity and phase
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SpiMode {
+    Mode0,
+    Mode1,
+    Mode2,
+    Mode3,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ChipSelect {
+    ActiveLow,
+    ActiveHigh,
+    /// Chip select is not driven by the controller, e.g. tied low or
+    /// switched by other means
+    None,
+}
+
+impl Default for SpiSettings {
+    fn default() -> Self {
+        SpiSettings {
+            clock_hz: 20_000,
+            mode: SpiMode::Mode0,
+            chip_select: ChipSelect::ActiveLow,
+        }
+    }
+}
+
+impl SpiSettings {
+    fn mode_flags(&self) -> SpiModeFlags {
+        let mode = match self.mode {
+            SpiMode::Mode0 => SpiModeFlags::SPI_MODE_0,
+            SpiMode::Mode1 => SpiModeFlags::SPI_MODE_1,
+            SpiMode::Mode2 => SpiModeFlags::SPI_MODE_2,
+            SpiMode::Mode3 => SpiModeFlags::SPI_MODE_3,
+        };
+        match self.chip_select {
+            ChipSelect::ActiveLow => mode,
+            ChipSelect::ActiveHigh => mode | SpiModeFlags::SPI_CS_HIGH,
+            ChipSelect::None => mode | SpiModeFlags::SPI_NO_CS,
+        }
+    }
+}
+
+pub fn new_spi_connection(device_name: &str) -> Result<Box<dyn DfuLoader>, Box<dyn Error>> {
+    new_spi_connection_with(device_name, &SpiSettings::default())
+}
+
+/// Open a spidev device, `device_name` is a path or a name in `/dev` like
+/// `spidev0.1`
+pub fn new_spi_connection_with(
+    device_name: &str,
+    settings: &SpiSettings,
+) -> Result<Box<dyn DfuLoader>, Box<dyn Error>> {
+    let spi = open_spidev(device_name, settings)?;
+
+    Ok(Box::new(SpiConnection::new(Box::new(spi))))
+}
 
-pub fn new_spi_connection(device_name: &String) -> Result<Box<dyn DfuLoader>, Box<dyn Error>> {
-    let mut spi = Spidev::open(format!("/dev/{}", device_name))?;
+/// Open and configure a spidev device, for an [SpiConnection] over a
+/// wrapped bus
+pub fn open_spidev(device_name: &str, settings: &SpiSettings) -> io::Result<Spidev> {
+    let mut spi = Spidev::open(device_path(device_name))?;
     let options = SpidevOptions::new()
         .bits_per_word(8)
-        .max_speed_hz(20_000)
-        .mode(SpiModeFlags::SPI_MODE_0)
+        .max_speed_hz(settings.clock_hz)
+        .mode(settings.mode_flags())
         .build();
     spi.configure(&options)?;
+    Ok(spi)
+}
 
-    Ok(Box::new(SpiConnection {
-        spi
-    }))
+fn device_path(device_name: &str) -> PathBuf {
+    match device_name.contains('/') {
+        true => PathBuf::from(device_name),
+        false => Path::new("/dev").join(device_name),
+    }
 }
 
+/// The SPI bootloader protocol (AN4286) over any full duplex bus
 pub struct SpiConnection {
-    spi: Spidev,
+    spi: Box<dyn FullDuplex>,
+    exchange: Exchange,
+    erase_timeout: Duration,
 }
 
 impl SpiConnection {
-    fn send_command(&mut self, command: u8) -> Result<(), DfuLoaderError> {
-        let tx_buf = [0x5A, command, command ^ 0xFF, 0x00, 0x00, 0x79];
-        let mut rx_buf = [0; 6];
-        println!("Out: {:02X?}", tx_buf);
-        {
-            let mut transfer = SpidevTransfer::read_write(&tx_buf, &mut rx_buf);
-            self.spi.transfer(&mut transfer)?;
+    pub fn new(spi: Box<dyn FullDuplex>) -> Self {
+        SpiConnection {
+            spi,
+            exchange: Exchange::default(),
+            erase_timeout: ERASE_TIMEOUT,
         }
-        println!("In : {:02X?}", rx_buf);
+    }
 
-        if rx_buf[4] != 0x79 {
-            return Err(ProtocolError());
-        }
-        Ok(())
+    /// Wait this long for an erase to finish instead of 40 s
+    pub fn with_erase_timeout(mut self, timeout: Duration) -> Self {
+        self.erase_timeout = timeout;
+        self
+    }
+
+    fn send_command(&mut self, command: u8) -> Result<(), DfuLoaderError> {
+        self.exchange = Exchange::start(command);
+        self.write_block(Stage::Command, vec![0x5A, command, command ^ 0xFF])?;
+        self.ack_frame(Stage::Command)
     }
 
     fn read_variable_block(&mut self) -> Result<Vec<u8>, DfuLoaderError> {
         let mut rx_buf = [0_u8; 2];
-        self.spi.read_exact(&mut rx_buf)?;
-        println!("{:02X?}", rx_buf);
+        self.spi
+            .read(&mut rx_buf)
+            .map_err(|e| self.exchange.fail(Stage::Data, e))?;
 
-        let datalen: usize = (rx_buf[1] + 1).into();
+        let datalen: usize = rx_buf[1] as usize + 1;
         let mut data_buf = vec![0u8; datalen];
-        self.spi.read_exact(&mut data_buf)?;
-        println!("{:02X?}", data_buf);
+        self.spi
+            .read(&mut data_buf)
+            .map_err(|e| self.exchange.fail(Stage::Data, e))?;
+        self.exchange.received(Stage::Data, &data_buf);
 
-        return Ok(data_buf);
+        Ok(data_buf)
     }
 
     fn read_block(&mut self, size: usize) -> Result<Vec<u8>, DfuLoaderError> {
         let mut data_buf = vec![0u8; size + 1]; // First byte is dummy
-        self.spi.read_exact(&mut data_buf)?;
-        println!("{:02X?}", data_buf);
+        self.spi
+            .read(&mut data_buf)
+            .map_err(|e| self.exchange.fail(Stage::Data, e))?;
+        self.exchange.received(Stage::Data, &data_buf[1..]);
 
-        return Ok(data_buf);
+        Ok(data_buf)
     }
 
-    fn ack_frame(&mut self) -> Result<(), DfuLoaderError> {
-        let tx_buf = [0x00, 0x00, 0x79];
-        let mut rx_buf = [0; 3];
-        {
-            let mut transfer = SpidevTransfer::read_write(&tx_buf, &mut rx_buf);
-            self.spi.transfer(&mut transfer)?;
-        }
-        println!("{:02X?}", rx_buf);
+    fn ack_frame(&mut self, stage: Stage) -> Result<(), DfuLoaderError> {
+        self.wait_for_ack(stage, ACK_TIMEOUT)
+    }
 
-        if rx_buf[1] != 0x79 {
-            return Err(CommandFailed(rx_buf[1]));
+    /// The acknowledge procedure of AN4286: after a dummy byte poll until
+    /// the device clocks out ACK or NACK and confirm it with an ACK. While
+    /// busy the device clocks out dummy bytes or BUSY, after [FAST_POLLS]
+    /// polls it is polled every [POLL_INTERVAL]. Only the answer is logged,
+    /// not the polling.
+    fn wait_for_ack(&mut self, stage: Stage, timeout: Duration) -> Result<(), DfuLoaderError> {
+        let deadline = Instant::now() + timeout;
+        self.spi
+            .write(&[0x00])
+            .map_err(|e| self.exchange.fail(stage, e))?;
+        for poll in 0.. {
+            if poll >= FAST_POLLS {
+                sleep(POLL_INTERVAL);
+            }
+            let mut response = [0u8; 1];
+            self.spi
+                .read(&mut response)
+                .map_err(|e| self.exchange.fail(stage, e))?;
+
+            match response[0] {
+                answer @ (ACK | NAK) => {
+                    self.exchange.received(stage, &response);
+                    self.spi
+                        .write(&[ACK])
+                        .map_err(|e| self.exchange.fail(stage, e))?;
+                    if answer == NAK {
+                        return Err(self.exchange.fail(stage, Failure::Nack));
+                    }
+                    return Ok(());
+                }
+                _ if Instant::now() >= deadline => break,
+                _ => {}
+            }
         }
-        Ok(())
+        Err(self.exchange.fail(stage, Failure::Timeout))
     }
 
     fn send_address(&mut self, address: u32) -> Result<(), DfuLoaderError> {
+        self.exchange.address = Some(address);
         let mut tx_buf = [
             ((address >> 24) & 0xFF) as u8,
             ((address >> 16) & 0xFF) as u8,
@@ -90,153 +216,126 @@ impl SpiConnection {
         ];
         tx_buf[4] = tx_buf[0] ^ tx_buf[1] ^ tx_buf[2] ^ tx_buf[3];
 
-        self.spi.write(&tx_buf)?;
-        Ok(())
+        self.write_block(Stage::Address, tx_buf.to_vec())
     }
 
     fn send_size(&mut self, size: u16) -> Result<(), DfuLoaderError> {
-        if size > 256 {
-            return Err(ProtocolError());
-        }
-
         let tx_buf = [(size - 1) as u8, ((size - 1) as u8) ^ 0xFF];
 
-        self.spi.write(&tx_buf)?;
-        Ok(())
-    }
-
-    fn write_block(&mut self, data: Vec<u8>) -> Result<(), DfuLoaderError> {
-        println!("Out: {:02X?}", data);
-        self.spi.write(&data)?;
-        Ok(())
+        self.write_block(Stage::Length, tx_buf.to_vec())
     }
 
-    fn write_unprotect(&mut self) -> Result<(), DfuLoaderError> {
-        self.send_command(0x73)?;
-        self.ack_frame()?;
-        Ok(())
+    fn write_block(&mut self, stage: Stage, data: Vec<u8>) -> Result<(), DfuLoaderError> {
+        self.exchange.sent(stage, &data);
+        self.spi
+            .write(&data)
+            .map_err(|e| self.exchange.fail(stage, e))
     }
 }
 
 impl DfuLoader for SpiConnection {
     fn initialize(&mut self) -> Result<(), DfuLoaderError> {
-        let tx_buf = [0x5A, 0x00, 0x00, 0x79];
+        let tx_buf = [0x5A, 0x00, 0x00, ACK];
         let mut rx_buf = [0; 4];
-        {
-            let mut transfer = SpidevTransfer::read_write(&tx_buf, &mut rx_buf);
-            self.spi.transfer(&mut transfer)?;
-        }
-        println!("{:02X?}", rx_buf);
+        trace::sync("->", &tx_buf);
+        self.spi.transfer(&tx_buf, &mut rx_buf)?;
+        trace::sync("<-", &rx_buf);
 
         if rx_buf[2] == 0xA5 {
             return Err(AlreadySynced());
         }
-        if rx_buf[2] != 0x79 {
+        if rx_buf[2] != ACK {
             return Err(SyncError());
         }
         Ok(())
     }
 
+    /// The SPI bootloader sends only its version, there are no option bytes
     fn get_version(&mut self) -> Result<BootloaderOptions, DfuLoaderError> {
-        return Err(NotImplemented())
+        self.send_command(0x01)?;
+        let data = self.read_block(1)?;
+        self.ack_frame(Stage::Ack)?;
+
+        Ok(BootloaderOptions {
+            version: data[1],
+            options: 0,
+        })
     }
 
     fn get_id(&mut self) -> Result<BootloaderChipId, DfuLoaderError> {
-        Err(NotImplemented())
+        self.send_command(0x02)?;
+        let data = self.read_variable_block()?;
+        self.ack_frame(Stage::Ack)?;
+
+        match data[..] {
+            [high, low] => Ok(BootloaderChipId {
+                chipid: u16::from_be_bytes([high, low]),
+            }),
+            _ => Err(self.exchange.fail(Stage::Data, Failure::Malformed)),
+        }
     }
 
     fn supported_functions(&mut self) -> Result<BootLoaderInfo, DfuLoaderError> {
         self.send_command(0x00)?;
-        let _data = self.read_variable_block()?;
+        let data = self.read_variable_block()?;
 
-        self.ack_frame()?;
+        self.ack_frame(Stage::Ack)?;
 
-        Ok(BootLoaderInfo{
-            version: 0,
-            supported_functions: vec!(Get)
+        Ok(BootLoaderInfo {
+            version: data[0],
+            supported_functions: data[1..].iter().map(|&x| Functions::from(x)).collect(),
         })
     }
 
+    /// The device acknowledges once more after the option bytes are
+    /// reprogrammed, then it resets
     fn write_unprotect(&mut self) -> Result<(), DfuLoaderError> {
         self.send_command(0x73)?;
+        self.wait_for_ack(Stage::Ack, self.erase_timeout)
+    }
 
-        // Wait for the reset to complete
-        for _ in 0..10 {
-            match self.ack_frame() {
-                Err(err) => match err {
-                    CommandFailed(0xFF) => {
-                        thread::sleep(time::Duration::from_millis(100));
-                    }
-                    _ => {
-                        return Err(err);
-                    }
-                },
-                Ok(_) => {
-                    break;
-                }
-            }
+    fn read_memory(&mut self, address: u32, size: usize) -> Result<Vec<u8>, DfuLoaderError> {
+        if size > 256 || size == 0 {
+            return Err(InvalidRequest("read size must be 1 to 256 bytes"));
         }
 
-        // Do this twice for the additional reset on the F4?
-        for _ in 0..20 {
-            match self.ack_frame() {
-                Err(err) => match err {
-                    CommandFailed(0xFF) => {
-                        thread::sleep(time::Duration::from_millis(1000));
-                    }
-                    CommandFailed(0xA5) => {
-                        thread::sleep(time::Duration::from_millis(1000));
-                    }
-                    _ => {
-                        return Err(err);
-                    }
-                },
-                Ok(_) => {
-                    return Ok(());
-                }
-            }
-        }
-
-        Err(Timeout())
-    }
-
-    fn read_memory(&mut self, address: u32, size: u8) -> Result<Vec<u8>, DfuLoaderError> {
         self.send_command(0x11)?;
 
         self.send_address(address)?;
-        self.ack_frame()?;
+        self.ack_frame(Stage::Address)?;
 
         self.send_size(size as u16)?;
-        self.ack_frame()?;
+        self.ack_frame(Stage::Length)?;
 
-        let data = self.read_block(size as usize)?;
-        Ok(data)
+        let data = self.read_block(size)?;
+        Ok(data[1..].to_vec())
     }
 
     fn write_memory(&mut self, address: u32, data: Vec<u8>) -> Result<(), DfuLoaderError> {
         let len = data.len();
         if len > 256 || len == 0 {
-            return Err(ProtocolError());
+            return Err(InvalidRequest("write size must be 1 to 256 bytes"));
         }
 
         self.send_command(0x31)?;
 
         self.send_address(address)?;
-        self.ack_frame()?;
+        self.ack_frame(Stage::Address)?;
 
-        let mut block = vec![(len - 1) as u8];
+        // The length has to be even, pad with the value of erased flash
+        let mut block = vec![(len - 1 + len % 2) as u8];
         block.extend_from_slice(data.as_slice());
         if len % 2 == 1 {
             block.push(0xFF);
         }
 
         let mut checksum = block[0];
-        block[1..].iter().for_each(|v| checksum = checksum ^ v);
+        block[1..].iter().for_each(|v| checksum ^= v);
         block.push(checksum);
 
-        self.write_block(block)?;
+        self.write_block(Stage::Data, block)?;
 
-        self.ack_frame()?;
+        self.ack_frame(Stage::Data)?;
 
         Ok(())
     }
@@ -244,36 +343,218 @@ impl DfuLoader for SpiConnection {
     fn erase_all(&mut self) -> Result<(), DfuLoaderError> {
         self.send_command(0x44)?;
 
-        let special_erase = [0xFF as u8, 0xFF, 0xFF ^ 0xFF];
-        self.write_block(special_erase.to_vec())?;
+        let special_erase = [0xFF_u8, 0xFF, 0x00];
+        self.write_block(Stage::Length, special_erase.to_vec())?;
 
-        for _ in 0..20 {
-            match self.ack_frame() {
-                Err(err) => match err {
-                    CommandFailed(0xFF) => {
-                        thread::sleep(time::Duration::from_millis(1000));
-                    }
-                    CommandFailed(0xA5) => {
-                        thread::sleep(time::Duration::from_millis(1000));
-                    }
-                    _ => {
-                        return Err(err);
-                    }
-                },
-                Ok(_) => {
-                    break;
-                }
-            }
+        self.wait_for_ack(Stage::Ack, self.erase_timeout)
+    }
+
+    fn erase_pages(&mut self, pages: &[u16]) -> Result<(), DfuLoaderError> {
+        if pages.is_empty() || pages.len() > 0xFFF0 {
+            return Err(InvalidRequest("page count must be 1 to 65520"));
         }
 
-        return Ok(());
+        self.send_command(0x44)?;
+
+        let count = ((pages.len() - 1) as u16).to_be_bytes();
+        self.write_block(Stage::Length, vec![count[0], count[1], count[0] ^ count[1]])?;
+        self.ack_frame(Stage::Length)?;
+
+        let mut block: Vec<u8> = pages.iter().flat_map(|p| p.to_be_bytes()).collect();
+        let mut checksum = block[0];
+        block[1..].iter().for_each(|v| checksum ^= v);
+        block.push(checksum);
+        self.write_block(Stage::Data, block)?;
+
+        self.wait_for_ack(Stage::Ack, self.erase_timeout)
     }
 
     fn go(&mut self, address: u32) -> Result<(), DfuLoaderError> {
         self.send_command(0x21)?;
         self.send_address(address)?;
-        self.ack_frame()?;
+        self.ack_frame(Stage::Address)?;
 
         Ok(())
     }
-}
\ No newline at end of file
+
+    fn get_checksum(&mut self, address: u32, length: u32) -> Result<u32, DfuLoaderError> {
+        if !address.is_multiple_of(4) || !length.is_multiple_of(4) || length == 0 {
+            return Err(InvalidRequest("checksum area must be word aligned"));
+        }
+
+        self.send_command(0xA1)?;
+        self.send_address(address)?;
+        self.ack_frame(Stage::Address)?;
+
+        let size = length.to_be_bytes();
+        let checksum = size[0] ^ size[1] ^ size[2] ^ size[3];
+        self.write_block(
+            Stage::Length,
+            vec![size[0], size[1], size[2], size[3], checksum],
+        )?;
+        self.ack_frame(Stage::Length)?;
+
+        let data = self.read_block(5)?;
+        if data[1] ^ data[2] ^ data[3] ^ data[4] != data[5] {
+            return Err(self.exchange.fail(Stage::Data, Failure::Malformed));
+        }
+
+        Ok(u32::from_be_bytes([data[1], data[2], data[3], data[4]]))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::emulator::{EmulatedDevice, SpiBusPort, DEFAULT_COMMANDS};
+    use crate::image::MemoryImage;
+    use crate::programmer;
+
+    const F401RE: u16 = 0x433;
+    const FLASH: u32 = 0x0800_0000;
+
+    fn spi(device: &EmulatedDevice) -> SpiConnection {
+        let mut connection = SpiConnection::new(Box::new(device.spi()));
+        connection.initialize().unwrap();
+        connection
+    }
+
+    #[test]
+    fn lists_supported_functions() {
+        let device = EmulatedDevice::new(F401RE)
+            .unwrap()
+            .with_bootloader_version(0x12);
+        let mut connection = spi(&device);
+
+        let info = connection.supported_functions().unwrap();
+        assert_eq!(info.version, 0x12);
+        assert!(info.supports(Functions::ExtendedErase));
+    }
+
+    #[test]
+    fn identifies_the_device() {
+        let device = EmulatedDevice::new(F401RE)
+            .unwrap()
+            .with_bootloader_version(0x12);
+        let mut connection = spi(&device);
+
+        assert_eq!(connection.get_version().unwrap().version, 0x12);
+        assert_eq!(connection.get_id().unwrap().chipid, F401RE);
+    }
+
+    #[test]
+    fn initialize_on_synced_device() {
+        let device = EmulatedDevice::new(F401RE).unwrap();
+        let mut connection = spi(&device);
+        assert!(matches!(
+            connection.initialize(),
+            Err(DfuLoaderError::AlreadySynced())
+        ));
+        assert!(connection.supported_functions().is_ok());
+    }
+
+    #[test]
+    fn writes_and_reads_back_odd_lengths() {
+        let device = EmulatedDevice::new(F401RE).unwrap();
+        let mut connection = spi(&device);
+
+        connection.write_memory(FLASH, vec![1, 2, 3]).unwrap();
+
+        assert_eq!(
+            connection.read_memory(FLASH, 4).unwrap(),
+            vec![1, 2, 3, 0xFF]
+        );
+        assert_eq!(device.read_flash(FLASH, 4), vec![1, 2, 3, 0xFF]);
+    }
+
+    #[test]
+    fn writes_and_verifies_image() {
+        let device = EmulatedDevice::new(F401RE).unwrap();
+        let mut connection = spi(&device);
+        let data: Vec<u8> = (0..600).map(|i| (i * 7 + 3) as u8).collect();
+        let image = MemoryImage::from_binary(FLASH + 0x100, &data).unwrap();
+
+        for (address, data) in image.chunks(programmer::MAX_BLOCK_SIZE) {
+            connection.write_memory(address, data).unwrap();
+        }
+
+        assert_eq!(programmer::verify(&mut connection, &image).unwrap(), None);
+    }
+
+    #[test]
+    fn erases_pages_and_everything() {
+        let device = EmulatedDevice::new(F401RE)
+            .unwrap()
+            .with_flash(FLASH, &[0x00; 4])
+            .with_flash(FLASH + 0x4000, &[0x00; 4])
+            .with_flash(FLASH + 0x8000, &[0x00; 4]);
+        let mut connection = spi(&device);
+
+        connection.erase_pages(&[0, 2]).unwrap();
+        assert_eq!(device.read_flash(FLASH, 4), vec![0xFF; 4]);
+        assert_eq!(device.read_flash(FLASH + 0x4000, 4), vec![0x00; 4]);
+        assert_eq!(device.read_flash(FLASH + 0x8000, 4), vec![0xFF; 4]);
+
+        connection.erase_all().unwrap();
+        assert_eq!(device.read_flash(FLASH + 0x4000, 4), vec![0xFF; 4]);
+    }
+
+    /// Stops answering after a mass erase request, like a hanging device
+    struct StallOnMassErase {
+        port: SpiBusPort,
+        stalled: bool,
+    }
+
+    impl FullDuplex for StallOnMassErase {
+        fn transfer(&mut self, tx: &[u8], rx: &mut [u8]) -> io::Result<()> {
+            if self.stalled {
+                rx.fill(0xA5);
+                return Ok(());
+            }
+            self.stalled = tx == [0xFF, 0xFF, 0x00];
+            self.port.transfer(tx, rx)
+        }
+    }
+
+    #[test]
+    fn erase_times_out_without_acknowledge() {
+        let device = EmulatedDevice::new(F401RE).unwrap();
+        let bus = StallOnMassErase {
+            port: device.spi(),
+            stalled: false,
+        };
+        let mut connection =
+            SpiConnection::new(Box::new(bus)).with_erase_timeout(Duration::from_millis(50));
+        connection.initialize().unwrap();
+
+        let err = connection.erase_all().unwrap_err();
+
+        assert!(err.is_timeout());
+        assert_eq!(
+            err.to_string(),
+            "ExtendedErase (0x44) failed during ack: timeout"
+        );
+    }
+
+    #[test]
+    fn write_unprotect_waits_for_the_second_acknowledge() {
+        let device = EmulatedDevice::new(F401RE)
+            .unwrap()
+            .with_write_protection(true);
+        let mut connection = spi(&device);
+
+        connection.write_unprotect().unwrap();
+        assert!(!device.is_write_protected());
+    }
+
+    #[test]
+    fn reads_the_checksum_of_an_area() {
+        // The CRC unit gives 0xDF8A8A2B for the word 0x12345678
+        let device = EmulatedDevice::new(F401RE)
+            .unwrap()
+            .with_commands(&[DEFAULT_COMMANDS, &[0xA1]].concat())
+            .with_flash(FLASH, &0x1234_5678_u32.to_le_bytes());
+
+        assert_eq!(spi(&device).get_checksum(FLASH, 4).unwrap(), 0xDF8A_8A2B);
+    }
+}
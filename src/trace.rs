@@ -0,0 +1,65 @@
+//! Protocol frames in the log, under the [TARGET] target.
+//!
+//! At debug level every frame is logged with its direction, the command,
+//! the stage and what it means, at trace level followed by its bytes:
+//!
+//! ```text
+//! -> ReadMemory address 0x08000000
+//! -> [08, 00, 00, 00, 08]
+//! <- ReadMemory address ACK
+//! <- [79]
+//! ```
+//!
+//! Nothing is logged unless the application installs a logger.
+
+use crate::dfuloader::{Exchange, Functions, Stage, ACK, BUSY, NAK};
+use log::{debug, log_enabled, trace, Level};
+
+/// The log target of the frames
+pub const TARGET: &str = "stm32loader::frame";
+
+impl Exchange {
+    /// Log a frame sent to the device
+    pub(crate) fn sent(&self, stage: Stage, data: &[u8]) {
+        if !log_enabled!(target: TARGET, Level::Debug) {
+            return;
+        }
+        let meaning = match (stage, data) {
+            (Stage::Command, _) => format!("0x{:02X}", self.command),
+            (Stage::Address, _) => match self.address {
+                Some(address) => format!("{:#010X}", address),
+                None => "address".to_string(),
+            },
+            (_, [ACK]) => "ACK".to_string(),
+            _ => format!("{} bytes", data.len()),
+        };
+        self.log("->", stage, &meaning, data);
+    }
+
+    /// Log a frame received from the device
+    pub(crate) fn received(&self, stage: Stage, data: &[u8]) {
+        if !log_enabled!(target: TARGET, Level::Debug) {
+            return;
+        }
+        let meaning = match (stage, data) {
+            (Stage::Data, _) => format!("{} bytes", data.len()),
+            (_, [ACK]) => "ACK".to_string(),
+            (_, [NAK]) => "NACK".to_string(),
+            (_, [BUSY]) => "BUSY".to_string(),
+            (_, [byte]) => format!("unexpected 0x{:02X}", byte),
+            _ => format!("{} bytes", data.len()),
+        };
+        self.log("<-", stage, &meaning, data);
+    }
+
+    fn log(&self, direction: &str, stage: Stage, meaning: &str, data: &[u8]) {
+        let command = Functions::from(self.command);
+        debug!(target: TARGET, "{} {} {} {}", direction, command, stage, meaning);
+        trace!(target: TARGET, "{} {:02X?}", direction, data);
+    }
+}
+
+/// Log the sync bytes and the answer, which belong to no command
+pub(crate) fn sync(direction: &str, data: &[u8]) {
+    debug!(target: TARGET, "{} sync {:02X?}", direction, data);
+}
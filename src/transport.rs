@@ -0,0 +1,145 @@
+//! The byte level transports the bootloader protocols run over.
+//!
+//! The USART protocol needs a [ByteStream], the SPI protocol a [FullDuplex]
+//! bus. Implement one of these to run a protocol over something else, like
+//! a mock, a USB CDC bridge or a recorded trace.
+
+use serialport::SerialPort;
+use spidev::{Spidev, SpidevTransfer};
+use std::io;
+use std::io::{Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::time::Duration;
+
+/// A bidirectional byte stream.
+///
+/// Reads block until data arrives or the stream times out, a timeout must
+/// be reported as [io::ErrorKind::TimedOut], the protocol relies on it to
+/// detect a missing response.
+pub trait ByteStream: Read + Write + Send {}
+
+/// A bus that clocks out `tx` while clocking in the same number of bytes
+/// into `rx`
+pub trait FullDuplex: Send {
+    fn transfer(&mut self, tx: &[u8], rx: &mut [u8]) -> io::Result<()>;
+
+    /// Clock in bytes while sending zeros
+    fn read(&mut self, rx: &mut [u8]) -> io::Result<()> {
+        let tx = vec![0u8; rx.len()];
+        self.transfer(&tx, rx)
+    }
+
+    /// Clock out bytes, ignoring what comes back
+    fn write(&mut self, tx: &[u8]) -> io::Result<()> {
+        let mut rx = vec![0u8; tx.len()];
+        self.transfer(tx, &mut rx)
+    }
+}
+
+impl ByteStream for Box<dyn SerialPort> {}
+
+impl FullDuplex for Spidev {
+    fn transfer(&mut self, tx: &[u8], rx: &mut [u8]) -> io::Result<()> {
+        let mut transfer = SpidevTransfer::read_write(tx, rx);
+        Spidev::transfer(self, &mut transfer)
+    }
+}
+
+/// A TCP connection to a serial port server, like ser2net or a
+/// network attached UART bridge
+pub struct TcpTransport {
+    stream: TcpStream,
+}
+
+impl TcpTransport {
+    pub fn connect(address: impl ToSocketAddrs, timeout: Duration) -> io::Result<Self> {
+        let stream = TcpStream::connect(address)?;
+        stream.set_read_timeout(Some(timeout))?;
+        stream.set_nodelay(true)?;
+        Ok(TcpTransport { stream })
+    }
+}
+
+impl Read for TcpTransport {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self.stream.read(buf) {
+            // Depending on the platform a read timeout shows up as WouldBlock
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                Err(io::Error::new(io::ErrorKind::TimedOut, e))
+            }
+            Ok(0) => Err(io::ErrorKind::UnexpectedEof.into()),
+            result => result,
+        }
+    }
+}
+
+impl Write for TcpTransport {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.stream.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.stream.flush()
+    }
+}
+
+impl ByteStream for TcpTransport {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dfuloader::DfuLoader;
+    use crate::emulator::EmulatedDevice;
+    use crate::serial::SerialConnection;
+    use std::net::TcpListener;
+    use std::thread;
+
+    /// Serve the emulated device on a local TCP port, like ser2net would
+    fn serve(device: EmulatedDevice) -> u16 {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut usart = device.usart();
+            let mut buffer = [0u8; 512];
+            while let Ok(n @ 1..) = stream.read(&mut buffer) {
+                usart.write_all(&buffer[..n]).unwrap();
+                while let Ok(n) = usart.read(&mut buffer) {
+                    stream.write_all(&buffer[..n]).unwrap();
+                }
+            }
+        });
+        port
+    }
+
+    #[test]
+    fn usart_protocol_over_tcp() {
+        let device = EmulatedDevice::new(0x433).unwrap();
+        let port = serve(device.clone());
+        let stream =
+            TcpTransport::connect(("127.0.0.1", port), Duration::from_millis(100)).unwrap();
+        let mut connection = SerialConnection::new(Box::new(stream));
+
+        connection.initialize().unwrap();
+        assert_eq!(connection.get_id().unwrap().chipid, 0x433);
+        connection
+            .write_memory(0x0800_0000, vec![1, 2, 3, 4])
+            .unwrap();
+        assert_eq!(
+            connection.read_memory(0x0800_0000, 4).unwrap(),
+            vec![1, 2, 3, 4]
+        );
+        assert_eq!(device.read_flash(0x0800_0000, 4), vec![1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn tcp_read_timeout_is_reported_as_timed_out() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let mut stream = TcpTransport::connect(address, Duration::from_millis(10)).unwrap();
+
+        let mut buffer = [0u8; 1];
+        let err = stream.read(&mut buffer).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+    }
+}
@@ -0,0 +1,136 @@
+//! Frames of the USART protocol (AN3155), shared by the blocking
+//! [SerialConnection](crate::serial::SerialConnection) and its async
+//! counterpart. Only the byte layout lives here, the connections do the I/O.
+
+use crate::dfuloader::DfuLoaderError::InvalidRequest;
+use crate::dfuloader::{
+    BootLoaderInfo, BootloaderChipId, BootloaderOptions, DfuLoaderError, Failure, Functions, Stage,
+    ACK,
+};
+
+/// The byte the bootloader detects the baud rate from
+pub(crate) const SYNC: u8 = 0x7F;
+
+/// Global erase with the Extended Erase command
+pub(crate) const ERASE_ALL: [u8; 3] = [0xFF, 0xFF, 0x00];
+
+/// A malformed or refused response, and where in the exchange it came
+pub(crate) type Rejection = (Stage, Failure);
+
+pub(crate) fn command(code: u8) -> [u8; 2] {
+    [code, code ^ 0xFF]
+}
+
+pub(crate) fn address(address: u32) -> [u8; 5] {
+    let mut frame = [0u8; 5];
+    frame[0..4].copy_from_slice(&address.to_be_bytes());
+    frame[4] = calculate_checksum(&frame[0..4]);
+    frame
+}
+
+/// The number of bytes to read with Read Memory
+pub(crate) fn read_length(size: usize) -> Result<[u8; 2], DfuLoaderError> {
+    if size > 256 || size == 0 {
+        return Err(InvalidRequest("read size must be 1 to 256 bytes"));
+    }
+    let length = (size - 1) as u8;
+    Ok([length, length ^ 0xFF])
+}
+
+/// The length, data and checksum of Write Memory
+pub(crate) fn write_data(data: Vec<u8>) -> Result<Vec<u8>, DfuLoaderError> {
+    if data.len() > 256 || data.is_empty() {
+        return Err(InvalidRequest("write size must be 1 to 256 bytes"));
+    }
+    let mut frame = vec![(data.len() - 1) as u8];
+    frame.extend(data);
+    frame.push(calculate_checksum(&frame));
+    Ok(frame)
+}
+
+/// The page list of Extended Erase
+pub(crate) fn erase_pages(pages: &[u16]) -> Result<Vec<u8>, DfuLoaderError> {
+    if pages.is_empty() || pages.len() > 0xFFF0 {
+        return Err(InvalidRequest("page count must be 1 to 65520"));
+    }
+    let mut frame = ((pages.len() - 1) as u16).to_be_bytes().to_vec();
+    pages.iter().for_each(|p| frame.extend(p.to_be_bytes()));
+    frame.push(calculate_checksum(&frame));
+    Ok(frame)
+}
+
+/// The length of Get Checksum, after checking the area is word aligned
+pub(crate) fn checksum_length(address: u32, length: u32) -> Result<[u8; 5], DfuLoaderError> {
+    if !address.is_multiple_of(4) || !length.is_multiple_of(4) || length == 0 {
+        return Err(InvalidRequest("checksum area must be word aligned"));
+    }
+    let mut frame = [0u8; 5];
+    frame[0..4].copy_from_slice(&length.to_be_bytes());
+    frame[4] = calculate_checksum(&frame[0..4]);
+    Ok(frame)
+}
+
+/// The answer to Get Version: version, two option bytes and ACK
+pub(crate) fn parse_version(response: &[u8]) -> Result<BootloaderOptions, Rejection> {
+    if response[3] != ACK {
+        return Err((Stage::Ack, Failure::from_response(response[3])));
+    }
+    Ok(BootloaderOptions {
+        version: response[0],
+        options: (response[1] as u16) << 8 | response[2] as u16,
+    })
+}
+
+/// The answer to Get after the length byte: version, commands and ACK
+pub(crate) fn parse_functions(response: &[u8]) -> Result<BootLoaderInfo, Rejection> {
+    let n = response.len();
+    if response[n - 1] != ACK {
+        return Err((Stage::Ack, Failure::from_response(response[n - 1])));
+    }
+    Ok(BootLoaderInfo {
+        version: response[0],
+        supported_functions: response[1..n - 1]
+            .iter()
+            .map(|&x| Functions::from(x))
+            .collect(),
+    })
+}
+
+/// The length byte of the answer to Get ID, the STM32 always returns two
+/// bytes
+pub(crate) fn check_id_length(length: u8) -> Result<(), Rejection> {
+    match length {
+        1 => Ok(()),
+        _ => Err((Stage::Data, Failure::Malformed)),
+    }
+}
+
+/// The answer to Get ID after the length byte: product ID and ACK
+pub(crate) fn parse_id(response: &[u8]) -> Result<BootloaderChipId, Rejection> {
+    if response[2] != ACK {
+        return Err((Stage::Ack, Failure::from_response(response[2])));
+    }
+    Ok(BootloaderChipId {
+        chipid: (response[0] as u16) << 8 | response[1] as u16,
+    })
+}
+
+/// The answer to Get Checksum: the CRC and its checksum
+pub(crate) fn parse_checksum(response: &[u8]) -> Result<u32, Rejection> {
+    if calculate_checksum(&response[0..4]) != response[4] {
+        return Err((Stage::Data, Failure::Malformed));
+    }
+    Ok(u32::from_be_bytes([
+        response[0],
+        response[1],
+        response[2],
+        response[3],
+    ]))
+}
+
+pub(crate) fn calculate_checksum(data: &[u8]) -> u8 {
+    let mut checksum = data[0];
+    data[1..].iter().for_each(|v| checksum ^= v);
+
+    checksum
+}